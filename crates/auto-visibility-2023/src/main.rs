@@ -17,6 +17,7 @@ use pamc112::{
     RotationDirection::{self, *},
 };
 use serde::Deserialize;
+use settling::SettleParams;
 
 #[derive(Parser)]
 struct Opts {
@@ -42,6 +43,19 @@ struct Config {
     pamc_step: f64,
 
     pamc_wait: f64,
+
+    // Wait for the visibility to settle instead of `pamc_wait` and the fixed wait in the loop
+    settle: Option<SettleConfig>,
+}
+
+#[derive(Deserialize)]
+struct SettleConfig {
+    // Number of visibility readings the criterion is evaluated over
+    window: usize,
+    // Standard deviation of visibility below which it counts as settled
+    threshold: f64,
+    // Seconds
+    timeout: f64,
 }
 
 fn main() -> anyhow::Result<()> {
@@ -49,6 +63,14 @@ fn main() -> anyhow::Result<()> {
 
     let opts = Opts::parse();
     let config: Config = toml::from_str(&fs_err::read_to_string(opts.config_path)?)?;
+    if let Some(settle) = &config.settle {
+        if settle.window < 2 {
+            bail!("Settling window must contain at least two readings");
+        }
+        if !(settle.timeout >= 0. && settle.timeout.is_finite()) {
+            bail!("Settling timeout must be a non-negative number of seconds");
+        }
+    }
     let channel = ChannelNumber {
         channel: config.channel,
         sub_channel: config.sub_channel,
@@ -179,6 +201,8 @@ fn main() -> anyhow::Result<()> {
     let mut i = 1;
     let mut flag = false;
 
+    let wait = || wait_after_drive(&config, &ctrlc, &handle, channel);
+
     while !flag && !ctrlc() {
         info!("{} times", i);
         let mut grad = gradient(&config, &ctrlc, &mut pamc, &handle, channel)?;
@@ -222,14 +246,7 @@ fn main() -> anyhow::Result<()> {
                     dire[j] = Ccw;
                 }
                 movement[j] *= rate[j];
-                drive_pamc(
-                    &mut pamc,
-                    j as u8,
-                    dire[j],
-                    1500,
-                    movement[j],
-                    config.pamc_wait,
-                )?;
+                drive_pamc(&mut pamc, j as u8, dire[j], 1500, movement[j], &wait)?;
                 let direction_coef = if let Cw = dire[j] { 1. } else { -1. };
                 rotation[j] += direction_coef * step_size1 * rate[j];
             }
@@ -240,7 +257,9 @@ fn main() -> anyhow::Result<()> {
             c.push(rotation[2]);
             d.push(rotation[3]);
             r_vec.push(r);
-            sleep(Duration::from_millis(200));
+            if config.settle.is_none() {
+                sleep(Duration::from_millis(200));
+            }
             let temp = vis_func(&config, &ctrlc, &handle, channel)?;
             result.push(temp);
             info!("Visibility: {}", temp);
@@ -355,23 +374,24 @@ fn gradient(
     let mut small = [0.0; 4];
 
     let move_p = config.pamc_step;
+    let wait = || wait_after_drive(config, ctrlc.clone(), handle, channel);
 
     let o = vis_func(config, ctrlc.clone(), handle, channel)?;
     info!("Now visibility is {o:.4}");
     for i in 0..4usize {
         let coef = config.pamc_coef[i];
 
-        drive_pamc(pamc, i as u8, Cw, 1500, move_p * coef, config.pamc_wait)?; // clockwise
+        drive_pamc(pamc, i as u8, Cw, 1500, move_p * coef, &wait)?; // clockwise
         big[i] = vis_func(config, ctrlc.clone(), handle, channel)?;
-        drive_pamc(pamc, i as u8, Ccw, 1500, move_p, config.pamc_wait)?;
+        drive_pamc(pamc, i as u8, Ccw, 1500, move_p, &wait)?;
         let o_temp = vis_func(config, ctrlc.clone(), handle, channel)?;
         if (o - o_temp).abs() > e {
             bail!("Error: I cannot come back to the original point. ({o:.4}, {o_temp:.4})",);
         }
 
-        drive_pamc(pamc, i as u8, Cw, 1500, move_p, config.pamc_wait)?; // Anticlcockwise
+        drive_pamc(pamc, i as u8, Cw, 1500, move_p, &wait)?; // Anticlcockwise
         small[i] = vis_func(config, ctrlc.clone(), handle, channel)?;
-        drive_pamc(pamc, i as u8, Cw, 1500, move_p * coef, config.pamc_wait)?;
+        drive_pamc(pamc, i as u8, Cw, 1500, move_p * coef, &wait)?;
         let o_temp = vis_func(config, ctrlc.clone(), handle, channel)?;
         if (o - o_temp).abs() > e {
             bail!("Error: I cannot come back to the original point. ({o:.4}, {o_temp:.4})",);
@@ -393,13 +413,13 @@ fn drive_pamc(
     direction: RotationDirection,
     frequency: u16,
     count: f64,
-    wait: f64,
+    wait: &dyn Fn() -> anyhow::Result<()>,
 ) -> anyhow::Result<()> {
     let count = count as u16;
     match count.cmp(&0) {
         Ordering::Greater => {
             pamc.drive(channel, direction, frequency, count)?;
-            sleep(Duration::from_secs_f64(wait));
+            wait()?;
         }
         Ordering::Equal => {}
         Ordering::Less => {
@@ -409,6 +429,28 @@ fn drive_pamc(
     Ok(())
 }
 
+fn wait_after_drive(
+    config: &Config,
+    ctrlc: impl Fn() -> bool,
+    handle: &Handle<TriggerAsync>,
+    channel: ChannelNumber,
+) -> anyhow::Result<()> {
+    match &config.settle {
+        None => sleep(Duration::from_secs_f64(config.pamc_wait)),
+        Some(settle) => {
+            let params = SettleParams {
+                window: settle.window,
+                threshold: settle.threshold,
+                timeout: Duration::from_secs_f64(settle.timeout),
+            };
+            settling::wait_until_settled(&params, || {
+                vis_func(config, &ctrlc, handle, channel).map(|v| Some([v]))
+            })?;
+        }
+    }
+    Ok(())
+}
+
 // struct DataFrame {
 //     data: Vec<Vec<f64>>,
 //     columns: Vec<String>,
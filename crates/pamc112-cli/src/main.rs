@@ -1,13 +1,19 @@
 use std::time::Duration;
 
+use anyhow::Context;
 use clap::{Parser, Subcommand};
 use pamc112::{Pamc112, RotationDirection};
+use tm2070::net::Annotator;
 
 #[derive(Parser)]
 struct Opts {
     port: String,
     #[clap(long, default_value = "1.0")]
     timeout_secs: f64,
+    /// Send a marker for the drive to a recording, e.g. `tm2070-continuous-measurement
+    /// --marker-socket 127.0.0.1:7071`
+    #[clap(long)]
+    annotate: Option<String>,
     #[command(subcommand)]
     sub: Sub,
 }
@@ -26,6 +32,15 @@ enum Sub {
 fn main() -> anyhow::Result<()> {
     let opts = Opts::parse();
     let mut controller = Pamc112::new(&opts.port, Duration::from_secs_f64(opts.timeout_secs))?;
+    if let Some(addr) = &opts.annotate {
+        let mut annotator = Annotator::connect(addr, "pamc112")
+            .with_context(|| format!("Could not connect to {addr}"))?;
+        controller.on_drive(move |drive| {
+            if let Err(e) = annotator.mark(&drive.to_string()) {
+                eprintln!("Failed to send a marker: {e}");
+            }
+        });
+    }
     if let Sub::Drive {
         channel,
         direction,
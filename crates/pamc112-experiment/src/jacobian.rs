@@ -0,0 +1,335 @@
+use std::path::PathBuf;
+
+use anyhow::{bail, Context};
+use clap::Args;
+use log::info;
+use pamc112::{Pamc112, RotationDirection};
+use serde::{Deserialize, Serialize};
+use tm2070::Tm2070;
+
+use crate::{check_channel, drive_signed, measure_urad, Ctx};
+
+#[derive(Args)]
+pub struct JacobianOpts {
+    /// Channel moved as the first column of the Jacobian
+    channel_a: u8,
+    /// Channel moved as the second column of the Jacobian
+    channel_b: u8,
+    /// Where to save the fitted Jacobian (TOML)
+    output_path: PathBuf,
+    /// Pulses per perturbation step
+    #[clap(long, default_value = "50")]
+    step: u16,
+    /// Number of perturbation steps per channel and direction
+    #[clap(long, default_value = "5")]
+    repeat: usize,
+    /// Number of samples averaged per measurement
+    #[clap(long, default_value = "20")]
+    average: usize,
+}
+
+#[derive(Args)]
+pub struct GotoOpts {
+    /// Jacobian saved by the `jacobian` subcommand
+    jacobian_path: PathBuf,
+    /// Target x angle in µrad
+    #[clap(allow_hyphen_values = true)]
+    x: f64,
+    /// Target y angle in µrad
+    #[clap(allow_hyphen_values = true)]
+    y: f64,
+    /// Allowed deviation from the target for each axis in µrad
+    #[clap(long, default_value = "1.0")]
+    tolerance: f64,
+    #[clap(long, default_value = "10")]
+    max_iterations: usize,
+    /// Number of samples averaged per measurement
+    #[clap(long, default_value = "20")]
+    average: usize,
+}
+
+/// Linear response of the autocollimator to a pair of channels.
+///
+/// All coefficients are in µrad per pulse, where a pulse is counted positive for
+/// clockwise and negative for counterclockwise rotation.
+/// `cw[i][j]` is the response of axis `i` (0: x, 1: y) to channel `channels[j]`
+/// driven clockwise, and likewise for `ccw`.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Jacobian {
+    pub channels: [u8; 2],
+    pub cw: [[f64; 2]; 2],
+    pub ccw: [[f64; 2]; 2],
+    /// RMS of fit residuals in µrad, laid out as the coefficients
+    pub cw_residual: [[f64; 2]; 2],
+    pub ccw_residual: [[f64; 2]; 2],
+}
+
+impl Jacobian {
+    pub fn load(path: &std::path::Path) -> anyhow::Result<Self> {
+        let ret = toml::from_str(&fs_err::read_to_string(path)?)
+            .with_context(|| format!("Failed to parse Jacobian from {path:?}"))?;
+        Ok(ret)
+    }
+
+    fn column(&self, channel: usize, positive: bool) -> [f64; 2] {
+        let m = if positive { &self.cw } else { &self.ccw };
+        [m[0][channel], m[1][channel]]
+    }
+
+    /// Signed pulses for each channel that move the reading by `delta` (µrad).
+    ///
+    /// Since the response depends on the direction, each combination of directions is
+    /// tried, and the solution consistent with its assumed directions is returned.
+    pub fn solve(&self, delta: [f64; 2]) -> Option<[f64; 2]> {
+        [(true, true), (true, false), (false, true), (false, false)]
+            .into_iter()
+            .find_map(|(pa, pb)| {
+                let p = solve_2x2(self.column(0, pa), self.column(1, pb), delta)?;
+                let consistent = |p: f64, positive: bool| p == 0. || (p > 0.) == positive;
+                (consistent(p[0], pa) && consistent(p[1], pb)).then_some(p)
+            })
+    }
+}
+
+/// Solves `a * p[0] + b * p[1] = c`.
+/// Fails if `a` and `b` are (nearly) parallel, whatever their scale.
+fn solve_2x2(a: [f64; 2], b: [f64; 2], c: [f64; 2]) -> Option<[f64; 2]> {
+    /// Sine of the smallest angle between the columns
+    const MIN_SIN: f64 = 1e-6;
+    let det = a[0] * b[1] - b[0] * a[1];
+    if det.abs() <= MIN_SIN * a[0].hypot(a[1]) * b[0].hypot(b[1]) {
+        return None;
+    }
+    Some([
+        (c[0] * b[1] - b[0] * c[1]) / det,
+        (a[0] * c[1] - c[0] * a[1]) / det,
+    ])
+}
+
+pub fn fit(
+    tm2070: &mut Tm2070,
+    pamc: &mut Pamc112,
+    opts: &JacobianOpts,
+    ctx: &Ctx,
+) -> anyhow::Result<()> {
+    check_channel(opts.channel_a)?;
+    check_channel(opts.channel_b)?;
+    if opts.channel_a == opts.channel_b {
+        bail!("Channels must be different");
+    }
+    if !(1..=9999).contains(&opts.step) {
+        bail!("Invalid step: {}", opts.step);
+    }
+    if opts.repeat < 2 {
+        bail!("At least 2 steps are required to fit the response");
+    }
+
+    let channels = [opts.channel_a, opts.channel_b];
+    let mut jacobian = Jacobian {
+        channels,
+        cw: Default::default(),
+        ccw: Default::default(),
+        cw_residual: Default::default(),
+        ccw_residual: Default::default(),
+    };
+    for (j, &channel) in channels.iter().enumerate() {
+        // The counterclockwise sweep brings the mount roughly back to the start.
+        for direction in [RotationDirection::Cw, RotationDirection::Ccw] {
+            let step = match direction {
+                RotationDirection::Cw => opts.step as i32,
+                RotationDirection::Ccw => -(opts.step as i32),
+            };
+            // Takes up the backlash after a reversal, which would otherwise only shift
+            // the first point and bias the slope
+            drive_signed(pamc, channel, step)?;
+            ctx.wait_after_drive(tm2070)?;
+            let mut pulses = vec![0.];
+            let mut record = vec![measure_urad(tm2070, opts.average)?];
+            for k in 1..=opts.repeat {
+                if ctx.interrupted() {
+                    bail!("Ctrl-C");
+                }
+                drive_signed(pamc, channel, step)?;
+                ctx.wait_after_drive(tm2070)?;
+                pulses.push((k as i32 * step) as f64);
+                record.push(measure_urad(tm2070, opts.average)?);
+            }
+            for i in 0..2 {
+                let response = record.iter().map(|r| r[i]).collect::<Vec<_>>();
+                let (slope, residual) = linear_fit(&pulses, &response);
+                info!("Channel {channel} {direction:?}, axis {i}: {slope:.4} µrad/pulse (residual {residual:.3} µrad)");
+                let (m, r) = match direction {
+                    RotationDirection::Cw => (&mut jacobian.cw, &mut jacobian.cw_residual),
+                    RotationDirection::Ccw => (&mut jacobian.ccw, &mut jacobian.ccw_residual),
+                };
+                m[i][j] = slope;
+                r[i][j] = residual;
+            }
+        }
+    }
+
+    println!("{jacobian:#?}");
+    fs_err::write(&opts.output_path, toml::to_string_pretty(&jacobian)?)?;
+    Ok(())
+}
+
+/// Least squares fit of `y = slope * x + intercept`.
+/// Returns the slope and the RMS of the residuals.
+fn linear_fit(x: &[f64], y: &[f64]) -> (f64, f64) {
+    let n = x.len() as f64;
+    let mean_x = x.iter().sum::<f64>() / n;
+    let mean_y = y.iter().sum::<f64>() / n;
+    let sxy = x
+        .iter()
+        .zip(y)
+        .map(|(x, y)| (x - mean_x) * (y - mean_y))
+        .sum::<f64>();
+    let sxx = x.iter().map(|x| (x - mean_x).powi(2)).sum::<f64>();
+    let slope = sxy / sxx;
+    let intercept = mean_y - slope * mean_x;
+    let residual = x
+        .iter()
+        .zip(y)
+        .map(|(x, y)| (y - (slope * x + intercept)).powi(2))
+        .sum::<f64>();
+    (slope, (residual / n).sqrt())
+}
+
+pub fn goto(
+    tm2070: &mut Tm2070,
+    pamc: &mut Pamc112,
+    opts: &GotoOpts,
+    ctx: &Ctx,
+) -> anyhow::Result<()> {
+    let jacobian = Jacobian::load(&opts.jacobian_path)?;
+    move_to(
+        tm2070,
+        pamc,
+        &jacobian,
+        [opts.x, opts.y],
+        opts.tolerance,
+        opts.max_iterations,
+        opts.average,
+        ctx,
+    )
+}
+
+/// Iteratively moves the reading to `target` (µrad) using `jacobian`.
+#[allow(clippy::too_many_arguments)]
+pub fn move_to(
+    tm2070: &mut Tm2070,
+    pamc: &mut Pamc112,
+    jacobian: &Jacobian,
+    target: [f64; 2],
+    tolerance: f64,
+    max_iterations: usize,
+    average: usize,
+    ctx: &Ctx,
+) -> anyhow::Result<()> {
+    for i in 0.. {
+        if ctx.interrupted() {
+            bail!("Ctrl-C");
+        }
+        let current = measure_urad(tm2070, average)?;
+        let delta = [target[0] - current[0], target[1] - current[1]];
+        info!("Iteration {i}: at {current:?} µrad, {delta:?} µrad to go");
+        if delta.iter().all(|d| d.abs() <= tolerance) {
+            info!("Reached target");
+            return Ok(());
+        }
+        if i == max_iterations {
+            bail!("Target not reached in {max_iterations} iterations: {delta:?} µrad to go");
+        }
+        let pulses = jacobian
+            .solve(delta)
+            .context("Jacobian is singular or inconsistent")?
+            .map(|p| p.round() as i32);
+        if pulses == [0, 0] {
+            bail!("Remaining move of {delta:?} µrad is below one pulse but exceeds the tolerance");
+        }
+        for (&channel, pulses) in jacobian.channels.iter().zip(pulses) {
+            if pulses != 0 {
+                drive_signed(pamc, channel, pulses)?;
+            }
+        }
+        ctx.wait_after_drive(tm2070)?;
+    }
+    unreachable!()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn jacobian(cw: [[f64; 2]; 2], ccw: [[f64; 2]; 2]) -> Jacobian {
+        Jacobian {
+            channels: [0, 1],
+            cw,
+            ccw,
+            cw_residual: [[0.; 2]; 2],
+            ccw_residual: [[0.; 2]; 2],
+        }
+    }
+
+    /// Reading change caused by the signed pulses `p`.
+    fn response(j: &Jacobian, p: [f64; 2]) -> [f64; 2] {
+        let a = j.column(0, p[0] >= 0.);
+        let b = j.column(1, p[1] >= 0.);
+        [0, 1].map(|i| a[i] * p[0] + b[i] * p[1])
+    }
+
+    fn assert_close(a: [f64; 2], b: [f64; 2]) {
+        assert!(
+            (a[0] - b[0]).abs() < 1e-9 && (a[1] - b[1]).abs() < 1e-9,
+            "{a:?} != {b:?}"
+        );
+    }
+
+    #[test]
+    fn inverts_a_symmetric_response_in_every_quadrant() {
+        let m = [[0.5, 0.1], [-0.05, 0.4]];
+        let j = jacobian(m, m);
+        for delta in [[10., 20.], [-10., 20.], [10., -20.], [-10., -20.]] {
+            let p = j.solve(delta).unwrap();
+            assert_close(response(&j, p), delta);
+        }
+    }
+
+    #[test]
+    fn uses_the_response_of_each_direction() {
+        // Counterclockwise moves twice as far per pulse
+        let j = jacobian([[1., 0.], [0., 1.]], [[2., 0.], [0., 2.]]);
+        assert_close(j.solve([-4., 3.]).unwrap(), [-2., 3.]);
+        assert_close(j.solve([4., -3.]).unwrap(), [4., -1.5]);
+    }
+
+    #[test]
+    fn picks_the_consistent_directions_with_crosstalk() {
+        let j = jacobian([[0.5, 0.2], [0.1, 0.4]], [[0.3, 0.1], [0.2, 0.6]]);
+        for delta in [[5., -3.], [-5., 3.], [1., 8.], [-7., -2.]] {
+            let p = j.solve(delta).unwrap();
+            assert_close(response(&j, p), delta);
+        }
+    }
+
+    #[test]
+    fn zero_delta_needs_no_pulses() {
+        let m = [[0.5, 0.1], [-0.05, 0.4]];
+        assert_close(jacobian(m, m).solve([0., 0.]).unwrap(), [0., 0.]);
+    }
+
+    #[test]
+    fn solves_for_small_coefficients() {
+        // Responses of the order of nrad per pulse
+        let m = [[5e-9, 1e-9], [-5e-10, 4e-9]];
+        let j = jacobian(m, m);
+        let p = j.solve([1e-7, 2e-7]).unwrap();
+        assert_close(response(&j, p), [1e-7, 2e-7]);
+    }
+
+    #[test]
+    fn fails_when_the_channels_move_along_the_same_line() {
+        let m = [[1., 2.], [1., 2.]];
+        assert!(jacobian(m, m).solve([1., 0.]).is_none());
+    }
+}
@@ -1,6 +1,11 @@
+mod jacobian;
+mod raster;
+mod search;
+
 use std::{
     cmp::Ordering,
     io::{BufWriter, Write},
+    path::Path,
     sync::{
         atomic::{AtomicBool, Ordering::SeqCst},
         Arc,
@@ -10,20 +15,65 @@ use std::{
 };
 
 use anyhow::{bail, Context};
-use clap::Parser;
+use chrono::{DateTime, Local};
+use clap::{Args, Parser, Subcommand};
 use fs_err::OpenOptions;
-use log::warn;
+use log::{info, warn};
 use pamc112::{
     Pamc112,
     RotationDirection::{self, *},
 };
 use radians::{Angle, Deg64, Rad64};
-use tm2070::Tm2070;
+use serde::Serialize;
+use settling::{Outcome, SettleParams, Settling};
+use tm2070::{net::Annotator, Tm2070};
 
+/// Without a subcommand, the arguments of `sweep` follow the ports, as before subcommands were added.
 #[derive(Parser)]
+#[command(subcommand_negates_reqs = true)]
 struct Opts {
+    /// `sim` simulates the controller, coupled to the autocollimator if that is simulated too
     pamc_port: String,
+    /// `sim` or `sim:<settings>` simulates the autocollimator
     tm2070_port: String,
+    /// Wait a fixed time in seconds after each drive instead of waiting for the reading to settle
+    #[clap(long)]
+    fixed_wait: Option<f64>,
+    /// Number of readings the settling criterion is evaluated over
+    #[clap(long, default_value = "5")]
+    settle_window: usize,
+    /// Standard deviation in µrad below which the reading counts as settled
+    #[clap(long, default_value = "0.2")]
+    settle_threshold: f64,
+    /// Maximum time in seconds to wait for the reading to settle
+    #[clap(long, default_value = "3.0")]
+    settle_timeout: f64,
+    /// Send a marker for each drive to a recording, e.g. `tm2070-continuous-measurement
+    /// --marker-socket 127.0.0.1:7071`
+    #[clap(long)]
+    annotate: Option<String>,
+    #[command(flatten)]
+    sweep: Option<SweepOpts>,
+    #[command(subcommand)]
+    sub: Option<Sub>,
+}
+
+#[derive(Subcommand)]
+enum Sub {
+    /// Repeatedly sweep one channel, stepping the other channel between sweeps
+    Sweep(SweepOpts),
+    /// Fit the Jacobian between a pair of channels and the autocollimator axes
+    Jacobian(jacobian::JacobianOpts),
+    /// Move to a target angle with a coordinated two-channel move
+    Goto(jacobian::GotoOpts),
+    /// Reacquire a lost return beam by driving two channels in a spiral
+    Search(search::SearchOpts),
+    /// Map the autocollimator response over a grid of two channels
+    Raster(raster::RasterOpts),
+}
+
+#[derive(Args, Clone)]
+struct SweepOpts {
     channel: u8,
     direction: RotationDirection,
     step: u16,
@@ -31,53 +81,250 @@ struct Opts {
     other_direction: RotationDirection,
     other_step: u16,
     output_path: String,
+    /// Continue from the next free output index instead of starting from 0
+    #[clap(long)]
+    resume: bool,
+}
+
+/// Contents of the `{output_path}_{i:03}.toml` sidecar written next to each sweep.
+#[derive(Serialize)]
+struct SweepMetadata {
+    index: usize,
+    channel: u8,
+    direction: String,
+    step: u16,
+    other_channel: u8,
+    other_direction: String,
+    other_step: u16,
+    /// Pulses driven on `other_channel` before this sweep, counted from the first index
+    other_cumulative_pulses: u64,
+    /// Samples averaged per row
+    average: usize,
+    started_at: DateTime<Local>,
+    finished_at: DateTime<Local>,
+    rows: usize,
+    config: RunConfig,
 }
 
 fn main() -> anyhow::Result<()> {
     env_logger::builder().format_timestamp_nanos().init();
     let opts = Opts::parse();
-    if opts.channel >= 22 {
-        bail!("Invalid channel number: {}", opts.channel);
+    if opts.settle_window < 2 {
+        bail!("Settling window must contain at least two readings");
     }
-    if !(1..=9999).contains(&opts.step) {
-        bail!("Invalid step: {}", opts.step);
+    let sub = match (&opts.sub, &opts.sweep) {
+        (Some(sub), None) => sub,
+        (None, Some(sweep)) => &Sub::Sweep(sweep.clone()),
+        (Some(_), Some(_)) => bail!("Sweep arguments cannot be combined with a subcommand"),
+        (None, None) => bail!("Either a subcommand or sweep arguments are required"),
+    };
+    let (mut pamc, mut tm2070) = if opts.pamc_port == "sim" {
+        simulate(&opts.tm2070_port)?
+    } else {
+        let pamc = Pamc112::new(&opts.pamc_port, Duration::from_secs(1))?;
+        (pamc, Tm2070::new(&opts.tm2070_port)?)
+    };
+    if let Some(addr) = &opts.annotate {
+        let mut annotator = Annotator::connect(addr, "pamc112")
+            .with_context(|| format!("Could not connect to {addr}"))?;
+        pamc.on_drive(move |drive| {
+            if let Err(e) = annotator.mark(&drive.to_string()) {
+                warn!("Failed to send a marker: {e}");
+            }
+        });
     }
-    let mut pamc = Pamc112::new(&opts.pamc_port, Duration::from_secs(1))?;
-    let mut tm2070 = Tm2070::new(&opts.tm2070_port)?;
 
     let ctrlc = Arc::new(AtomicBool::new(false));
     {
         let ctrlc = ctrlc.clone();
         ctrlc::set_handler(move || ctrlc.store(true, SeqCst))?;
     }
+    let wait = match opts.fixed_wait {
+        Some(secs) => {
+            Wait::Fixed(Duration::try_from_secs_f64(secs).context("Invalid --fixed-wait")?)
+        }
+        None => {
+            if !(opts.settle_threshold.is_finite() && opts.settle_threshold > 0.) {
+                bail!("Settling threshold must be positive");
+            }
+            Wait::Settle(SettleParams {
+                window: opts.settle_window,
+                threshold: opts.settle_threshold,
+                timeout: Duration::try_from_secs_f64(opts.settle_timeout)
+                    .context("Invalid --settle-timeout")?,
+            })
+        }
+    };
+    let config = RunConfig {
+        pamc_port: opts.pamc_port.clone(),
+        tm2070_port: opts.tm2070_port.clone(),
+        fixed_wait: opts.fixed_wait,
+        settle_window: opts.settle_window,
+        settle_threshold: opts.settle_threshold,
+        settle_timeout: opts.settle_timeout,
+    };
+    let ctx = Ctx {
+        ctrlc,
+        wait,
+        config,
+    };
+
+    match sub {
+        Sub::Sweep(opts) => sweep(&mut tm2070, &mut pamc, opts, &ctx),
+        Sub::Jacobian(opts) => jacobian::fit(&mut tm2070, &mut pamc, opts, &ctx),
+        Sub::Goto(opts) => jacobian::goto(&mut tm2070, &mut pamc, opts, &ctx),
+        Sub::Search(opts) => search::search(&mut tm2070, &mut pamc, opts, &ctx),
+        Sub::Raster(opts) => raster::raster(&mut tm2070, &mut pamc, opts, &ctx),
+    }
+}
+
+/// State shared by all subcommands.
+struct Ctx {
+    ctrlc: Arc<AtomicBool>,
+    wait: Wait,
+    config: RunConfig,
+}
+
+/// The options common to all subcommands, recorded in sidecars.
+#[derive(Clone, Debug, Serialize)]
+struct RunConfig {
+    pamc_port: String,
+    tm2070_port: String,
+    /// Seconds waited after each drive; the settling options are unused if present
+    #[serde(skip_serializing_if = "Option::is_none")]
+    fixed_wait: Option<f64>,
+    settle_window: usize,
+    /// µrad
+    settle_threshold: f64,
+    /// Seconds
+    settle_timeout: f64,
+}
+
+enum Wait {
+    Fixed(Duration),
+    /// Wait until x and y (in µrad) settle
+    Settle(SettleParams),
+}
+
+impl Ctx {
+    fn interrupted(&self) -> bool {
+        self.ctrlc.load(SeqCst)
+    }
+
+    /// Waits for the mount to come to rest after a drive.
+    /// Fails if the reading does not settle within the timeout or the beam is lost;
+    /// use [`Ctx::settle`] where that is expected.
+    fn wait_after_drive(&self, tm2070: &mut Tm2070) -> anyhow::Result<()> {
+        match self.settle(tm2070)? {
+            None => Ok(()),
+            Some(settling) => match settling.outcome {
+                Outcome::Settled => Ok(()),
+                Outcome::TimedOut => match settling.spread {
+                    Some(spread) => bail!(
+                        "Reading did not settle in {:?} (spread: {spread:.3} µrad)",
+                        settling.elapsed
+                    ),
+                    None => bail!("Reading did not settle in {:?}", settling.elapsed),
+                },
+                Outcome::Unavailable => bail!("Beam lost while settling"),
+            },
+        }
+    }
+
+    /// Waits like [`Ctx::wait_after_drive`], but returns how settling ended instead of failing,
+    /// or `None` after a fixed wait. Settling is abandoned as soon as the beam is out of range.
+    fn settle(&self, tm2070: &mut Tm2070) -> anyhow::Result<Option<Settling>> {
+        match &self.wait {
+            Wait::Fixed(duration) => {
+                sleep(*duration);
+                Ok(None)
+            }
+            Wait::Settle(params) => {
+                let settling = settling::wait_until_settled(params, || {
+                    let data = tm2070.single_1()?;
+                    anyhow::Ok(
+                        (data.x.zip(data.y)).map(|(x, y)| [x, y].map(|a| a.value().val() * 1e6)),
+                    )
+                })?;
+                Ok(Some(settling))
+            }
+        }
+    }
+}
+
+fn sweep(
+    tm2070: &mut Tm2070,
+    pamc: &mut Pamc112,
+    opts: &SweepOpts,
+    ctx: &Ctx,
+) -> anyhow::Result<()> {
+    check_channel(opts.channel)?;
+    check_channel(opts.other_channel)?;
+    if !(1..=9999).contains(&opts.step) {
+        bail!("Invalid step: {}", opts.step);
+    }
 
     let threshold = Deg64::new(0.5).rad();
     let within_threshold =
         |angle: [Rad64; 2]| angle.into_iter().all(|x| angle_lt(x.mag(), threshold));
-    let mut i = 0;
-    while within_threshold(measure(&mut tm2070, 1)?) && !ctrlc.load(SeqCst) {
-        make_x_zero(&mut tm2070, &mut pamc, &opts)?;
+    let mut i = if opts.resume {
+        let i = next_free_index(&opts.output_path)?;
+        info!("Resuming from index {i}");
+        i
+    } else {
+        0
+    };
+    while within_threshold(measure(tm2070, 1)?) && !ctx.interrupted() {
+        // Both files are created before measuring, so that a name clash does not lose a sweep
+        let (tsv_path, toml_path) = sweep_paths(&opts.output_path, i);
+        let create = |path| OpenOptions::new().create_new(true).write(true).open(path);
+        let mut file = BufWriter::new(create(&tsv_path)?);
+        let mut sidecar = create(&toml_path)?;
+        writeln!(file, "time\tpulses\tx\ty")?;
+
+        make_x_zero(tm2070, pamc, opts, ctx)?;
 
         let count = 20;
-        let initial = measure(&mut tm2070, count)?;
-        let mut record = vec![initial];
+        let started_at = Local::now();
+        let mut pulses = 0;
+        let mut rows = 0;
+        let mut record = |file: &mut BufWriter<_>, pulses, [x, y]: [Rad64; 2]| {
+            rows += 1;
+            writeln!(
+                file,
+                "{}\t{pulses}\t{}\t{}",
+                Local::now().to_rfc3339(),
+                x.val(),
+                y.val()
+            )?;
+            file.flush()
+        };
+        record(&mut file, pulses, measure(tm2070, count)?)?;
         while {
             pamc.drive(opts.channel, opts.direction, 1500, opts.step)?;
-            sleep(Duration::from_secs_f64(0.15));
-            let res = measure(&mut tm2070, count)?;
-            record.push(res);
-            within_threshold(res) && !ctrlc.load(SeqCst)
+            pulses += opts.step as u64;
+            ctx.wait_after_drive(tm2070)?;
+            let angle = measure(tm2070, count)?;
+            record(&mut file, pulses, angle)?;
+            within_threshold(angle) && !ctx.interrupted()
         } {}
 
-        let mut file = BufWriter::new(
-            OpenOptions::new()
-                .create_new(true)
-                .write(true)
-                .open(format!("{}_{i:03}.tsv", opts.output_path))?,
-        );
-        for [x, y] in record {
-            writeln!(file, "{}\t{}", x.val(), y.val())?;
-        }
+        let metadata = SweepMetadata {
+            index: i,
+            channel: opts.channel,
+            direction: format!("{:?}", opts.direction),
+            step: opts.step,
+            other_channel: opts.other_channel,
+            other_direction: format!("{:?}", opts.other_direction),
+            other_step: opts.other_step,
+            other_cumulative_pulses: i as u64 * opts.other_step as u64,
+            average: count,
+            started_at,
+            finished_at: Local::now(),
+            rows,
+            config: ctx.config.clone(),
+        };
+        sidecar.write_all(toml::to_string_pretty(&metadata)?.as_bytes())?;
 
         i += 1;
         pamc.drive(
@@ -86,38 +333,130 @@ fn main() -> anyhow::Result<()> {
             1500,
             opts.other_step,
         )?;
-        sleep(Duration::from_secs_f64(0.15));
+        ctx.wait_after_drive(tm2070)?;
     }
 
     Ok(())
 }
 
-fn make_x_zero(tm2070: &mut Tm2070, pamc: &mut Pamc112, opts: &Opts) -> anyhow::Result<()> {
-    let mut x = || anyhow::Ok(tm2070.single_1()?.x.context("ND")?.value());
-    let mut drive = |step: i16| match step.cmp(&0) {
-        Ordering::Less => pamc.drive(opts.channel, Ccw, 1500, step.unsigned_abs()),
-        Ordering::Equal => {
-            warn!("Driving 0 steps");
-            Ok(())
+fn sweep_paths(output_path: &str, i: usize) -> (String, String) {
+    (
+        format!("{output_path}_{i:03}.tsv"),
+        format!("{output_path}_{i:03}.toml"),
+    )
+}
+
+/// The index after the highest one for which the data or the sidecar exists.
+fn next_free_index(output_path: &str) -> anyhow::Result<usize> {
+    let path = Path::new(output_path);
+    let dir = match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    };
+    let prefix = format!(
+        "{}_",
+        path.file_name()
+            .context("Invalid output path")?
+            .to_string_lossy()
+    );
+    let mut next = 0;
+    for entry in fs_err::read_dir(dir)? {
+        let name = entry?.file_name();
+        let index = (name.to_str())
+            .and_then(|name| name.strip_prefix(&prefix))
+            .and_then(|rest| rest.strip_suffix(".tsv").or(rest.strip_suffix(".toml")))
+            .and_then(|index| index.parse::<usize>().ok());
+        if let Some(index) = index {
+            next = next.max(index + 1);
         }
-        Ordering::Greater => pamc.drive(opts.channel, Cw, 1500, step.unsigned_abs()),
+    }
+    Ok(next)
+}
+
+fn make_x_zero(
+    tm2070: &mut Tm2070,
+    pamc: &mut Pamc112,
+    opts: &SweepOpts,
+    ctx: &Ctx,
+) -> anyhow::Result<()> {
+    let x = |tm2070: &mut Tm2070| anyhow::Ok(tm2070.single_1()?.x.context("ND")?.value());
+    let mut drive = |tm2070: &mut Tm2070, step: i32| {
+        drive_signed(pamc, opts.channel, step)?;
+        ctx.wait_after_drive(tm2070)
     };
-    let sleep = || sleep(Duration::from_secs_f64(0.15));
-    while angle_lt(Rad64::new(-0.5e-3), x()?) {
-        drive(-100)?;
-        sleep();
+    while angle_lt(Rad64::new(-0.5e-3), x(tm2070)?) {
+        drive(tm2070, -100)?;
+    }
+    while angle_lt(x(tm2070)?, Rad64::new(-0.1e-3)) {
+        drive(tm2070, 30)?;
     }
-    while angle_lt(x()?, Rad64::new(-0.1e-3)) {
-        drive(30)?;
-        sleep();
+    while angle_lt(x(tm2070)?, Rad64::new(-0.03e-3)) {
+        drive(tm2070, 5)?;
+    }
+    while angle_lt(x(tm2070)?, Rad64::new(0.)) {
+        drive(tm2070, 1)?;
+    }
+    Ok(())
+}
+
+/// Tilt in µrad per pulse of each channel. Even channels mainly tilt x, odd channels y,
+/// with some crosstalk so that the Jacobian is not trivial.
+fn simulated_response(channel: u8) -> [f64; 2] {
+    let (main, cross) = (0.05, 0.008);
+    let sign = if channel % 4 < 2 { 1. } else { -1. };
+    match channel % 2 {
+        0 => [sign * main, cross],
+        _ => [-cross, sign * main],
     }
-    while angle_lt(x()?, Rad64::new(-0.03e-3)) {
-        drive(5)?;
-        sleep();
+}
+
+fn simulate(tm2070_port: &str) -> anyhow::Result<(Pamc112, Tm2070)> {
+    let pamc_sim = pamc112::sim::Simulator::new();
+    let positions = pamc_sim.positions();
+    let pamc = Pamc112::from_serial(pamc_sim.into_port())?;
+    let Some(settings) = tm2070_port.strip_prefix("sim") else {
+        warn!("Simulated controller does not move the real mirror");
+        return Ok((pamc, Tm2070::new(tm2070_port)?));
+    };
+    let config: tm2070::sim::SimConfig = settings.trim_start_matches(':').parse()?;
+    let base = config.tilt;
+    let tilt_source = Box::new(move || {
+        let mut tilt = base;
+        for channel in 0..pamc112::sim::CHANNELS as u8 {
+            let response = simulated_response(channel);
+            let pulses = positions.get(channel);
+            tilt[0] += response[0] * pulses;
+            tilt[1] += response[1] * pulses;
+        }
+        tilt
+    });
+    let tm2070_sim = tm2070::sim::Simulator::new(config).with_tilt_source(tilt_source);
+    Ok((pamc, Tm2070::from_serial(tm2070_sim.into_port())))
+}
+
+fn check_channel(channel: u8) -> anyhow::Result<()> {
+    if channel >= 22 {
+        bail!("Invalid channel number: {channel}");
     }
-    while angle_lt(x()?, Rad64::new(0.)) {
-        drive(1)?;
-        sleep();
+    Ok(())
+}
+
+/// Drives `step` pulses, where positive is clockwise and negative is counterclockwise.
+/// Steps exceeding the per-command limit of the controller are split into several drives.
+fn drive_signed(pamc: &mut Pamc112, channel: u8, step: i32) -> anyhow::Result<()> {
+    let direction = match step.cmp(&0) {
+        Ordering::Less => Ccw,
+        Ordering::Equal => {
+            warn!("Driving 0 steps");
+            return Ok(());
+        }
+        Ordering::Greater => Cw,
+    };
+    let mut rest = step.unsigned_abs();
+    while rest > 0 {
+        let count = rest.min(9999);
+        pamc.drive(channel, direction, 1500, count as u16)?;
+        rest -= count;
     }
     Ok(())
 }
@@ -140,3 +479,33 @@ fn measure(tm2070: &mut Tm2070, count: usize) -> anyhow::Result<[Rad64; 2]> {
     }
     Ok([x, y].map(|x| Rad64::new(x / count as f64)))
 }
+
+/// Same as [`measure`], but in microradians.
+fn measure_urad(tm2070: &mut Tm2070, count: usize) -> anyhow::Result<[f64; 2]> {
+    Ok(measure(tm2070, count)?.map(|x| x.val() * 1e6))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn resumes_after_the_highest_index() {
+        let dir = std::env::temp_dir().join(format!("pamc112-experiment-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        let output_path = dir.join("run").to_str().unwrap().to_owned();
+        assert_eq!(next_free_index(&output_path).unwrap(), 0);
+        for name in [
+            "run_000.tsv",
+            "run_000.toml",
+            "run_001.tsv",
+            "run_003.toml",
+            "other_007.tsv",
+        ] {
+            std::fs::write(dir.join(name), "").unwrap();
+        }
+        assert_eq!(next_free_index(&output_path).unwrap(), 4);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
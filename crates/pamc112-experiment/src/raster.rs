@@ -0,0 +1,173 @@
+use std::io::{BufWriter, Write};
+
+use anyhow::bail;
+use chrono::{DateTime, Local};
+use clap::Args;
+use fs_err::OpenOptions;
+use log::info;
+use pamc112::Pamc112;
+use serde::Serialize;
+use settling::Outcome;
+use tm2070::Tm2070;
+
+use crate::{check_channel, drive_signed, Ctx};
+
+#[derive(Args)]
+pub struct RasterOpts {
+    channel_a: u8,
+    channel_b: u8,
+    /// Number of nodes along channel A
+    nodes_a: u32,
+    /// Number of nodes along channel B
+    nodes_b: u32,
+    /// Signed pulses between nodes along channel A (positive is clockwise)
+    #[clap(allow_hyphen_values = true)]
+    step_a: i32,
+    /// Signed pulses between nodes along channel B (positive is clockwise)
+    #[clap(allow_hyphen_values = true)]
+    step_b: i32,
+    /// Writes `{output_path}.csv` and `{output_path}.toml`
+    output_path: String,
+    /// Number of samples averaged per node
+    #[clap(long, default_value = "20")]
+    average: usize,
+    /// Drive back to the first node after the scan
+    #[clap(long)]
+    return_to_origin: bool,
+}
+
+/// Contents of the `{output_path}.toml` sidecar.
+///
+/// The CSV has one row per node in acquisition order, with columns
+/// `i,j,pulses_a,pulses_b,time,valid,x,y,settling`. `i` and `j` are node indices along
+/// channel A and B, `pulses_*` are the node positions relative to the first node,
+/// and `x`, `y` are averaged over valid samples in radians (empty if none was valid).
+/// `settling` is how the wait before measuring ended: `settled`, `timed out`, `unavailable`
+/// (the beam was lost) or `fixed` with `--fixed-wait`.
+#[derive(Serialize)]
+struct RasterMetadata {
+    channel_a: u8,
+    channel_b: u8,
+    nodes_a: u32,
+    nodes_b: u32,
+    step_a: i32,
+    step_b: i32,
+    average: usize,
+    serpentine: bool,
+    started_at: DateTime<Local>,
+    finished_at: DateTime<Local>,
+    completed_nodes: u64,
+}
+
+pub fn raster(
+    tm2070: &mut Tm2070,
+    pamc: &mut Pamc112,
+    opts: &RasterOpts,
+    ctx: &Ctx,
+) -> anyhow::Result<()> {
+    check_channel(opts.channel_a)?;
+    check_channel(opts.channel_b)?;
+    if opts.channel_a == opts.channel_b {
+        bail!("Channels must be different");
+    }
+    if opts.nodes_a == 0 || opts.nodes_b == 0 {
+        bail!("Grid must have at least one node");
+    }
+
+    let open = |extension| {
+        OpenOptions::new()
+            .create_new(true)
+            .write(true)
+            .open(format!("{}.{extension}", opts.output_path))
+    };
+    let mut file = BufWriter::new(open("csv")?);
+    writeln!(file, "i,j,pulses_a,pulses_b,time,valid,x,y,settling")?;
+
+    let started_at = Local::now();
+    let mut position = [0i32; 2];
+    let mut completed_nodes = 0;
+    let res = (|| {
+        for j in 0..opts.nodes_b {
+            if j > 0 {
+                drive_signed(pamc, opts.channel_b, opts.step_b)?;
+                position[1] += opts.step_b;
+            }
+            // Serpentine order, so that channel A reverses only at the end of each row
+            let forward = j % 2 == 0;
+            for k in 0..opts.nodes_a {
+                if ctx.interrupted() {
+                    bail!("Ctrl-C");
+                }
+                if k > 0 {
+                    let step = if forward { opts.step_a } else { -opts.step_a };
+                    drive_signed(pamc, opts.channel_a, step)?;
+                    position[0] += step;
+                }
+                let settling = match ctx.settle(tm2070)? {
+                    None => "fixed",
+                    Some(settling) => match settling.outcome {
+                        Outcome::Settled => "settled",
+                        Outcome::TimedOut => "timed out",
+                        Outcome::Unavailable => "unavailable",
+                    },
+                };
+                let i = if forward { k } else { opts.nodes_a - 1 - k };
+                let (valid, angle) = measure_valid(tm2070, opts.average)?;
+                let [x, y] =
+                    angle.map_or([String::new(), String::new()], |a| a.map(|a| a.to_string()));
+                writeln!(
+                    file,
+                    "{i},{j},{},{},{},{valid},{x},{y},{settling}",
+                    position[0],
+                    position[1],
+                    Local::now().to_rfc3339()
+                )?;
+                file.flush()?;
+                completed_nodes += 1;
+                info!("Node ({i}, {j}): {valid}/{} valid", opts.average);
+            }
+        }
+        anyhow::Ok(())
+    })();
+
+    if opts.return_to_origin {
+        info!("Returning to origin from {position:?} pulses");
+        for (channel, position) in [(opts.channel_a, position[0]), (opts.channel_b, position[1])] {
+            if position != 0 {
+                drive_signed(pamc, channel, -position)?;
+            }
+        }
+    }
+
+    let metadata = RasterMetadata {
+        channel_a: opts.channel_a,
+        channel_b: opts.channel_b,
+        nodes_a: opts.nodes_a,
+        nodes_b: opts.nodes_b,
+        step_a: opts.step_a,
+        step_b: opts.step_b,
+        average: opts.average,
+        serpentine: true,
+        started_at,
+        finished_at: Local::now(),
+        completed_nodes,
+    };
+    open("toml")?.write_all(toml::to_string_pretty(&metadata)?.as_bytes())?;
+    res
+}
+
+/// Averages valid samples in radians, returning the number of valid samples as well.
+/// Unlike [`crate::measure`], the beam may be lost at some nodes of a map.
+fn measure_valid(tm2070: &mut Tm2070, count: usize) -> anyhow::Result<(usize, Option<[f64; 2]>)> {
+    let mut valid = 0;
+    let mut sum = [0.; 2];
+    for _ in 0..count {
+        let data = tm2070.single_1()?;
+        if let (Some(x), Some(y)) = (data.x, data.y) {
+            valid += 1;
+            sum[0] += x.value().val();
+            sum[1] += y.value().val();
+        }
+    }
+    Ok((valid, (valid > 0).then(|| sum.map(|s| s / valid as f64))))
+}
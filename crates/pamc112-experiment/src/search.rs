@@ -0,0 +1,204 @@
+use std::path::PathBuf;
+
+use anyhow::bail;
+use clap::Args;
+use log::info;
+use pamc112::Pamc112;
+use tm2070::Tm2070;
+
+use crate::{
+    check_channel, drive_signed,
+    jacobian::{move_to, Jacobian},
+    measure_urad, Ctx,
+};
+
+#[derive(Args)]
+pub struct SearchOpts {
+    channel_a: u8,
+    channel_b: u8,
+    /// Pulses between neighbouring search points
+    #[clap(long, default_value = "100")]
+    step: u16,
+    /// Maximum search radius in units of `step`
+    #[clap(long, default_value = "20")]
+    max_radius: u32,
+    /// Jacobian used to re-centre the beam after reacquisition, fitted for the same channels.
+    /// If omitted, the response is estimated where the beam is found from one step of
+    /// `--probe-step` pulses per channel and direction.
+    #[clap(long)]
+    jacobian_path: Option<PathBuf>,
+    /// Pulses per step when estimating the response without `--jacobian-path`
+    #[clap(long, default_value = "50")]
+    probe_step: u16,
+    /// Allowed deviation from the centre for each axis in µrad
+    #[clap(long, default_value = "1.0")]
+    tolerance: f64,
+    /// Maximum number of moves while re-centring
+    #[clap(long, default_value = "10")]
+    max_iterations: usize,
+    /// Number of samples averaged per measurement while re-centring
+    #[clap(long, default_value = "20")]
+    average: usize,
+}
+
+pub fn search(
+    tm2070: &mut Tm2070,
+    pamc: &mut Pamc112,
+    opts: &SearchOpts,
+    ctx: &Ctx,
+) -> anyhow::Result<()> {
+    check_channel(opts.channel_a)?;
+    check_channel(opts.channel_b)?;
+    if opts.channel_a == opts.channel_b {
+        bail!("Channels must be different");
+    }
+    for step in [opts.step, opts.probe_step] {
+        if !(1..=9999).contains(&step) {
+            bail!("Invalid step: {step}");
+        }
+    }
+    if !(opts.tolerance.is_finite() && opts.tolerance > 0.) {
+        bail!("Tolerance must be positive");
+    }
+    let channels = [opts.channel_a, opts.channel_b];
+    let jacobian = opts
+        .jacobian_path
+        .as_deref()
+        .map(Jacobian::load)
+        .transpose()?;
+    if let Some(jacobian) = &jacobian {
+        if jacobian.channels != channels {
+            bail!(
+                "Jacobian was fitted for channels {:?}, not {channels:?}",
+                jacobian.channels
+            );
+        }
+    }
+
+    if !spiral(tm2070, pamc, channels, opts, ctx)? {
+        bail!("Beam not found within {} steps", opts.max_radius);
+    }
+    let jacobian = match jacobian {
+        Some(jacobian) => jacobian,
+        None => probe(tm2070, pamc, channels, opts, ctx)?,
+    };
+    move_to(
+        tm2070,
+        pamc,
+        &jacobian,
+        [0., 0.],
+        opts.tolerance,
+        opts.max_iterations,
+        opts.average,
+        ctx,
+    )
+}
+
+/// Estimates the response at the current position from one step forth and back on each channel.
+/// A step that loses the beam is undone and taken in the other direction instead.
+/// The residuals are left at zero, as a single step has none.
+fn probe(
+    tm2070: &mut Tm2070,
+    pamc: &mut Pamc112,
+    channels: [u8; 2],
+    opts: &SearchOpts,
+    ctx: &Ctx,
+) -> anyhow::Result<Jacobian> {
+    let mut jacobian = Jacobian {
+        channels,
+        cw: Default::default(),
+        ccw: Default::default(),
+        cw_residual: Default::default(),
+        ccw_residual: Default::default(),
+    };
+    'channels: for (j, &channel) in channels.iter().enumerate() {
+        for step in [opts.probe_step as i32, -(opts.probe_step as i32)] {
+            if ctx.interrupted() {
+                bail!("Ctrl-C");
+            }
+            let start = measure_urad(tm2070, opts.average)?;
+            drive_signed(pamc, channel, step)?;
+            ctx.settle(tm2070)?;
+            if !is_valid(tm2070)? {
+                info!("Beam lost after {step} pulses on channel {channel}; stepping back");
+                drive_signed(pamc, channel, -step)?;
+                ctx.wait_after_drive(tm2070)?;
+                continue;
+            }
+            let away = measure_urad(tm2070, opts.average)?;
+            drive_signed(pamc, channel, -step)?;
+            ctx.wait_after_drive(tm2070)?;
+            let back = measure_urad(tm2070, opts.average)?;
+            let (forth, return_) = match step > 0 {
+                true => (&mut jacobian.cw, &mut jacobian.ccw),
+                false => (&mut jacobian.ccw, &mut jacobian.cw),
+            };
+            for i in 0..2 {
+                forth[i][j] = (away[i] - start[i]) / step as f64;
+                return_[i][j] = (back[i] - away[i]) / -step as f64;
+            }
+            continue 'channels;
+        }
+        bail!(
+            "Beam lost after {} pulses on channel {channel} in either direction",
+            opts.probe_step
+        );
+    }
+    info!("Estimated response: {jacobian:?}");
+    Ok(jacobian)
+}
+
+/// Walks a square spiral around the current position until a valid reading is found.
+/// Returns to the starting point and returns `false` if nothing is found within the radius.
+fn spiral(
+    tm2070: &mut Tm2070,
+    pamc: &mut Pamc112,
+    channels: [u8; 2],
+    opts: &SearchOpts,
+    ctx: &Ctx,
+) -> anyhow::Result<bool> {
+    let step = opts.step as i32;
+    let mut position = [0i32; 2];
+    let directions = [(0, 1), (1, 1), (0, -1), (1, -1)];
+    let res = (|| {
+        if is_valid(tm2070)? {
+            return anyhow::Ok(true);
+        }
+        for leg in 0.. {
+            let (axis, sign) = directions[leg % 4];
+            let length = leg as u32 / 2 + 1;
+            for _ in 0..length {
+                if ctx.interrupted() {
+                    bail!("Ctrl-C");
+                }
+                let next = position[axis] + sign;
+                if next.unsigned_abs() > opts.max_radius {
+                    return Ok(false);
+                }
+                drive_signed(pamc, channels[axis], sign * step)?;
+                position[axis] = next;
+                // The beam is expected to be missing at most points
+                ctx.settle(tm2070)?;
+                if is_valid(tm2070)? {
+                    info!("Beam found at {position:?} steps");
+                    return Ok(true);
+                }
+            }
+        }
+        unreachable!()
+    })();
+    if !matches!(res, Ok(true)) {
+        info!("Returning to the start from {position:?} steps");
+        for (&channel, position) in channels.iter().zip(position) {
+            if position != 0 {
+                drive_signed(pamc, channel, -position * step)?;
+            }
+        }
+    }
+    res
+}
+
+fn is_valid(tm2070: &mut Tm2070) -> anyhow::Result<bool> {
+    let data = tm2070.single_1()?;
+    Ok(data.x.is_some() && data.y.is_some())
+}
@@ -1,16 +1,53 @@
-use std::{borrow::Cow, time::Duration};
+pub mod sim;
+
+use std::{borrow::Cow, fmt, time::Duration};
 
 use anyhow::bail;
 use bstr::BStr;
 #[cfg(feature = "clap")]
 use clap::ValueEnum;
 use log::info;
-use serialport::{DataBits, Parity, StopBits};
+use serialport::{DataBits, Parity, SerialPort, StopBits};
 
 use serial_wrapper::SerialWrapper;
 
 pub struct Pamc112 {
     serial_wrapper: SerialWrapper,
+    drive_hooks: Vec<DriveHook>,
+}
+
+type DriveHook = Box<dyn FnMut(&Drive) + Send>;
+
+/// A drive command, as passed to the hooks of [`Pamc112::on_drive`].
+#[derive(Clone, Copy, Debug)]
+pub struct Drive {
+    pub channel: u8,
+    pub direction: RotationDirection,
+    pub frequency: u16,
+    pub count: u16,
+}
+
+impl Drive {
+    /// Time the pulses take.
+    pub fn duration(&self) -> Duration {
+        Duration::from_secs_f64(self.count as f64 / self.frequency as f64)
+    }
+}
+
+impl fmt::Display for Drive {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let direction = match self.direction {
+            RotationDirection::Cw => "cw",
+            RotationDirection::Ccw => "ccw",
+        };
+        write!(
+            f,
+            "drive {} {direction} {} pulses at {} Hz",
+            (b'A' + self.channel) as char,
+            self.count,
+            self.frequency
+        )
+    }
 }
 
 impl Pamc112 {
@@ -22,8 +59,16 @@ impl Pamc112 {
             .flow_control(serialport::FlowControl::None)
             .timeout(timeout)
             .open()?;
+        Self::from_serial(serial)
+    }
+
+    /// Wraps a port that has already been opened and configured, e.g. [`sim::Simulator::into_port`].
+    pub fn from_serial(serial: Box<dyn SerialPort>) -> anyhow::Result<Self> {
         let serial_wrapper = SerialWrapper::new(serial);
-        let mut ret = Self { serial_wrapper };
+        let mut ret = Self {
+            serial_wrapper,
+            drive_hooks: vec![],
+        };
         ret.check_connection()?;
         Ok(ret)
     }
@@ -33,6 +78,11 @@ impl Pamc112 {
         self.read_wait(b"OK")
     }
 
+    /// Calls `hook` just before each drive is sent, e.g. to mark moves in a recording.
+    pub fn on_drive(&mut self, hook: impl FnMut(&Drive) + Send + 'static) {
+        self.drive_hooks.push(Box::new(hook));
+    }
+
     /// Constraints (panics otherwise)
     /// * channel < 22
     /// * 1 <= frequency <= 1500
@@ -48,6 +98,15 @@ impl Pamc112 {
         assert!((1..=1500).contains(&frequency));
         assert!(count > 0, "Setting count to 0 causes an indefinite drive!");
         assert!(count < 10000);
+        let drive = Drive {
+            channel,
+            direction,
+            frequency,
+            count,
+        };
+        for hook in &mut self.drive_hooks {
+            hook(&drive);
+        }
         let direction = match direction {
             RotationDirection::Cw => "NR",
             RotationDirection::Ccw => "RR",
@@ -70,7 +129,7 @@ impl Pamc112 {
 
     fn read_wait(&mut self, expect: &[u8]) -> anyhow::Result<()> {
         // Blocking read
-        let read = self.serial_wrapper.read_tx.recv()?;
+        let read = self.serial_wrapper.read_tx.recv()?.data;
         info!("Read: {:?}", BStr::new(&read));
         if read == expect {
             Ok(())
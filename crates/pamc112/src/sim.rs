@@ -0,0 +1,122 @@
+//! A virtual PAMC-112 for testing without the controller.
+//!
+//! Drives take `count / frequency` seconds, during which the pulse count of the channel
+//! advances at the drive frequency, so that a coupled simulation sees the mirror move.
+
+use std::{
+    sync::{Arc, Mutex},
+    time::Instant,
+};
+
+use bstr::ByteSlice;
+use serial_wrapper::virtual_port::{VirtualDevice, VirtualPort};
+use serialport::SerialPort;
+
+pub const CHANNELS: usize = 22;
+
+/// Signed pulse counts of all channels, Cw positive, shared with whatever reads them.
+#[derive(Clone, Default)]
+pub struct Positions(Arc<Mutex<[f64; CHANNELS]>>);
+impl Positions {
+    /// Panics if channel >= 22.
+    pub fn get(&self, channel: u8) -> f64 {
+        self.0.lock().unwrap()[channel as usize]
+    }
+}
+
+struct Drive {
+    channel: usize,
+    /// Signed pulses per second
+    rate: f64,
+    count: u16,
+    start: Instant,
+    /// Position of the channel when the drive started
+    from: f64,
+}
+
+#[derive(Default)]
+pub struct Simulator {
+    positions: Positions,
+    input: Vec<u8>,
+    output: Vec<u8>,
+    drive: Option<Drive>,
+}
+
+impl Simulator {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn positions(&self) -> Positions {
+        self.positions.clone()
+    }
+
+    pub fn into_port(self) -> Box<dyn SerialPort> {
+        VirtualPort::new("sim", self).boxed()
+    }
+
+    fn execute(&mut self, line: &[u8]) {
+        let response: &[u8] = match line {
+            b"CON" => b"OK",
+            _ => match self.parse_drive(line) {
+                // The controller does not accept commands while driving
+                Some(drive) if self.drive.is_none() => {
+                    self.drive = Some(drive);
+                    b"OK"
+                }
+                _ => b"NG",
+            },
+        };
+        self.output.extend(response);
+        self.output.extend(b"\r\n");
+    }
+
+    /// `NR` (Cw) or `RR` (Ccw), 4-digit frequency, 4-digit count, channel letter.
+    fn parse_drive(&self, line: &[u8]) -> Option<Drive> {
+        if line.len() != 11 {
+            return None;
+        }
+        let sign = match &line[..2] {
+            b"NR" => 1.,
+            b"RR" => -1.,
+            _ => return None,
+        };
+        let frequency = line[2..6].to_str().ok()?.parse::<u16>().ok()?;
+        let count = line[6..10].to_str().ok()?.parse::<u16>().ok()?;
+        let channel = line[10].checked_sub(b'A')? as usize;
+        if !(1..=1500).contains(&frequency) || count == 0 || channel >= CHANNELS {
+            return None;
+        }
+        Some(Drive {
+            channel,
+            rate: sign * f64::from(frequency),
+            count,
+            start: Instant::now(),
+            from: self.positions.0.lock().unwrap()[channel],
+        })
+    }
+}
+
+impl VirtualDevice for Simulator {
+    fn receive(&mut self, bytes: &[u8]) {
+        self.input.extend(bytes);
+        while let Some(i) = self.input.find(b"\r\n") {
+            let line = self.input.drain(..i + 2).take(i).collect::<Vec<_>>();
+            self.execute(&line);
+        }
+    }
+
+    fn transmit(&mut self, out: &mut Vec<u8>) {
+        if let Some(drive) = &self.drive {
+            let total = f64::from(drive.count);
+            let done = (drive.start.elapsed().as_secs_f64() * drive.rate.abs()).min(total);
+            self.positions.0.lock().unwrap()[drive.channel] =
+                drive.from + drive.rate.signum() * done.floor();
+            if done >= total {
+                self.drive = None;
+                self.output.extend(b"FIN\r\n");
+            }
+        }
+        out.append(&mut self.output);
+    }
+}
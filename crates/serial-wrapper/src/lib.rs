@@ -1,9 +1,11 @@
+pub mod virtual_port;
+
 use std::{
     borrow::Cow,
     collections::VecDeque,
     sync::mpsc,
     thread::{sleep, spawn},
-    time::Duration,
+    time::{Duration, Instant},
 };
 
 use anyhow::bail;
@@ -11,8 +13,18 @@ use itertools::Itertools;
 use log::error;
 use serialport::SerialPort;
 
+/// A line read from the port, without the trailing CRLF.
+#[derive(Clone, Debug)]
+pub struct Line {
+    /// Number of lines received before this one since the port was wrapped.
+    pub seq: u64,
+    /// When the end of the line was read from the port.
+    pub received: Instant,
+    pub data: Vec<u8>,
+}
+
 pub struct SerialWrapper {
-    pub read_tx: mpsc::Receiver<Vec<u8>>,
+    pub read_tx: mpsc::Receiver<Line>,
     pub write_rx: mpsc::Sender<Cow<'static, [u8]>>,
 }
 
@@ -23,6 +35,7 @@ impl SerialWrapper {
         spawn(move || {
             let res = (|| -> anyhow::Result<()> {
                 let mut read_deque = VecDeque::new();
+                let mut seq = 0;
                 loop {
                     // Write
                     for message in write_tx.try_iter() {
@@ -37,12 +50,18 @@ impl SerialWrapper {
                         if actual_read < expected_read {
                             bail!("Expected {expected_read} bytes, found {actual_read} bytes");
                         }
+                        let received = Instant::now();
                         read_deque.extend(buf);
                         while let Some(i) = (read_deque.iter().tuple_windows().enumerate())
                             .find_map(|(i, (&x, &y))| (&[x, y] == b"\r\n").then_some(i))
                         {
-                            let line = Vec::from_iter(read_deque.drain(..i + 2).take(i));
-                            read_rx.send(line)?;
+                            let data = Vec::from_iter(read_deque.drain(..i + 2).take(i));
+                            read_rx.send(Line {
+                                seq,
+                                received,
+                                data,
+                            })?;
+                            seq += 1;
                         }
                     }
                     // 1 / (115200 Hz) = 8 microseconds, so wait 20 microseconds
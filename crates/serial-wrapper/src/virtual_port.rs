@@ -0,0 +1,195 @@
+//! An in-process stand-in for a serial port, used to simulate devices.
+
+use std::{
+    collections::VecDeque,
+    io,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use serialport::{ClearBuffer, DataBits, FlowControl, Parity, SerialPort, StopBits};
+
+/// The device on the other end of a [`VirtualPort`].
+pub trait VirtualDevice: Send {
+    /// Called with bytes written by the host.
+    fn receive(&mut self, bytes: &[u8]);
+    /// Appends bytes that the device has sent by now.
+    fn transmit(&mut self, out: &mut Vec<u8>);
+}
+
+pub struct VirtualPort<D> {
+    name: String,
+    timeout: Duration,
+    inner: Arc<Mutex<Inner<D>>>,
+}
+
+struct Inner<D> {
+    device: D,
+    pending: VecDeque<u8>,
+}
+impl<D: VirtualDevice> Inner<D> {
+    fn poll(&mut self) {
+        let mut out = vec![];
+        self.device.transmit(&mut out);
+        self.pending.extend(out);
+    }
+}
+
+impl<D: VirtualDevice + 'static> VirtualPort<D> {
+    pub fn new(name: impl Into<String>, device: D) -> Self {
+        Self {
+            name: name.into(),
+            timeout: Duration::from_secs(1),
+            inner: Arc::new(Mutex::new(Inner {
+                device,
+                pending: VecDeque::new(),
+            })),
+        }
+    }
+
+    pub fn boxed(self) -> Box<dyn SerialPort> {
+        Box::new(self)
+    }
+
+    fn lock(&self) -> io::Result<std::sync::MutexGuard<'_, Inner<D>>> {
+        (self.inner.lock()).map_err(|_| io::Error::other("Device panicked"))
+    }
+}
+
+impl<D: VirtualDevice + 'static> io::Read for VirtualPort<D> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let mut inner = self.lock()?;
+        inner.poll();
+        let count = buf.len().min(inner.pending.len());
+        for (b, p) in buf.iter_mut().zip(inner.pending.drain(..count)) {
+            *b = p;
+        }
+        if count == 0 && !buf.is_empty() {
+            return Err(io::ErrorKind::TimedOut.into());
+        }
+        Ok(count)
+    }
+}
+
+impl<D: VirtualDevice + 'static> io::Write for VirtualPort<D> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.lock()?.device.receive(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl<D: VirtualDevice + 'static> SerialPort for VirtualPort<D> {
+    fn name(&self) -> Option<String> {
+        Some(self.name.clone())
+    }
+
+    fn baud_rate(&self) -> serialport::Result<u32> {
+        Ok(115200)
+    }
+
+    fn data_bits(&self) -> serialport::Result<DataBits> {
+        Ok(DataBits::Eight)
+    }
+
+    fn flow_control(&self) -> serialport::Result<FlowControl> {
+        Ok(FlowControl::None)
+    }
+
+    fn parity(&self) -> serialport::Result<Parity> {
+        Ok(Parity::None)
+    }
+
+    fn stop_bits(&self) -> serialport::Result<StopBits> {
+        Ok(StopBits::One)
+    }
+
+    fn timeout(&self) -> Duration {
+        self.timeout
+    }
+
+    fn set_baud_rate(&mut self, _: u32) -> serialport::Result<()> {
+        Ok(())
+    }
+
+    fn set_data_bits(&mut self, _: DataBits) -> serialport::Result<()> {
+        Ok(())
+    }
+
+    fn set_flow_control(&mut self, _: FlowControl) -> serialport::Result<()> {
+        Ok(())
+    }
+
+    fn set_parity(&mut self, _: Parity) -> serialport::Result<()> {
+        Ok(())
+    }
+
+    fn set_stop_bits(&mut self, _: StopBits) -> serialport::Result<()> {
+        Ok(())
+    }
+
+    fn set_timeout(&mut self, timeout: Duration) -> serialport::Result<()> {
+        self.timeout = timeout;
+        Ok(())
+    }
+
+    fn write_request_to_send(&mut self, _: bool) -> serialport::Result<()> {
+        Ok(())
+    }
+
+    fn write_data_terminal_ready(&mut self, _: bool) -> serialport::Result<()> {
+        Ok(())
+    }
+
+    fn read_clear_to_send(&mut self) -> serialport::Result<bool> {
+        Ok(true)
+    }
+
+    fn read_data_set_ready(&mut self) -> serialport::Result<bool> {
+        Ok(true)
+    }
+
+    fn read_ring_indicator(&mut self) -> serialport::Result<bool> {
+        Ok(false)
+    }
+
+    fn read_carrier_detect(&mut self) -> serialport::Result<bool> {
+        Ok(true)
+    }
+
+    fn bytes_to_read(&self) -> serialport::Result<u32> {
+        let mut inner = self.lock()?;
+        inner.poll();
+        Ok(inner.pending.len() as u32)
+    }
+
+    fn bytes_to_write(&self) -> serialport::Result<u32> {
+        Ok(0)
+    }
+
+    fn clear(&self, buffer_to_clear: ClearBuffer) -> serialport::Result<()> {
+        if let ClearBuffer::Input | ClearBuffer::All = buffer_to_clear {
+            self.lock()?.pending.clear();
+        }
+        Ok(())
+    }
+
+    fn try_clone(&self) -> serialport::Result<Box<dyn SerialPort>> {
+        Ok(Box::new(Self {
+            name: self.name.clone(),
+            timeout: self.timeout,
+            inner: self.inner.clone(),
+        }))
+    }
+
+    fn set_break(&self) -> serialport::Result<()> {
+        Ok(())
+    }
+
+    fn clear_break(&self) -> serialport::Result<()> {
+        Ok(())
+    }
+}
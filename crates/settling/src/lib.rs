@@ -0,0 +1,171 @@
+use std::{
+    collections::VecDeque,
+    time::{Duration, Instant},
+};
+
+use log::{info, warn};
+
+#[derive(Clone, Debug)]
+pub struct SettleParams {
+    /// Number of most recent readings the spread is computed over.
+    pub window: usize,
+    /// The signal is settled once the standard deviation of every component
+    /// over the window drops below this value (in units of the signal).
+    pub threshold: f64,
+    pub timeout: Duration,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Outcome {
+    Settled,
+    TimedOut,
+    /// The source reported that no valid reading is available.
+    Unavailable,
+}
+
+#[derive(Clone, Debug)]
+pub struct Settling {
+    pub outcome: Outcome,
+    /// Time from the call until the outcome was determined.
+    pub elapsed: Duration,
+    pub readings: usize,
+    /// Largest standard deviation among the components over the last full window,
+    /// or `None` if the window was never filled.
+    pub spread: Option<f64>,
+}
+
+/// Reads `read` repeatedly until the signal is settled or the timeout expires.
+///
+/// `read` is expected to block until the next reading is available,
+/// and returns `None` when the signal is unavailable (e.g. beam lost),
+/// in which case waiting is abandoned.
+pub fn wait_until_settled<const N: usize, E>(
+    params: &SettleParams,
+    mut read: impl FnMut() -> Result<Option<[f64; N]>, E>,
+) -> Result<Settling, E> {
+    assert!(
+        params.window >= 2,
+        "window must contain at least two readings"
+    );
+    let start = Instant::now();
+    let mut window = VecDeque::with_capacity(params.window);
+    let mut readings = 0;
+    let mut spread = None;
+    let outcome = loop {
+        let Some(reading) = read()? else {
+            break Outcome::Unavailable;
+        };
+        readings += 1;
+        if window.len() == params.window {
+            window.pop_front();
+        }
+        window.push_back(reading);
+        if window.len() == params.window {
+            let s = max_std(&window);
+            spread = Some(s);
+            if s < params.threshold {
+                break Outcome::Settled;
+            }
+        }
+        if start.elapsed() >= params.timeout {
+            break Outcome::TimedOut;
+        }
+    };
+    let ret = Settling {
+        outcome,
+        elapsed: start.elapsed(),
+        readings,
+        spread,
+    };
+    match outcome {
+        Outcome::Settled => info!("Settled in {:?} ({readings} readings)", ret.elapsed),
+        Outcome::TimedOut => warn!("Not settled in {:?} (spread: {spread:?})", ret.elapsed),
+        Outcome::Unavailable => warn!("Signal unavailable while settling"),
+    }
+    Ok(ret)
+}
+
+fn max_std<const N: usize>(window: &VecDeque<[f64; N]>) -> f64 {
+    let n = window.len() as f64;
+    (0..N)
+        .map(|i| {
+            let mean = window.iter().map(|r| r[i]).sum::<f64>() / n;
+            let var = window.iter().map(|r| (r[i] - mean).powi(2)).sum::<f64>() / (n - 1.);
+            var.sqrt()
+        })
+        .fold(0., f64::max)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn params(window: usize, threshold: f64, timeout: Duration) -> SettleParams {
+        SettleParams {
+            window,
+            threshold,
+            timeout,
+        }
+    }
+
+    /// A source returning `readings` in order, then failing.
+    fn source(
+        readings: Vec<Option<[f64; 2]>>,
+    ) -> impl FnMut() -> Result<Option<[f64; 2]>, &'static str> {
+        let mut readings = readings.into_iter();
+        move || readings.next().ok_or("exhausted")
+    }
+
+    #[test]
+    fn settles_once_the_window_is_quiet() {
+        let readings = [[5., 0.], [1., 0.], [1., 0.], [1., 0.]].map(Some).to_vec();
+        let settling =
+            wait_until_settled(&params(3, 1e-3, Duration::from_secs(60)), source(readings))
+                .unwrap();
+        assert_eq!(settling.outcome, Outcome::Settled);
+        assert_eq!(settling.readings, 4);
+        assert_eq!(settling.spread, Some(0.));
+    }
+
+    #[test]
+    fn spread_is_the_largest_sample_std() {
+        // Second component: 1, 2, 3 has a sample standard deviation of 1
+        let readings = [[0., 1.], [0., 2.], [0., 3.]].map(Some).to_vec();
+        let settling =
+            wait_until_settled(&params(3, 1.5, Duration::from_secs(60)), source(readings)).unwrap();
+        assert_eq!(settling.outcome, Outcome::Settled);
+        assert!((settling.spread.unwrap() - 1.).abs() < 1e-12);
+    }
+
+    #[test]
+    fn times_out_before_the_window_fills() {
+        let readings = vec![Some([0., 0.])];
+        let settling =
+            wait_until_settled(&params(3, 1., Duration::ZERO), source(readings)).unwrap();
+        assert_eq!(settling.outcome, Outcome::TimedOut);
+        assert_eq!(settling.readings, 1);
+        assert_eq!(settling.spread, None);
+    }
+
+    #[test]
+    fn stops_when_unavailable() {
+        let readings = vec![Some([0., 0.]), None, Some([0., 0.])];
+        let settling =
+            wait_until_settled(&params(2, 1., Duration::from_secs(60)), source(readings)).unwrap();
+        assert_eq!(settling.outcome, Outcome::Unavailable);
+        assert_eq!(settling.readings, 1);
+    }
+
+    #[test]
+    fn propagates_read_errors() {
+        let readings = vec![Some([0., 0.])];
+        let result = wait_until_settled(&params(2, 1., Duration::from_secs(60)), source(readings));
+        assert_eq!(result.unwrap_err(), "exhausted");
+    }
+
+    #[test]
+    #[should_panic(expected = "at least two readings")]
+    fn rejects_a_window_of_one() {
+        let _ = wait_until_settled(&params(1, 1., Duration::ZERO), source(vec![]));
+    }
+}
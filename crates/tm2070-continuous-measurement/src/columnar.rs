@@ -0,0 +1,370 @@
+//! Parquet output.
+//!
+//! Rows are buffered and written as a row group on each flush. Because a Parquet file is
+//! unreadable until its footer is written on close, the metadata of each row group is also
+//! appended to a log next to the segment (`*.parquet.rowgroups`), which is removed once the
+//! segment is closed. [`recover`] writes the footer of a segment from its log.
+
+use std::{
+    collections::{BTreeMap, HashMap},
+    io::{Seek, SeekFrom, Write},
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+use anyhow::Context;
+use arrow_array::{
+    Array, ArrayRef, Float64Array, RecordBatch, StringArray, TimestampNanosecondArray, UInt64Array,
+};
+use arrow_schema::{DataType, Field, Schema, SchemaRef, TimeUnit};
+use chrono::{DateTime, Local};
+use fs_err::{File, OpenOptions};
+use parquet::{
+    arrow::{arrow_reader::ParquetRecordBatchReaderBuilder, ArrowWriter},
+    basic::{Compression, ZstdLevel},
+    file::{
+        metadata::{
+            FileMetaData, ParquetMetaData, ParquetMetaDataReader, ParquetMetaDataWriter,
+            RowGroupMetaData,
+        },
+        properties::WriterProperties,
+    },
+};
+use tm2070::{
+    record::{Marker, Record, SCHEMA_VERSION},
+    recording::{log_row_group, read_row_group_log, row_group_log_path},
+};
+
+use crate::sink::{Scan, Sink};
+
+/// Rows per row group at most, should flushes be far apart.
+const ROW_GROUP_SIZE: usize = 1 << 17;
+
+/// Columns:
+/// * `time`: arrival time, UTC timestamp in ns
+/// * `seq`, `source`, `unit`, `judge`: as in the record schema
+/// * `x`, `y`, `norm`: rad, null when unavailable
+/// * `direction`: degrees
+/// * `marker`: the text of a marker, null for samples
+///
+/// A marker row only has `time`, `source` and `marker`.
+/// The record schema version is stored in the metadata as `tm2070.record.version`.
+fn schema() -> SchemaRef {
+    let time = DataType::Timestamp(TimeUnit::Nanosecond, Some("UTC".into()));
+    let metadata = HashMap::from([(
+        "tm2070.record.version".to_owned(),
+        SCHEMA_VERSION.to_string(),
+    )]);
+    Arc::new(
+        Schema::new(vec![
+            Field::new("time", time, false),
+            Field::new("seq", DataType::UInt64, true),
+            Field::new("source", DataType::Utf8, false),
+            Field::new("unit", DataType::Utf8, true),
+            Field::new("x", DataType::Float64, true),
+            Field::new("y", DataType::Float64, true),
+            Field::new("norm", DataType::Float64, true),
+            Field::new("direction", DataType::Float64, true),
+            Field::new("judge", DataType::Utf8, true),
+            Field::new("marker", DataType::Utf8, true),
+        ])
+        .with_metadata(metadata),
+    )
+}
+
+#[derive(Default)]
+struct Rows {
+    time: Vec<i64>,
+    seq: Vec<Option<u64>>,
+    source: Vec<String>,
+    unit: Vec<Option<String>>,
+    x: Vec<Option<f64>>,
+    y: Vec<Option<f64>>,
+    norm: Vec<Option<f64>>,
+    direction: Vec<Option<f64>>,
+    judge: Vec<Option<String>>,
+    marker: Vec<Option<String>>,
+}
+impl Rows {
+    fn push(&mut self, record: &Record) -> anyhow::Result<()> {
+        let rad = |a: Option<tm2070::angle::Angle>| a.map(|a| a.value().val());
+        let data = &record.data;
+        let time = record
+            .time
+            .timestamp_nanos_opt()
+            .context("Time out of range")?;
+        self.time.push(time);
+        self.seq.push(Some(record.seq));
+        self.source.push(record.source.to_string());
+        self.unit.push(Some(data.unit.to_string()));
+        self.x.push(rad(data.x));
+        self.y.push(rad(data.y));
+        self.norm.push(rad(data.norm));
+        self.direction.push(Some(data.direction.0.val()));
+        (self.judge).push(Some(
+            String::from_utf8_lossy(data.judge.as_bytes()).into_owned(),
+        ));
+        self.marker.push(None);
+        Ok(())
+    }
+
+    fn push_marker(&mut self, marker: &Marker) -> anyhow::Result<()> {
+        let time = marker
+            .time
+            .timestamp_nanos_opt()
+            .context("Time out of range")?;
+        self.time.push(time);
+        self.seq.push(None);
+        self.source.push(marker.source.to_string());
+        self.unit.push(None);
+        self.x.push(None);
+        self.y.push(None);
+        self.norm.push(None);
+        self.direction.push(None);
+        self.judge.push(None);
+        self.marker.push(Some(marker.marker.to_string()));
+        Ok(())
+    }
+
+    fn len(&self) -> usize {
+        self.time.len()
+    }
+
+    fn take_batch(&mut self) -> anyhow::Result<RecordBatch> {
+        let rows = std::mem::take(self);
+        let columns: Vec<ArrayRef> = vec![
+            Arc::new(TimestampNanosecondArray::from(rows.time).with_timezone("UTC")),
+            Arc::new(UInt64Array::from(rows.seq)),
+            Arc::new(StringArray::from(rows.source)),
+            Arc::new(StringArray::from(rows.unit)),
+            Arc::new(Float64Array::from(rows.x)),
+            Arc::new(Float64Array::from(rows.y)),
+            Arc::new(Float64Array::from(rows.norm)),
+            Arc::new(Float64Array::from(rows.direction)),
+            Arc::new(StringArray::from(rows.judge)),
+            Arc::new(StringArray::from(rows.marker)),
+        ];
+        Ok(RecordBatch::try_new(schema(), columns)?)
+    }
+}
+
+fn properties() -> WriterProperties {
+    WriterProperties::builder()
+        .set_compression(Compression::ZSTD(ZstdLevel::default()))
+        .build()
+}
+
+/// Magic number at the start and end of a Parquet file.
+const MAGIC: &[u8] = b"PAR1";
+
+/// Writes a Parquet file row group by row group, each reaching the disk on flush.
+///
+/// [`ArrowWriter`] holds back what it writes until the file is closed, so each row group is
+/// encoded by its own writer in memory and its column chunks are appended to the file here.
+pub struct ParquetSink {
+    file: File,
+    /// Bytes written to `file`.
+    position: u64,
+    row_groups: Vec<RowGroupMetaData>,
+    rows: Rows,
+    log_path: PathBuf,
+    log: File,
+    /// Row groups written to the log so far.
+    logged: usize,
+}
+
+impl ParquetSink {
+    pub fn create(path: &Path) -> anyhow::Result<Self> {
+        let mut file = File::create(path)?;
+        file.write_all(MAGIC)?;
+        let log_path = row_group_log_path(path);
+        Ok(Self {
+            file,
+            position: MAGIC.len() as u64,
+            row_groups: vec![],
+            rows: Rows::default(),
+            log: File::create(&log_path)?,
+            log_path,
+            logged: 0,
+        })
+    }
+
+    fn write_row_group(&mut self) -> anyhow::Result<()> {
+        if self.rows.len() == 0 {
+            return Ok(());
+        }
+        let batch = self.rows.take_batch()?;
+        let mut buf = vec![];
+        let mut writer = ArrowWriter::try_new(&mut buf, schema(), Some(properties()))?;
+        writer.write(&batch)?;
+        writer.flush()?;
+        let row_group = writer.flushed_row_groups()[0].clone();
+        writer.close()?;
+
+        // The column chunks follow the magic number; the page indexes and footer are left out
+        let end = chunks_end(std::slice::from_ref(&row_group)) as usize;
+        self.file.write_all(&buf[MAGIC.len()..end])?;
+        let shift = (self.position - MAGIC.len() as u64) as i64;
+        self.position += (end - MAGIC.len()) as u64;
+
+        let shifted = |offset: i64| offset + shift;
+        let columns = (row_group.columns().iter())
+            .map(|column| {
+                (column.clone().into_builder())
+                    .set_data_page_offset(shifted(column.data_page_offset()))
+                    .set_dictionary_page_offset(column.dictionary_page_offset().map(shifted))
+                    .set_index_page_offset(column.index_page_offset().map(shifted))
+                    .set_offset_index_offset(None)
+                    .set_offset_index_length(None)
+                    .set_column_index_offset(None)
+                    .set_column_index_length(None)
+                    .build()
+            })
+            .collect::<Result<_, _>>()?;
+        let mut builder = (row_group.clone().into_builder()).set_column_metadata(columns);
+        if let Some(offset) = row_group.file_offset() {
+            builder = builder.set_file_offset(shifted(offset));
+        }
+        self.row_groups.push(builder.build()?);
+        Ok(())
+    }
+}
+
+impl Sink for ParquetSink {
+    fn write(&mut self, record: &Record) -> anyhow::Result<()> {
+        self.rows.push(record)?;
+        if self.rows.len() >= ROW_GROUP_SIZE {
+            self.write_row_group()?;
+        }
+        Ok(())
+    }
+
+    fn mark(&mut self, marker: &Marker) -> anyhow::Result<()> {
+        self.rows.push_marker(marker)
+    }
+
+    /// Writes the buffered rows as a row group and logs it once it is on the disk.
+    fn flush(&mut self) -> anyhow::Result<()> {
+        self.write_row_group()?;
+        if self.logged == self.row_groups.len() {
+            return Ok(());
+        }
+        self.file.sync_data()?;
+        for row_group in &self.row_groups[self.logged..] {
+            log_row_group(&mut self.log, row_group)?;
+        }
+        self.log.sync_data()?;
+        self.logged = self.row_groups.len();
+        Ok(())
+    }
+
+    /// Size of the row groups written so far plus an estimate for the buffered rows.
+    fn bytes(&self) -> u64 {
+        // Roughly the compressed size of a row
+        const ROW_BYTES: u64 = 24;
+        self.position + self.rows.len() as u64 * ROW_BYTES
+    }
+
+    fn finish(mut self: Box<Self>) -> anyhow::Result<()> {
+        self.write_row_group()?;
+        let metadata = footer(std::mem::take(&mut self.row_groups))?;
+        ParquetMetaDataWriter::new(&mut self.file, &metadata).finish()?;
+        self.file.sync_all()?;
+        drop(self.log);
+        fs_err::remove_file(&self.log_path)?;
+        Ok(())
+    }
+}
+
+/// End of the last column chunk of `row_groups`, or of the magic number if there are none.
+fn chunks_end(row_groups: &[RowGroupMetaData]) -> u64 {
+    (row_groups.iter())
+        .flat_map(|row_group| row_group.columns())
+        .map(|column| {
+            let (start, len) = column.byte_range();
+            start + len
+        })
+        .max()
+        .unwrap_or(MAGIC.len() as u64)
+}
+
+/// Metadata of a file holding `row_groups`, with the schema and key-value metadata
+/// that [`ArrowWriter`] writes, taken from the footer of an empty file.
+fn footer(row_groups: Vec<RowGroupMetaData>) -> anyhow::Result<ParquetMetaData> {
+    let mut empty = vec![];
+    ArrowWriter::try_new(&mut empty, schema(), Some(properties()))?.close()?;
+    let (rest, len) = empty.split_at(empty.len() - MAGIC.len() - 4);
+    let len = u32::from_le_bytes(len[..4].try_into()?) as usize;
+    let empty = ParquetMetaDataReader::decode_metadata(&rest[rest.len() - len..])?;
+    let empty = empty.file_metadata();
+    let file_metadata = FileMetaData::new(
+        empty.version(),
+        row_groups.iter().map(RowGroupMetaData::num_rows).sum(),
+        empty.created_by().map(str::to_owned),
+        empty.key_value_metadata().cloned(),
+        empty.schema_descr_ptr(),
+        empty.column_orders().cloned(),
+    );
+    Ok(ParquetMetaData::new(file_metadata, row_groups))
+}
+
+/// Writes the footer of the segment at `path` from its row group log if it was not closed,
+/// then reads it back. Rows after the last logged row group are lost.
+pub fn recover(path: &Path) -> anyhow::Result<Scan> {
+    let log = row_group_log_path(path);
+    if log.exists() {
+        if read_scan(path).is_err() {
+            let row_groups = read_row_group_log(&log)?;
+            let mut file = OpenOptions::new().write(true).open(path)?;
+            file.set_len(chunks_end(&row_groups))?;
+            file.seek(SeekFrom::End(0))?;
+            ParquetMetaDataWriter::new(&mut file, &footer(row_groups)?).finish()?;
+            file.sync_all()?;
+        }
+        // Otherwise closed, but the log was not removed yet
+        fs_err::remove_file(&log)?;
+    }
+    read_scan(path)
+}
+
+fn read_scan(path: &Path) -> anyhow::Result<Scan> {
+    let (file, _) = File::open(path)?.into_parts();
+    let bytes = file.metadata()?.len();
+    let reader = ParquetRecordBatchReaderBuilder::try_new(file)?.build()?;
+    let mut scan = Scan {
+        bytes,
+        ..Scan::default()
+    };
+    let mut records_per_source = BTreeMap::<String, u64>::new();
+    for batch in reader {
+        let batch = batch?;
+        let column = |name| batch.column_by_name(name).context("Missing column");
+        let time = column("time")?;
+        let time = (time.as_any().downcast_ref::<TimestampNanosecondArray>())
+            .context("Unexpected type of time")?;
+        let source = column("source")?;
+        let source =
+            (source.as_any().downcast_ref::<StringArray>()).context("Unexpected type of source")?;
+        // Missing in segments written before markers
+        let marker = batch.column_by_name("marker");
+        let mut markers = 0;
+        for i in 0..batch.num_rows() {
+            if marker.is_some_and(|marker| marker.is_valid(i)) {
+                markers += 1;
+                continue;
+            }
+            *records_per_source
+                .entry(source.value(i).to_owned())
+                .or_default() += 1;
+            if !time.is_null(i) {
+                let t: DateTime<Local> = DateTime::from_timestamp_nanos(time.value(i)).into();
+                scan.first_time.get_or_insert(t);
+                scan.last_time = Some(t);
+            }
+        }
+        scan.records += (batch.num_rows() - markers) as u64;
+        scan.markers += markers as u64;
+    }
+    scan.records_per_source = records_per_source;
+    Ok(scan)
+}
@@ -1,24 +1,156 @@
-use std::{borrow::Cow, path::PathBuf, sync::mpsc, thread::sleep, time::Duration};
+mod columnar;
+mod markers;
+mod recorder;
+mod report;
+mod sink;
 
-use chrono::{DateTime, Local};
+use std::{
+    num::NonZeroUsize,
+    path::PathBuf,
+    sync::{
+        atomic::{AtomicBool, Ordering::SeqCst},
+        mpsc::{self, RecvTimeoutError},
+    },
+    thread::{self, sleep},
+    time::{Duration, Instant},
+};
+
+use anyhow::{bail, ensure};
 use clap::Parser;
-use fs_err::File;
-use log::error;
-use serde::{Deserialize, Serialize};
-use tm2070::Tm2070;
+use log::{error, info, warn};
+use markers::Markers;
+use radians::Rad64;
+use recorder::{Recorder, Rotation};
+use report::Reporter;
+use sink::Format;
+use tm2070::{
+    alarm::{AlarmMonitor, Condition, EventKind, Rule},
+    net::Publisher,
+    record::Record,
+    Tm2070, Tm2070Error,
+};
 
 #[derive(Parser)]
 struct Opts {
+    /// Each run records into a new session directory here
     save_dir: PathBuf,
     com_ports: Vec<String>,
+    /// Stop after this long, e.g. `90s`, `30m`, `12h` or `3d` (a plain number is seconds)
+    #[clap(long, value_parser = parse_duration)]
+    duration: Option<Duration>,
+    /// Stop once every head has sent this many samples
+    #[clap(long)]
+    max_samples: Option<u64>,
+    /// Have the heads send every n-th measurement only (`L,n`)
+    #[clap(long)]
+    interval: Option<NonZeroUsize>,
+    /// Record in bursts of this long, one every `--burst-every`
+    #[clap(long, value_parser = parse_duration, requires = "burst_every")]
+    burst_length: Option<Duration>,
+    /// Start a burst this often, e.g. `--burst-length 10s --burst-every 5m`
+    #[clap(long, value_parser = parse_duration, requires = "burst_length")]
+    burst_every: Option<Duration>,
+    #[clap(long, value_enum, default_value = "jsonl")]
+    format: Format,
+    /// Start a new segment once the current one exceeds this size in MB
+    #[clap(long, value_parser = parse_megabytes)]
+    rotate_size: Option<u64>,
+    /// Start a new segment after this many minutes
+    #[clap(long, value_parser = parse_minutes)]
+    rotate_interval: Option<Duration>,
+    /// Time between flushes to the disk (a plain number is seconds)
+    #[clap(long, default_value = "1.0", value_parser = parse_duration)]
+    flush_interval: Duration,
+    /// Continue the latest unfinished session in a new segment, e.g. after a crash
+    #[clap(long)]
+    resume: bool,
+    /// Consecutive samples needed to raise or clear an alarm
+    #[clap(long, default_value = "3")]
+    alarm_debounce: NonZeroUsize,
+    /// Raise an alarm when x or y is farther than this (µrad) from the zero of the head
+    #[clap(long)]
+    alarm_tolerance: Option<f64>,
+    /// Raise an alarm when the drift over the last 10 s exceeds this (µrad/s)
+    #[clap(long)]
+    alarm_drift: Option<f64>,
+    /// Time between reports of the statistics of each head (a plain number is seconds)
+    #[clap(long, default_value = "60", value_parser = parse_duration)]
+    stats_interval: Duration,
+    /// Time span of the latest samples the statistics are computed over (a plain number is seconds).
+    /// With `--burst-every`, only the samples of the current burst are used
+    #[clap(long, default_value = "600", value_parser = parse_duration)]
+    stats_window: Duration,
+    /// Also serve the samples as JSON lines over TCP on this address, e.g. `127.0.0.1:7070`
+    #[clap(long)]
+    publish: Option<String>,
+    /// Record each line typed into the terminal as a marker
+    #[clap(long)]
+    stdin_markers: bool,
+    /// Accept markers over TCP on this address, e.g. `127.0.0.1:7071`: one per line,
+    /// as plain text or as sent by `pamc112-experiment --annotate`
+    #[clap(long)]
+    marker_socket: Option<String>,
+}
+
+fn alarm_monitor(opts: &Opts) -> AlarmMonitor {
+    let debounce = |rule: Rule| rule.debounce(opts.alarm_debounce.get(), opts.alarm_debounce.get());
+    let mut monitor = AlarmMonitor::new();
+    monitor.add_rule(debounce(Rule::new("judge NG", Condition::JudgeNg)));
+    monitor.add_rule(debounce(Rule::new("beam lost", Condition::BeamLost)));
+    if let Some(tolerance) = opts.alarm_tolerance {
+        let half_width = Rad64::new(tolerance * 1e-6);
+        monitor.add_rule(debounce(Rule::new(
+            "out of tolerance",
+            Condition::OutsideBox {
+                center: [Rad64::new(0.); 2],
+                half_width: [half_width; 2],
+                hysteresis: Rad64::new(tolerance * 1e-7),
+            },
+        )));
+    }
+    if let Some(limit) = opts.alarm_drift {
+        monitor.add_rule(debounce(Rule::new(
+            "drift",
+            Condition::DriftRate {
+                limit: limit * 1e-6,
+                hysteresis: limit * 1e-7,
+                window: Duration::from_secs(10),
+            },
+        )));
+    }
+    monitor
+}
+
+/// Parses a number of seconds, optionally followed by `s`, `m`, `h` or `d`.
+fn parse_duration(s: &str) -> anyhow::Result<Duration> {
+    let s = s.trim();
+    let (number, scale) = match s.chars().last() {
+        Some('s') => (&s[..s.len() - 1], 1.),
+        Some('m') => (&s[..s.len() - 1], 60.),
+        Some('h') => (&s[..s.len() - 1], 3600.),
+        Some('d') => (&s[..s.len() - 1], 86400.),
+        _ => (s, 1.),
+    };
+    let seconds: f64 = number.trim().parse()?;
+    Ok(Duration::try_from_secs_f64(seconds * scale)?)
+}
+
+/// Parses a positive size in MB into bytes.
+fn parse_megabytes(s: &str) -> anyhow::Result<u64> {
+    let mb: f64 = s.trim().parse()?;
+    ensure!(
+        mb.is_finite() && mb * 1e6 >= 1.,
+        "Expected a size of at least 1e-6 MB, found {s}"
+    );
+    Ok((mb * 1e6) as u64)
 }
 
-#[derive(Serialize, Deserialize)]
-struct Record<'a> {
-    time: DateTime<Local>,
-    com_port: Cow<'a, str>,
-    x: Option<f64>,
-    y: Option<f64>,
+/// Parses a positive number of minutes.
+fn parse_minutes(s: &str) -> anyhow::Result<Duration> {
+    let minutes: f64 = s.trim().parse()?;
+    let duration = Duration::try_from_secs_f64(minutes * 60.)?;
+    ensure!(!duration.is_zero(), "Expected a positive number of minutes");
+    Ok(duration)
 }
 
 fn main() -> anyhow::Result<()> {
@@ -34,45 +166,183 @@ fn main() -> anyhow::Result<()> {
         }
     })?;
 
+    if let (Some(length), Some(every)) = (opts.burst_length, opts.burst_every) {
+        if length >= every {
+            bail!("--burst-length must be shorter than --burst-every");
+        }
+    }
+    let interval = opts.interval.map(NonZeroUsize::get);
+
     let mut tm2070s = opts
         .com_ports
         .iter()
         .map(|port| anyhow::Ok((port, Tm2070::new(port)?)))
         .collect::<Result<Vec<_>, _>>()?;
-    let mut handles = tm2070s
-        .iter_mut()
-        .map(|(port, tm2070)| anyhow::Ok((*port, tm2070.continuous_1(None)?)))
-        .collect::<Result<Vec<_>, _>>()?;
-    let mut records = vec![];
-    while tx.try_recv().is_err() {
-        for (port, handle) in &mut handles {
-            for data in handle.iter() {
-                let data = match data {
-                    Ok(data) => data,
+    let mut monitors = tm2070s
+        .iter()
+        .map(|(port, _)| {
+            let mut monitor = alarm_monitor(&opts);
+            let name = port.to_string();
+            monitor.on_event(move |event| match event.kind {
+                EventKind::Raised => warn!("{name}: {event}"),
+                EventKind::Cleared => info!("{name}: {event}"),
+            });
+            monitor
+        })
+        .collect::<Vec<_>>();
+    let rotation = Rotation {
+        max_bytes: opts.rotate_size,
+        max_duration: opts.rotate_interval,
+        flush_interval: opts.flush_interval,
+    };
+    let mut recorder = if opts.resume {
+        Recorder::resume(&opts.save_dir, rotation)?
+    } else {
+        Recorder::create(&opts.save_dir, opts.format, rotation)?
+    };
+    let publisher = opts.publish.as_deref().map(Publisher::bind).transpose()?;
+    let markers = Markers::new();
+    if opts.stdin_markers {
+        markers.read_stdin();
+    }
+    if let Some(addr) = &opts.marker_socket {
+        markers.listen(addr)?;
+    }
+    let mut reporter = Reporter::new(
+        recorder.dir(),
+        opts.com_ports.iter().map(String::as_str),
+        opts.stats_window,
+        opts.stats_interval,
+    )?;
+
+    let started = Instant::now();
+    let deadline = opts.duration.map(|duration| started + duration);
+    let is_over = |at: Instant| deadline.is_some_and(|deadline| at >= deadline);
+    let mut counts = vec![0; tm2070s.len()];
+    let is_full = |count: u64| opts.max_samples.is_some_and(|max| count >= max);
+    'run: for burst in 0.. {
+        let burst_start = started + opts.burst_every.unwrap_or_default() * burst;
+        while Instant::now() < burst_start {
+            if tx.try_recv().is_ok() || is_over(Instant::now()) {
+                break 'run;
+            }
+            write_markers(&markers, &mut recorder)?;
+            recorder.poll()?;
+            reporter.poll()?;
+            sleep(Duration::from_millis(100));
+        }
+        let burst_end = opts.burst_length.map(|length| burst_start + length);
+        if opts.burst_every.is_some() {
+            info!("Starting burst {burst}");
+            reporter.clear();
+        }
+
+        // Each head is read by its own thread, so that samples are handled as they arrive
+        let stops = (counts.iter())
+            .map(|&count| AtomicBool::new(is_full(count)))
+            .collect::<Vec<_>>();
+        let end_run = thread::scope(|scope| {
+            let (sample_tx, sample_rx) = mpsc::channel();
+            let mut started = Ok(());
+            for (head, (port, tm2070)) in tm2070s.iter_mut().enumerate() {
+                if stops[head].load(SeqCst) {
+                    continue;
+                }
+                let handle = match tm2070.continuous_1(interval) {
+                    Ok(handle) => handle,
                     Err(e) => {
-                        error!("Error: {e:#}");
-                        continue;
+                        started = Err(e);
+                        break;
                     }
                 };
-                let record = Record {
-                    time: Local::now(),
-                    com_port: Cow::Borrowed(*port),
-                    x: data.x.map(|x| x.value().val()),
-                    y: data.y.map(|y| y.value().val()),
-                };
-                records.push(record);
+                let (sample_tx, stop, port) = (sample_tx.clone(), &stops[head], *port);
+                scope.spawn(move || {
+                    while !stop.load(SeqCst) {
+                        let res = match handle.recv_timeout(Duration::from_millis(100)) {
+                            Ok(None) => continue,
+                            Ok(Some(sample)) => Ok(sample),
+                            Err(e) => Err(e),
+                        };
+                        let lost = matches!(res, Err(Tm2070Error::LinkLost));
+                        if sample_tx.send((head, res)).is_err() || lost {
+                            break;
+                        }
+                    }
+                    if let Err(e) = handle.close() {
+                        error!("{port}: Failed to close continuous fetch: {e:#}");
+                    }
+                });
+            }
+            drop(sample_tx);
+            let res = started.map_err(anyhow::Error::from).and_then(|()| loop {
+                if tx.try_recv().is_ok() {
+                    return anyhow::Ok(true);
+                }
+                // Wakes up at least this often to flush, report and write markers
+                let now = Instant::now();
+                let wake = [deadline, burst_end]
+                    .into_iter()
+                    .flatten()
+                    .fold(now + Duration::from_millis(100), Instant::min);
+                match sample_rx.recv_timeout(wake.saturating_duration_since(now)) {
+                    // Samples still on the way after reaching the count are dropped
+                    Ok((head, Ok(sample))) if !stops[head].load(SeqCst) => {
+                        let port = &opts.com_ports[head];
+                        monitors[head].check(&sample);
+                        reporter.push(head, &sample);
+                        let record = Record::new(port, sample);
+                        recorder.write(&record)?;
+                        if let Some(publisher) = &publisher {
+                            publisher.publish(&record)?;
+                        }
+                        counts[head] += 1;
+                        if is_full(counts[head]) {
+                            info!("{port}: Reached {} samples", counts[head]);
+                            // Stops sampling on the head
+                            stops[head].store(true, SeqCst);
+                        }
+                    }
+                    Ok((_, Ok(_))) => {}
+                    Ok((head, Err(e))) => error!("{}: {e:#}", opts.com_ports[head]),
+                    Err(RecvTimeoutError::Timeout) => {}
+                    // Every head has stopped
+                    Err(RecvTimeoutError::Disconnected) => return Ok(true),
+                }
+                write_markers(&markers, &mut recorder)?;
+                recorder.poll()?;
+                reporter.poll()?;
+                let now = Instant::now();
+                if is_over(now) {
+                    info!("Reached the duration");
+                    return Ok(true);
+                }
+                if burst_end.is_some_and(|end| now >= end) {
+                    return Ok(false);
+                }
+            });
+            // Also on failure, as the scope waits for every thread
+            for stop in &stops {
+                stop.store(true, SeqCst);
             }
+            res
+        })?;
+        if end_run {
+            break 'run;
+        }
+        if opts.burst_every.is_none() {
+            break;
         }
-        sleep(Duration::from_millis(10));
     }
 
-    serde_json::to_writer(
-        File::create(
-            opts.save_dir
-                .join(format!("{}.json", Local::now().format("%Y-%m-%d_%H-%M-%S"))),
-        )?,
-        &records,
-    )?;
+    write_markers(&markers, &mut recorder)?;
+    recorder.finish()
+}
 
+/// Writes the markers that arrived since the last call.
+fn write_markers(markers: &Markers, recorder: &mut Recorder) -> anyhow::Result<()> {
+    for marker in markers.try_iter() {
+        info!("{}: Marker {:?}", marker.source, marker.marker);
+        recorder.mark(&marker)?;
+    }
     Ok(())
 }
@@ -0,0 +1,98 @@
+//! Markers arriving while recording.
+//!
+//! Each line is one marker: either a [`Marker`] in JSON, as sent by
+//! [`tm2070::net::Annotator`], or plain text, which is stamped with the time it arrived.
+
+use std::{
+    io::{self, BufRead, BufReader},
+    net::{TcpListener, ToSocketAddrs},
+    sync::mpsc,
+    thread,
+};
+
+use log::{error, info, warn};
+use tm2070::record::Marker;
+
+pub struct Markers {
+    tx: mpsc::Sender<Marker<'static>>,
+    rx: mpsc::Receiver<Marker<'static>>,
+}
+
+impl Markers {
+    pub fn new() -> Self {
+        let (tx, rx) = mpsc::channel();
+        Self { tx, rx }
+    }
+
+    /// Reads markers typed into the terminal, named `stdin`.
+    pub fn read_stdin(&self) {
+        let tx = self.tx.clone();
+        thread::spawn(move || read_lines(io::stdin().lock(), "stdin", tx));
+        info!("Type a line and press Enter to mark the recording");
+    }
+
+    /// Accepts markers over TCP. Plain text is named after the address of the client.
+    pub fn listen(&self, addr: impl ToSocketAddrs) -> io::Result<()> {
+        let listener = TcpListener::bind(addr)?;
+        info!("Accepting markers on {}", listener.local_addr()?);
+        let tx = self.tx.clone();
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                let stream = match stream {
+                    Ok(stream) => stream,
+                    Err(e) => {
+                        error!("Failed to accept a marker client: {e}");
+                        continue;
+                    }
+                };
+                let source = match stream.peer_addr() {
+                    Ok(addr) => addr.to_string(),
+                    Err(_) => "socket".to_owned(),
+                };
+                let tx = tx.clone();
+                thread::spawn(move || read_lines(BufReader::new(stream), &source, tx));
+            }
+        });
+        Ok(())
+    }
+
+    /// Markers received since the last call.
+    pub fn try_iter(&self) -> impl Iterator<Item = Marker<'static>> + '_ {
+        self.rx.try_iter()
+    }
+}
+
+fn read_lines(reader: impl BufRead, source: &str, tx: mpsc::Sender<Marker<'static>>) {
+    for line in reader.lines() {
+        let line = match line {
+            Ok(line) => line,
+            Err(e) => {
+                warn!("{source}: {e}");
+                return;
+            }
+        };
+        let Some(marker) = parse(&line, source) else {
+            continue;
+        };
+        if tx.send(marker).is_err() {
+            return;
+        }
+    }
+}
+
+fn parse(line: &str, source: &str) -> Option<Marker<'static>> {
+    let line = line.trim();
+    if line.is_empty() {
+        return None;
+    }
+    if line.starts_with('{') {
+        match serde_json::from_str::<Marker>(line) {
+            Ok(marker) => match marker.check_version() {
+                Ok(()) => return Some(marker),
+                Err(e) => warn!("{source}: {e:#}"),
+            },
+            Err(e) => warn!("{source}: Not a marker ({e}); recording the line as text"),
+        }
+    }
+    Some(Marker::new(source.to_owned(), line.to_owned()))
+}
@@ -0,0 +1,268 @@
+//! Writes records into a session directory as they arrive.
+//!
+//! A session directory holds numbered segments and `index.toml`, which lists the segments
+//! with their time ranges, record counts and marker counts. The index is rewritten on every flush,
+//! so after a crash it is at most one flush interval behind; resuming the session
+//! recovers the segment that was open and continues into a new one.
+
+use std::{
+    collections::BTreeMap,
+    path::{Path, PathBuf},
+    time::{Duration, Instant},
+};
+
+use anyhow::{bail, Context};
+use chrono::{DateTime, Local};
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+use tm2070::record::{Marker, Record};
+
+use crate::sink::{Format, Sink};
+
+const INDEX_FILE: &str = "index.toml";
+
+#[derive(Serialize, Deserialize)]
+struct Index {
+    session: String,
+    format: Format,
+    started_at: DateTime<Local>,
+    /// Missing while the session is being recorded or if it was interrupted.
+    finished_at: Option<DateTime<Local>>,
+    records: u64,
+    records_per_source: BTreeMap<String, u64>,
+    #[serde(default)]
+    markers: u64,
+    segments: Vec<Segment>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct Segment {
+    file: String,
+    opened_at: DateTime<Local>,
+    closed_at: Option<DateTime<Local>>,
+    records: u64,
+    bytes: u64,
+    first_time: Option<DateTime<Local>>,
+    last_time: Option<DateTime<Local>>,
+    records_per_source: BTreeMap<String, u64>,
+    #[serde(default)]
+    markers: u64,
+    /// The segment was left open by an interrupted run and read back on resume.
+    #[serde(default)]
+    recovered: bool,
+}
+
+pub struct Rotation {
+    /// Start a new segment once the current one exceeds this many bytes.
+    pub max_bytes: Option<u64>,
+    /// Start a new segment once the current one has been open this long.
+    pub max_duration: Option<Duration>,
+    pub flush_interval: Duration,
+}
+
+pub struct Recorder {
+    dir: PathBuf,
+    index: Index,
+    rotation: Rotation,
+    sink: Option<Box<dyn Sink>>,
+    opened: Instant,
+    last_flush: Instant,
+}
+
+impl Recorder {
+    /// Starts a new session in a directory named after the current time.
+    pub fn create(save_dir: &Path, format: Format, rotation: Rotation) -> anyhow::Result<Self> {
+        let started_at = Local::now();
+        let session = started_at.format("%Y-%m-%d_%H-%M-%S").to_string();
+        let dir = save_dir.join(&session);
+        if dir.exists() {
+            bail!("{} already exists", dir.display());
+        }
+        fs_err::create_dir_all(&dir)?;
+        let index = Index {
+            session,
+            format,
+            started_at,
+            finished_at: None,
+            records: 0,
+            records_per_source: BTreeMap::new(),
+            markers: 0,
+            segments: vec![],
+        };
+        Self::start(dir, index, rotation)
+    }
+
+    /// Continues the latest session in `save_dir` that was not finished, in a new segment.
+    /// The format of the session is kept.
+    pub fn resume(save_dir: &Path, rotation: Rotation) -> anyhow::Result<Self> {
+        let mut sessions = vec![];
+        for entry in fs_err::read_dir(save_dir)? {
+            let path = entry?.path();
+            if path.join(INDEX_FILE).is_file() {
+                sessions.push(path);
+            }
+        }
+        sessions.sort();
+        let Some(dir) = sessions.pop() else {
+            bail!("No session found in {}", save_dir.display());
+        };
+        let index_path = dir.join(INDEX_FILE);
+        let mut index: Index = toml::from_str(&fs_err::read_to_string(&index_path)?)
+            .with_context(|| format!("Failed to read {}", index_path.display()))?;
+        if index.finished_at.is_some() {
+            bail!("The latest session {} has finished", index.session);
+        }
+        for segment in index.segments.iter_mut().filter(|s| s.closed_at.is_none()) {
+            let path = dir.join(&segment.file);
+            let scan = index.format.recover(&path)?;
+            warn!(
+                "Recovered {} records from {} (index had {})",
+                scan.records,
+                path.display(),
+                segment.records
+            );
+            segment.records = scan.records;
+            segment.bytes = scan.bytes;
+            segment.records_per_source = scan.records_per_source;
+            segment.markers = scan.markers;
+            segment.first_time = scan.first_time;
+            segment.last_time = scan.last_time;
+            segment.closed_at = Some(fs_err::metadata(&path)?.modified()?.into());
+            segment.recovered = true;
+        }
+        index.records = index.segments.iter().map(|s| s.records).sum();
+        index.markers = index.segments.iter().map(|s| s.markers).sum();
+        index.records_per_source.clear();
+        for segment in &index.segments {
+            for (source, count) in &segment.records_per_source {
+                *index.records_per_source.entry(source.clone()).or_default() += count;
+            }
+        }
+        info!("Resuming session {}", index.session);
+        Self::start(dir, index, rotation)
+    }
+
+    fn start(dir: PathBuf, index: Index, rotation: Rotation) -> anyhow::Result<Self> {
+        let now = Instant::now();
+        let mut ret = Self {
+            dir,
+            index,
+            rotation,
+            sink: None,
+            opened: now,
+            last_flush: now,
+        };
+        ret.open_segment()?;
+        Ok(ret)
+    }
+
+    /// The session directory.
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    pub fn write(&mut self, record: &Record) -> anyhow::Result<()> {
+        self.sink.as_mut().unwrap().write(record)?;
+        let segment = self.index.segments.last_mut().unwrap();
+        segment.records += 1;
+        segment.first_time.get_or_insert(record.time);
+        segment.last_time = Some(record.time);
+        *(segment.records_per_source)
+            .entry(record.source.to_string())
+            .or_default() += 1;
+        self.index.records += 1;
+        *(self.index.records_per_source)
+            .entry(record.source.to_string())
+            .or_default() += 1;
+        self.poll()
+    }
+
+    /// Writes a marker and flushes, so that it is on the disk right away.
+    pub fn mark(&mut self, marker: &Marker) -> anyhow::Result<()> {
+        self.sink.as_mut().unwrap().mark(marker)?;
+        self.index.segments.last_mut().unwrap().markers += 1;
+        self.index.markers += 1;
+        self.flush()
+    }
+
+    /// Flushes and rotates as due. Call regularly even when no records arrive.
+    pub fn poll(&mut self) -> anyhow::Result<()> {
+        let sink = self.sink.as_ref().unwrap();
+        let too_large = (self.rotation.max_bytes).is_some_and(|max| sink.bytes() >= max);
+        let too_old = (self.rotation.max_duration).is_some_and(|max| self.opened.elapsed() >= max);
+        if too_large || too_old {
+            self.close_segment()?;
+            self.open_segment()?;
+        } else if self.last_flush.elapsed() >= self.rotation.flush_interval {
+            self.flush()?;
+        }
+        Ok(())
+    }
+
+    /// Closes the last segment and marks the session as finished.
+    pub fn finish(mut self) -> anyhow::Result<()> {
+        self.close_segment()?;
+        self.index.finished_at = Some(Local::now());
+        self.write_index()?;
+        info!(
+            "Saved {} records in {} segments to {}",
+            self.index.records,
+            self.index.segments.len(),
+            self.dir.display()
+        );
+        Ok(())
+    }
+
+    fn flush(&mut self) -> anyhow::Result<()> {
+        let sink = self.sink.as_mut().unwrap();
+        sink.flush()?;
+        self.index.segments.last_mut().unwrap().bytes = sink.bytes();
+        self.last_flush = Instant::now();
+        self.write_index()
+    }
+
+    fn open_segment(&mut self) -> anyhow::Result<()> {
+        let file = format!(
+            "segment_{:04}.{}",
+            self.index.segments.len(),
+            self.index.format.extension()
+        );
+        let path = self.dir.join(&file);
+        info!("Writing to {}", path.display());
+        self.sink = Some(self.index.format.create(&path)?);
+        self.index.segments.push(Segment {
+            file,
+            opened_at: Local::now(),
+            closed_at: None,
+            records: 0,
+            bytes: 0,
+            first_time: None,
+            last_time: None,
+            records_per_source: BTreeMap::new(),
+            markers: 0,
+            recovered: false,
+        });
+        self.opened = Instant::now();
+        self.flush()
+    }
+
+    fn close_segment(&mut self) -> anyhow::Result<()> {
+        let mut sink = self.sink.take().unwrap();
+        sink.flush()?;
+        let bytes = sink.bytes();
+        sink.finish()?;
+        let segment = self.index.segments.last_mut().unwrap();
+        segment.bytes = bytes;
+        segment.closed_at = Some(Local::now());
+        self.write_index()
+    }
+
+    /// Replaces the index atomically, so that a crash never leaves it half written.
+    fn write_index(&self) -> anyhow::Result<()> {
+        let path = self.dir.join(INDEX_FILE);
+        let tmp = path.with_extension("toml.tmp");
+        fs_err::write(&tmp, toml::to_string(&self.index)?)?;
+        fs_err::rename(&tmp, &path)?;
+        Ok(())
+    }
+}
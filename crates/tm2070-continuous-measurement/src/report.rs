@@ -0,0 +1,113 @@
+//! Periodic stability statistics of each head, logged and appended to `stats.jsonl`.
+
+use std::{
+    io::{BufWriter, Write},
+    path::Path,
+    time::{Duration, Instant},
+};
+
+use chrono::{DateTime, Local};
+use fs_err::{File, OpenOptions};
+use log::info;
+use serde::Serialize;
+use tm2070::{
+    stats::{Statistics, Summary},
+    Sample,
+};
+
+const SIDECAR_FILE: &str = "stats.jsonl";
+
+#[derive(Serialize)]
+struct Entry<'a> {
+    time: DateTime<Local>,
+    source: &'a str,
+    #[serde(flatten)]
+    summary: &'a Summary,
+}
+
+pub struct Reporter {
+    heads: Vec<(String, Statistics)>,
+    interval: Duration,
+    last: Instant,
+    sidecar: BufWriter<File>,
+}
+
+impl Reporter {
+    /// Appends to the sidecar in `dir`, so that a resumed session keeps its history.
+    pub fn new<'a>(
+        dir: &Path,
+        names: impl IntoIterator<Item = &'a str>,
+        window: Duration,
+        interval: Duration,
+    ) -> anyhow::Result<Self> {
+        let sidecar = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(dir.join(SIDECAR_FILE))?;
+        Ok(Self {
+            heads: (names.into_iter())
+                .map(|name| (name.to_owned(), Statistics::new(window)))
+                .collect(),
+            interval,
+            last: Instant::now(),
+            sidecar: BufWriter::new(sidecar),
+        })
+    }
+
+    pub fn push(&mut self, head: usize, sample: &Sample) {
+        self.heads[head].1.push(sample);
+    }
+
+    /// Forgets the samples so far, e.g. at the start of a burst, since the statistics
+    /// assume evenly spaced samples.
+    pub fn clear(&mut self) {
+        for (_, statistics) in &mut self.heads {
+            statistics.clear();
+        }
+    }
+
+    /// Reports if the interval has passed.
+    pub fn poll(&mut self) -> anyhow::Result<()> {
+        if self.last.elapsed() < self.interval {
+            return Ok(());
+        }
+        self.last = Instant::now();
+        let time = Local::now();
+        for (name, statistics) in &self.heads {
+            let Some(summary) = statistics.summary() else {
+                info!("{name}: Not enough samples for statistics");
+                continue;
+            };
+            log(name, &summary);
+            let entry = Entry {
+                time,
+                source: name,
+                summary: &summary,
+            };
+            serde_json::to_writer(&mut self.sidecar, &entry)?;
+            self.sidecar.write_all(b"\n")?;
+        }
+        self.sidecar.flush()?;
+        Ok(())
+    }
+}
+
+fn log(name: &str, summary: &Summary) {
+    let urad = |[x, y]: [f64; 2]| format!("x {:.3} y {:.3}", x * 1e6, y * 1e6);
+    info!(
+        "{name}: {} samples ({} missing) over {:.1} s; µrad: mean {}, std {}, p-p {}; drift {} µrad/s",
+        summary.count,
+        summary.missing,
+        summary.duration,
+        urad(summary.mean),
+        urad(summary.std),
+        urad(summary.peak_to_peak),
+        urad(summary.drift),
+    );
+    if !summary.allan.is_empty() {
+        let allan = (summary.allan.iter())
+            .map(|point| format!("{:.3} s: {}", point.tau, urad(point.deviation)))
+            .collect::<Vec<_>>();
+        info!("{name}: Allan deviation (µrad) at {}", allan.join(", "));
+    }
+}
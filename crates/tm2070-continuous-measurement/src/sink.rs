@@ -0,0 +1,272 @@
+//! Writers of records in each file format.
+
+use std::{
+    collections::BTreeMap,
+    io::{self, BufRead, BufReader, BufWriter, Write},
+    path::Path,
+};
+
+use anyhow::Context;
+use chrono::{DateTime, Local};
+use clap::ValueEnum;
+use fs_err::File;
+use serde::{Deserialize, Serialize};
+use tm2070::record::{Marker, Record};
+
+use crate::columnar::{self, ParquetSink};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Format {
+    /// One JSON record per line
+    Jsonl,
+    /// Comma-separated values with a header line
+    Csv,
+    /// Apache Parquet with typed columns, compressed with zstd
+    Parquet,
+}
+
+impl Format {
+    pub fn extension(self) -> &'static str {
+        match self {
+            Format::Jsonl => "jsonl",
+            Format::Csv => "csv",
+            Format::Parquet => "parquet",
+        }
+    }
+
+    pub fn create(self, path: &Path) -> anyhow::Result<Box<dyn Sink>> {
+        let writer = || anyhow::Ok(Counting::new(BufWriter::new(File::create(path)?)));
+        Ok(match self {
+            Format::Jsonl => Box::new(JsonlSink(writer()?)),
+            Format::Csv => {
+                let mut writer = csv::Writer::from_writer(writer()?);
+                writer.write_record(CSV_HEADER)?;
+                Box::new(CsvSink(writer))
+            }
+            Format::Parquet => Box::new(ParquetSink::create(path)?),
+        })
+    }
+
+    /// Reads back a segment left open by a crash.
+    /// A partially written last line is cut off first;
+    /// a Parquet segment gets its footer written from its row group log.
+    pub fn recover(self, path: &Path) -> anyhow::Result<Scan> {
+        if self == Format::Parquet {
+            return columnar::recover(path);
+        }
+        let mut scan = Scan {
+            bytes: truncate_partial_line(path)?,
+            ..Scan::default()
+        };
+        if self == Format::Jsonl {
+            for line in BufReader::new(File::open(path)?).lines() {
+                let line = line?;
+                let value: serde_json::Value = serde_json::from_str(&line)
+                    .with_context(|| format!("Corrupt record in {}", path.display()))?;
+                if value.get("marker").is_some() {
+                    scan.markers += 1;
+                } else {
+                    scan.add(value["source"].as_str(), value["time"].as_str());
+                }
+            }
+        } else {
+            let mut reader = csv::Reader::from_path(path)?;
+            let headers = reader.headers()?.clone();
+            let column = |name| headers.iter().position(|h| h == name);
+            let (source, time) = (column("source"), column("time"));
+            let marker = column("marker");
+            for row in reader.records() {
+                let row = row?;
+                if marker
+                    .and_then(|i| row.get(i))
+                    .is_some_and(|m| !m.is_empty())
+                {
+                    scan.markers += 1;
+                    continue;
+                }
+                scan.add(
+                    source.and_then(|i| row.get(i)),
+                    time.and_then(|i| row.get(i)),
+                );
+            }
+        }
+        Ok(scan)
+    }
+}
+
+/// Cuts off a partially written last line and returns the remaining length.
+fn truncate_partial_line(path: &Path) -> anyhow::Result<u64> {
+    let file = fs_err::OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open(path)?;
+    let mut len = 0;
+    let mut reader = BufReader::new(&file);
+    let mut line = vec![];
+    loop {
+        line.clear();
+        let read = reader.read_until(b'\n', &mut line)?;
+        if read == 0 || line.last() != Some(&b'\n') {
+            break;
+        }
+        len += read as u64;
+    }
+    drop(reader);
+    file.set_len(len)?;
+    file.sync_all()?;
+    Ok(len)
+}
+
+/// What [`Format::recover`] found in a segment.
+#[derive(Default)]
+pub struct Scan {
+    /// Samples; markers are counted separately.
+    pub records: u64,
+    pub markers: u64,
+    pub bytes: u64,
+    pub records_per_source: BTreeMap<String, u64>,
+    pub first_time: Option<DateTime<Local>>,
+    pub last_time: Option<DateTime<Local>>,
+}
+impl Scan {
+    fn add(&mut self, source: Option<&str>, time: Option<&str>) {
+        self.records += 1;
+        *(self.records_per_source)
+            .entry(source.unwrap_or_default().to_owned())
+            .or_default() += 1;
+        if let Some(time) = time.and_then(|t| DateTime::parse_from_rfc3339(t).ok()) {
+            self.first_time.get_or_insert(time.into());
+            self.last_time = Some(time.into());
+        }
+    }
+}
+
+pub trait Sink {
+    fn write(&mut self, record: &Record) -> anyhow::Result<()>;
+    fn mark(&mut self, marker: &Marker) -> anyhow::Result<()>;
+    /// Pushes buffered records to the disk.
+    fn flush(&mut self) -> anyhow::Result<()>;
+    /// Approximate size of the output so far.
+    fn bytes(&self) -> u64;
+    fn finish(self: Box<Self>) -> anyhow::Result<()>;
+}
+
+struct JsonlSink(Counting<BufWriter<File>>);
+impl Sink for JsonlSink {
+    fn write(&mut self, record: &Record) -> anyhow::Result<()> {
+        serde_json::to_writer(&mut self.0, record)?;
+        self.0.write_all(b"\n")?;
+        Ok(())
+    }
+
+    fn mark(&mut self, marker: &Marker) -> anyhow::Result<()> {
+        serde_json::to_writer(&mut self.0, marker)?;
+        self.0.write_all(b"\n")?;
+        Ok(())
+    }
+
+    fn flush(&mut self) -> anyhow::Result<()> {
+        self.0.flush()?;
+        Ok(self.0.inner.get_ref().sync_data()?)
+    }
+
+    fn bytes(&self) -> u64 {
+        self.0.count
+    }
+
+    fn finish(mut self: Box<Self>) -> anyhow::Result<()> {
+        self.flush()
+    }
+}
+
+const CSV_HEADER: [&str; 11] = [
+    "version",
+    "time",
+    "seq",
+    "source",
+    "unit",
+    "x",
+    "y",
+    "norm",
+    "direction",
+    "judge",
+    "marker",
+];
+
+/// Same fields as JSON; angles in the notation of the record schema, empty when unavailable.
+/// Markers only fill `version`, `time`, `source` and `marker`.
+struct CsvSink(csv::Writer<Counting<BufWriter<File>>>);
+impl Sink for CsvSink {
+    fn write(&mut self, record: &Record) -> anyhow::Result<()> {
+        let angle = |a: Option<tm2070::angle::Angle>| a.map(|a| a.to_string()).unwrap_or_default();
+        let data = &record.data;
+        self.0.write_record([
+            record.version.to_string(),
+            record.time.to_rfc3339(),
+            record.seq.to_string(),
+            record.source.to_string(),
+            data.unit.to_string(),
+            angle(data.x),
+            angle(data.y),
+            angle(data.norm),
+            data.direction.0.val().to_string(),
+            String::from_utf8_lossy(data.judge.as_bytes()).into_owned(),
+            String::new(),
+        ])?;
+        Ok(())
+    }
+
+    fn mark(&mut self, marker: &Marker) -> anyhow::Result<()> {
+        let empty = String::new;
+        self.0.write_record([
+            marker.version.to_string(),
+            marker.time.to_rfc3339(),
+            empty(),
+            marker.source.to_string(),
+            empty(),
+            empty(),
+            empty(),
+            empty(),
+            empty(),
+            empty(),
+            marker.marker.to_string(),
+        ])?;
+        Ok(())
+    }
+
+    fn flush(&mut self) -> anyhow::Result<()> {
+        self.0.flush()?;
+        Ok(self.0.get_ref().inner.get_ref().sync_data()?)
+    }
+
+    fn bytes(&self) -> u64 {
+        self.0.get_ref().count
+    }
+
+    fn finish(mut self: Box<Self>) -> anyhow::Result<()> {
+        self.flush()
+    }
+}
+
+/// Counts the bytes passed to the inner writer.
+struct Counting<W> {
+    inner: W,
+    count: u64,
+}
+impl<W> Counting<W> {
+    fn new(inner: W) -> Self {
+        Self { inner, count: 0 }
+    }
+}
+impl<W: Write> Write for Counting<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let written = self.inner.write(buf)?;
+        self.count += written as u64;
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
@@ -1,15 +1,28 @@
-use std::{any::TypeId, convert::Infallible, future::pending, ops::Range, time::Duration};
+mod panel;
+mod trace;
+mod xy;
+
+use std::{
+    any::TypeId,
+    convert::Infallible,
+    future::pending,
+    io::ErrorKind,
+    ops::Range,
+    path::{Path as FilePath, PathBuf},
+    time::Duration,
+};
 
 use anyhow::Context;
+use chrono::{DateTime, Local};
 use iced::{
     alignment, event, font,
-    futures::{channel::mpsc::Sender, SinkExt},
+    futures::{channel::mpsc::Sender, SinkExt, StreamExt},
     keyboard::{self, Modifiers},
     mouse,
     widget::{
         button,
         canvas::{self, Cache, Frame, Geometry, Path, Program, Stroke, Text},
-        column, row, slider, text, text_input, Canvas, Space,
+        checkbox, column, pick_list, row, slider, text, text_input, Canvas, Space,
     },
     window, Application, Color, Command, Event, Font, Length, Point, Rectangle, Renderer, Settings,
     Size, Subscription, Theme, Vector,
@@ -17,8 +30,15 @@ use iced::{
 use itertools::{chain, iterate, zip_eq, Itertools};
 use log::error;
 use ordered_float::OrderedFloat;
-use tm2070::Tm2070;
+use panel::{PanelKind, PanelView, PanelViewParam};
+use tm2070::{
+    net::{Publisher, Subscriber},
+    record::Record,
+    SamplingData1, Tm2070,
+};
 use tokio::{select, sync::mpsc::UnboundedSender};
+use trace::{Trace, TraceWriter};
+use xy::{Target, XyView, XyViewParam};
 
 const FONT: Font = Font::with_name("Noto Sans JP");
 
@@ -35,14 +55,35 @@ struct App {
     modifiers: Modifiers,
 
     com_port: String,
+    publish: String,
     connection_status: ConnectionStatus,
 
     average_count: usize,
 
+    record_path: String,
+    recording: Option<TraceWriter>,
+    open_path: String,
+    /// The file shown instead of live samples
+    opened: Option<PathBuf>,
+
+    times: Vec<DateTime<Local>>,
     waveform_x: WaveformView,
     waveform_y: WaveformView,
     horizontal: WaveformHorizontalScale,
 
+    show_xy: bool,
+    xy_view: XyView,
+    /// In μrad, as typed
+    target_x: String,
+    target_y: String,
+    tolerance: String,
+    /// The latest live sample
+    latest: Option<SamplingData1>,
+
+    show_stats: bool,
+    panel_kind: PanelKind,
+    panel_view: PanelView,
+
     status_message: String,
 }
 impl Default for App {
@@ -50,11 +91,26 @@ impl Default for App {
         Self {
             modifiers: Default::default(),
             com_port: Default::default(),
+            publish: Default::default(),
             connection_status: Default::default(),
             average_count: 1,
+            record_path: Default::default(),
+            recording: None,
+            open_path: Default::default(),
+            opened: None,
+            times: Default::default(),
             waveform_x: Default::default(),
             waveform_y: Default::default(),
             horizontal: Default::default(),
+            show_xy: false,
+            xy_view: Default::default(),
+            target_x: Default::default(),
+            target_y: Default::default(),
+            tolerance: Default::default(),
+            latest: None,
+            show_stats: false,
+            panel_kind: Default::default(),
+            panel_view: Default::default(),
             status_message: Default::default(),
         }
     }
@@ -101,6 +157,7 @@ impl Application for App {
                             log::error!("{}", self.status_message);
                         }
                     }
+                    self.stop_recording();
                     return window::close(id);
                 }
                 Event::Keyboard(keyboard::Event::ModifiersChanged(modifiers)) => {
@@ -121,17 +178,54 @@ impl Application for App {
                     log::error!("{}", self.status_message);
                 }
             }
-            Message::DataPoint(x, y) => {
-                for (waveform, x) in zip_eq([&mut self.waveform_x, &mut self.waveform_y], [x, y]) {
-                    waveform.points.push(x);
-                    waveform
-                        .cumulative_sum
-                        .push(waveform.cumulative_sum.last().unwrap() + x);
-                    waveform.waveform_frame_cache.clear();
+            Message::DataPoint(record) => {
+                if let Some(writer) = &mut self.recording {
+                    if let Err(e) = writer.write(&record) {
+                        self.status_message = format!("Failed to record: {e:#}");
+                        log::error!("{}", self.status_message);
+                        self.recording = None;
+                    }
+                }
+                if let (Some(x), Some(y)) = (record.data.x, record.data.y) {
+                    self.push_point(record.time, [x.value().val(), y.value().val()]);
+                    self.latest = Some(record.data);
+                    self.refresh_panel(false);
                 }
             }
             Message::ComPortInput(input) => self.com_port = input,
+            Message::PublishInput(input) => self.publish = input,
+            Message::RecordPathInput(input) => self.record_path = input,
+            Message::StartRecording => match TraceWriter::create(self.record_path.trim()) {
+                Ok(writer) => {
+                    self.status_message = format!("Recording to {}", writer.path().display());
+                    self.recording = Some(writer);
+                }
+                Err(e) => {
+                    self.status_message = format!("Failed to start recording: {e:#}");
+                    log::error!("{}", self.status_message);
+                }
+            },
+            Message::StopRecording => self.stop_recording(),
+            Message::OpenPathInput(input) => self.open_path = input,
+            Message::Open => {
+                let (path, source) = parse_open_path(&self.open_path);
+                "Opening...".clone_into(&mut self.status_message);
+                return Command::perform(
+                    async move { trace::load(&path, source.as_deref()).map_err(|e| format!("{e:#}")) },
+                    Message::Opened,
+                );
+            }
+            Message::Opened(trace) => match trace {
+                Ok(trace) => self.show_trace(trace),
+                Err(e) => {
+                    self.status_message = format!("Failed to open: {e}");
+                    log::error!("{}", self.status_message);
+                }
+            },
             Message::Connect => {
+                if self.opened.take().is_some() {
+                    self.clear_points();
+                }
                 self.connection_status = ConnectionStatus::Connecting;
                 "Connecting...".clone_into(&mut self.status_message);
             }
@@ -151,8 +245,37 @@ impl Application for App {
                 for w in [&self.waveform_x, &self.waveform_y] {
                     w.waveform_frame_cache.clear();
                 }
+                self.xy_view.cache.clear();
+                self.panel_view.invalidate();
+                self.refresh_panel(true);
+            }
+            Message::SetAverageCount(count) => {
+                self.average_count = count;
+                self.xy_view.cache.clear();
+            }
+            Message::ShowXy(show) => self.show_xy = show,
+            Message::ShowStats(show) => {
+                self.show_stats = show;
+                self.refresh_panel(true);
+            }
+            Message::SelectPanel(kind) => {
+                self.panel_kind = kind;
+                self.panel_view.invalidate();
+                self.refresh_panel(true);
+            }
+            Message::RefreshPanel => self.refresh_panel(false),
+            Message::TargetXInput(input) => {
+                self.target_x = input;
+                self.xy_view.cache.clear();
+            }
+            Message::TargetYInput(input) => {
+                self.target_y = input;
+                self.xy_view.cache.clear();
+            }
+            Message::ToleranceInput(input) => {
+                self.tolerance = input;
+                self.xy_view.cache.clear();
             }
-            Message::SetAverageCount(count) => self.average_count = count,
         }
         Command::none()
     }
@@ -180,7 +303,7 @@ impl Application for App {
         let config_line = {
             let com_port_label = text("COM port:");
             let disconnected = matches!(self.connection_status, ConnectionStatus::Disconnected);
-            let com_port = text_input("e.g. COM3", &self.com_port);
+            let com_port = text_input("e.g. COM3 or tcp://127.0.0.1:7070/COM3", &self.com_port);
             let com_port = if disconnected {
                 com_port
                     .on_input(Message::ComPortInput)
@@ -191,6 +314,16 @@ impl Application for App {
             let connect =
                 button("Connect").on_press_maybe(disconnected.then_some(Message::Connect));
 
+            let publish_label = text("Publish:");
+            let publish = text_input("e.g. 127.0.0.1:7070", &self.publish);
+            let publish = if disconnected {
+                publish
+                    .on_input(Message::PublishInput)
+                    .on_submit(Message::Connect)
+            } else {
+                publish
+            };
+
             let average_label = text(format!("Average: {}", self.average_count));
             let average_slider = slider(1. ..=60.001, self.average_count as f64, |a| {
                 Message::SetAverageCount(a as usize)
@@ -200,6 +333,8 @@ impl Application for App {
                 com_port_label,
                 com_port,
                 connect,
+                publish_label,
+                publish,
                 average_label,
                 average_slider,
             ]
@@ -208,16 +343,135 @@ impl Application for App {
             .spacing(10)
         };
 
+        let file_line = {
+            let record_label = text("Record to:");
+            let record_path = text_input("e.g. trace.csv or trace.jsonl", &self.record_path);
+            let (record_path, record) = match &self.recording {
+                Some(writer) => (
+                    record_path,
+                    button(text(format!("Stop ({} samples)", writer.count())))
+                        .on_press(Message::StopRecording),
+                ),
+                None => (
+                    record_path
+                        .on_input(Message::RecordPathInput)
+                        .on_submit(Message::StartRecording),
+                    button("Start recording").on_press(Message::StartRecording),
+                ),
+            };
+
+            let disconnected = matches!(self.connection_status, ConnectionStatus::Disconnected);
+            let open_label = text("Open:");
+            let open_path = text_input(
+                "recording or session directory, optionally followed by #<source>",
+                &self.open_path,
+            );
+            let open_path = if disconnected {
+                open_path
+                    .on_input(Message::OpenPathInput)
+                    .on_submit(Message::Open)
+            } else {
+                open_path
+            };
+            let open = button("Open").on_press_maybe(disconnected.then_some(Message::Open));
+
+            row![
+                record_label,
+                record_path,
+                record,
+                open_label,
+                open_path,
+                open
+            ]
+            .align_items(iced::Alignment::Center)
+            .padding(5)
+            .spacing(10)
+        };
+
+        let view_line = {
+            let show_xy = checkbox("XY view", self.show_xy).on_toggle(Message::ShowXy);
+            let target_label = text("Target [μrad]  x:");
+            let target_x = text_input("0", &self.target_x).on_input(Message::TargetXInput);
+            let target_y_label = text("y:");
+            let target_y = text_input("0", &self.target_y).on_input(Message::TargetYInput);
+            let tolerance_label = text("Tolerance [μrad]:");
+            let tolerance = text_input("none", &self.tolerance).on_input(Message::ToleranceInput);
+
+            let show_stats = checkbox("Statistics", self.show_stats).on_toggle(Message::ShowStats);
+            let panel_kind = pick_list(PanelKind::ALL, Some(self.panel_kind), Message::SelectPanel);
+
+            row![
+                show_xy,
+                target_label,
+                target_x,
+                target_y_label,
+                target_y,
+                tolerance_label,
+                tolerance,
+                show_stats,
+                panel_kind
+            ]
+            .align_items(iced::Alignment::Center)
+            .padding(5)
+            .spacing(10)
+        };
+
         let status_line = text(&self.status_message);
 
         let space = || Space::with_height(10);
 
+        let waveforms = column![canvas_x, space(), canvas_y];
+        let plots: iced::Element<Message> = if self.show_xy {
+            let num_points = self.waveform_x.points.len();
+            let canvas_xy = Canvas::new(XyViewParam {
+                view: &self.xy_view,
+                x: &self.waveform_x.points,
+                y: &self.waveform_y.points,
+                visible: self.horizontal.visible(num_points),
+                average_count: self.average_count,
+                target: self.target(),
+                latest: self.latest.as_ref(),
+            })
+            .width(Length::FillPortion(2))
+            .height(Length::Fill);
+            row![
+                waveforms.width(Length::FillPortion(3)),
+                Space::with_width(10),
+                canvas_xy
+            ]
+            .into()
+        } else {
+            waveforms.into()
+        };
+
+        let plots: iced::Element<Message> = if self.show_stats {
+            let canvas_panel = Canvas::new(PanelViewParam {
+                view: &self.panel_view,
+                kind: self.panel_kind,
+            })
+            .width(Length::Fill)
+            .height(Length::Fill);
+            column![
+                plots,
+                space(),
+                row![
+                    text(panel::describe(self.panel_view.summary())).width(Length::FillPortion(1)),
+                    canvas_panel.width(Length::FillPortion(2))
+                ]
+                .spacing(10)
+                .height(Length::FillPortion(1))
+            ]
+            .into()
+        } else {
+            plots
+        };
+
         column![
-            canvas_x,
-            space(),
-            canvas_y,
+            plots,
             space(),
             config_line,
+            file_line,
+            view_line,
             status_line
         ]
         .into()
@@ -229,8 +483,9 @@ impl Application for App {
         let connected = !matches!(self.connection_status, ConnectionStatus::Disconnected);
         let connection = connected.then(|| {
             let com_port = self.com_port.clone();
+            let publish = self.publish.clone();
             iced::subscription::channel(TypeId::of::<SubscriptionId>(), 100, |mut tx| async move {
-                let Err(e) = tm2070_worker(&mut tx, com_port).await else {
+                let Err(e) = tm2070_worker(&mut tx, com_port, publish).await else {
                     // Ok(Infallible) never happens though...
                     pending().await
                 };
@@ -243,7 +498,11 @@ impl Application for App {
 
         let events = event::listen().map(Message::Event);
 
-        Subscription::batch(chain![connection, [events]])
+        // Catches up once samples stop arriving
+        let refresh = (self.show_stats && self.panel_view.is_stale())
+            .then(|| iced::time::every(panel::REFRESH_INTERVAL).map(|_| Message::RefreshPanel));
+
+        Subscription::batch(chain![connection, refresh, [events]])
     }
 
     fn theme(&self) -> Self::Theme {
@@ -251,28 +510,193 @@ impl Application for App {
     }
 }
 
-async fn tm2070_worker(tx: &mut Sender<Message>, com_port: String) -> anyhow::Result<Infallible> {
-    let mut tm2070 =
+/// `com_port` of the form `tcp://<address>[/<source>]` receives the samples of another
+/// `tm2070-gui` or `tm2070-continuous-measurement` instead of opening a serial port.
+async fn tm2070_worker(
+    tx: &mut Sender<Message>,
+    com_port: String,
+    publish: String,
+) -> anyhow::Result<Infallible> {
+    let publisher = match publish.trim() {
+        "" => None,
+        addr => {
+            Some(Publisher::bind(addr).with_context(|| format!("Could not publish on {addr}"))?)
+        }
+    };
+    if let Some(remote) = com_port.strip_prefix("tcp://") {
+        return subscriber_worker(tx, remote, publisher).await;
+    }
+    let tm2070 =
         Tm2070::new(&com_port).with_context(|| format!("Could not connect to {com_port:?}"))?;
-    let handle = tm2070.continuous_1(None)?;
+    let mut samples = tm2070.into_continuous_1(None)?.into_stream();
     let (main_tx, mut rx) = tokio::sync::mpsc::unbounded_channel();
     tx.send(Message::ConnectionEstablished(main_tx)).await?;
     loop {
-        if let Some(event) = handle.recv()? {
-            if let (Some(x), Some(y)) = (event.x, event.y) {
-                tx.send(Message::DataPoint(x.value().val(), y.value().val()))
-                    .await?;
+        select! {
+            event = samples.next() => {
+                let sample = event.context("Sampling stopped")??;
+                on_record(tx, publisher.as_ref(), Record::new(com_port.clone(), sample)).await?;
+            },
+            _ = rx.recv() => break,
+        }
+    }
+    pending().await
+}
+
+async fn subscriber_worker(
+    tx: &mut Sender<Message>,
+    remote: &str,
+    publisher: Option<Publisher>,
+) -> anyhow::Result<Infallible> {
+    let (addr, source) = match remote.split_once('/') {
+        Some((addr, source)) => (addr, Some(source.to_owned())),
+        None => (remote, None),
+    };
+    let mut subscriber =
+        Subscriber::connect(addr).with_context(|| format!("Could not connect to {addr}"))?;
+    // Lets the reading thread notice a disconnection while the publisher is silent
+    subscriber.set_timeout(Some(Duration::from_secs(1)))?;
+    let (records_tx, mut records) = tokio::sync::mpsc::unbounded_channel();
+    std::thread::spawn(move || {
+        while !records_tx.is_closed() {
+            let record = match subscriber.recv() {
+                Err(e) if matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => {
+                    continue
+                }
+                record => record.transpose(),
+            };
+            let end = !matches!(record, Some(Ok(_)));
+            if records_tx.send(record).is_err() || end {
+                break;
             }
         }
-        let sleep = tokio::time::sleep(Duration::from_secs_f64(1. / 120.));
+    });
+    let (main_tx, mut rx) = tokio::sync::mpsc::unbounded_channel();
+    tx.send(Message::ConnectionEstablished(main_tx)).await?;
+    loop {
         select! {
-            _ = sleep => {},
+            record = records.recv() => {
+                let record = record.flatten().context("Publisher closed the connection")??;
+                if source.as_ref().is_some_and(|source| *source != record.source) {
+                    continue;
+                }
+                on_record(tx, publisher.as_ref(), record).await?;
+            },
             _ = rx.recv() => break,
         }
     }
     pending().await
 }
 
+async fn on_record(
+    tx: &mut Sender<Message>,
+    publisher: Option<&Publisher>,
+    record: Record<'static>,
+) -> anyhow::Result<()> {
+    if let Some(publisher) = publisher {
+        publisher.publish(&record)?;
+    }
+    tx.send(Message::DataPoint(record)).await?;
+    Ok(())
+}
+
+impl App {
+    fn push_point(&mut self, time: DateTime<Local>, xy: [f64; 2]) {
+        self.times.push(time);
+        for (waveform, x) in zip_eq([&mut self.waveform_x, &mut self.waveform_y], xy) {
+            waveform.points.push(x);
+            waveform
+                .cumulative_sum
+                .push(waveform.cumulative_sum.last().unwrap() + x);
+            waveform.waveform_frame_cache.clear();
+        }
+        self.xy_view.cache.clear();
+        self.panel_view.invalidate();
+    }
+
+    fn clear_points(&mut self) {
+        self.times.clear();
+        self.waveform_x = Default::default();
+        self.waveform_y = Default::default();
+        self.horizontal = Default::default();
+        self.latest = None;
+        self.xy_view.cache.clear();
+        self.panel_view.invalidate();
+        self.refresh_panel(true);
+    }
+
+    /// Recomputes the statistics of the points shown if they are shown and out of date,
+    /// at most every [`panel::REFRESH_INTERVAL`] unless `now`.
+    fn refresh_panel(&mut self, now: bool) {
+        if !self.show_stats {
+            return;
+        }
+        let visible = self.horizontal.visible(self.times.len());
+        let xy = [&self.waveform_x, &self.waveform_y].map(|w| &w.points[visible.clone()]);
+        (self.panel_view).refresh(self.panel_kind, &self.times[visible], xy, now);
+    }
+
+    /// The target typed in μrad; blank or invalid coordinates count as 0.
+    fn target(&self) -> Target {
+        let parse = |s: &str| s.trim().parse::<f64>().ok().map(|a| a * 1e-6);
+        Target {
+            xy: [&self.target_x, &self.target_y].map(|s| parse(s).unwrap_or(0.)),
+            tolerance: parse(&self.tolerance).filter(|&t| t > 0.),
+        }
+    }
+
+    fn stop_recording(&mut self) {
+        let Some(writer) = self.recording.take() else {
+            return;
+        };
+        let path = writer.path().to_owned();
+        match writer.finish() {
+            Ok(count) => {
+                self.status_message = format!("Recorded {count} samples to {}", path.display())
+            }
+            Err(e) => {
+                self.status_message = format!("Failed to finish {}: {e:#}", path.display());
+                log::error!("{}", self.status_message);
+            }
+        }
+    }
+
+    /// Replaces the samples shown with `trace`, zoomed out to show all of it.
+    fn show_trace(&mut self, trace: Trace) {
+        self.clear_points();
+        for (&time, &xy) in zip_eq(&trace.times, &trace.points) {
+            self.push_point(time, xy);
+        }
+        self.horizontal.window = (trace.points.len() as f64).max(10.);
+        self.refresh_panel(true);
+        let others = (trace.sources.iter())
+            .filter(|s| **s != trace.source)
+            .join(", ");
+        self.status_message = format!(
+            "Opened {} samples of {} from {} ({} without x or y)",
+            trace.points.len(),
+            trace.source,
+            trace.path.display(),
+            trace.missing
+        );
+        if !others.is_empty() {
+            self.status_message += &format!("; also has {others}, shown with #<source>");
+        }
+        self.opened = Some(trace.path);
+    }
+}
+
+/// Splits `path#source`, unless `#` is part of an existing path.
+fn parse_open_path(input: &str) -> (PathBuf, Option<String>) {
+    let input = input.trim();
+    match input.rsplit_once('#') {
+        Some((path, source)) if !FilePath::new(input).exists() => {
+            (path.into(), Some(source.to_owned()))
+        }
+        _ => (input.into(), None),
+    }
+}
+
 struct WaveformViewParam<'a> {
     modifiers: Modifiers,
     #[allow(dead_code)]
@@ -293,6 +717,22 @@ struct WaveformHorizontalScale {
     // datapoints per full width
     window: f64,
 }
+impl WaveformHorizontalScale {
+    fn leftmost(&self, num_points: usize) -> f64 {
+        match self.position {
+            WaveformPosition::Rightmost => 0f64.max(num_points as f64 - self.window),
+            WaveformPosition::Custom(left) => left,
+        }
+    }
+
+    /// Indices of the data points within the window.
+    fn visible(&self, num_points: usize) -> Range<usize> {
+        let left = self.leftmost(num_points);
+        // float -> int is saturating cast
+        let (left, right) = (left as usize, (left + self.window) as usize);
+        left.min(num_points)..right.min(num_points)
+    }
+}
 impl Default for WaveformHorizontalScale {
     fn default() -> Self {
         Self {
@@ -595,10 +1035,7 @@ impl Program<Message> for WaveformViewParam<'_> {
 
 impl WaveformViewParam<'_> {
     fn leftmost_datapoint(&self) -> f64 {
-        match self.horizontal.position {
-            WaveformPosition::Rightmost => 0f64.max(self.left_for_rightmost()),
-            WaveformPosition::Custom(left) => left,
-        }
+        self.horizontal.leftmost(self.num_points())
     }
 
     // If this value < 0, it means the data point count is fewer than window.
@@ -682,6 +1119,7 @@ enum Message {
     FontLoaded(Result<(), iced::font::Error>),
 
     ComPortInput(String),
+    PublishInput(String),
     Connect,
     ConnectionEstablished(UnboundedSender<()>),
     ConnectionLost(Option<String>),
@@ -690,5 +1128,21 @@ enum Message {
 
     WaveformScrolled(WaveformPosition, f64),
 
-    DataPoint(f64, f64),
+    RecordPathInput(String),
+    StartRecording,
+    StopRecording,
+    OpenPathInput(String),
+    Open,
+    Opened(Result<Trace, String>),
+
+    ShowXy(bool),
+    TargetXInput(String),
+    TargetYInput(String),
+    ToleranceInput(String),
+    ShowStats(bool),
+    SelectPanel(PanelKind),
+    /// Recomputes the statistics held back while samples were arriving
+    RefreshPanel,
+
+    DataPoint(Record<'static>),
 }
@@ -0,0 +1,410 @@
+//! Diagnostics of the points shown in the waveforms, beyond the moving average:
+//! statistics, and a histogram, power spectrum or Allan deviation plot of x and y.
+
+use std::{
+    fmt,
+    ops::Range,
+    time::{Duration, Instant},
+};
+
+use chrono::{DateTime, Local};
+use iced::{
+    alignment,
+    widget::canvas::{Cache, Frame, Geometry, Path, Program, Stroke, Text},
+    Color, Point, Rectangle, Renderer, Size, Theme,
+};
+use tm2070::stats::{self, Summary};
+
+use super::{grid_size, iterate_range, linear_map, path_from_iter, Message};
+
+/// Of x and y.
+const COLORS: [Color; 2] = [Color::from_rgb(0., 1., 0.), Color::from_rgb(0.3, 0.7, 1.)];
+
+const HISTOGRAM_BINS: usize = 40;
+/// Values per segment of the power spectrum; longer windows are averaged over segments.
+const SPECTRUM_SEGMENT: usize = 1024;
+/// Minimum time between recomputations while samples keep arriving.
+pub const REFRESH_INTERVAL: Duration = Duration::from_millis(500);
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum PanelKind {
+    #[default]
+    Histogram,
+    Spectrum,
+    Allan,
+}
+
+impl PanelKind {
+    pub const ALL: [PanelKind; 3] = [PanelKind::Histogram, PanelKind::Spectrum, PanelKind::Allan];
+}
+
+impl fmt::Display for PanelKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            PanelKind::Histogram => "Histogram",
+            PanelKind::Spectrum => "Power spectrum",
+            PanelKind::Allan => "Allan deviation",
+        })
+    }
+}
+
+/// Seconds since the first point.
+fn seconds(times: &[DateTime<Local>]) -> Vec<f64> {
+    let Some(&first) = times.first() else {
+        return vec![];
+    };
+    (times.iter())
+        .map(|&t| (t - first).num_nanoseconds().unwrap_or(i64::MAX) as f64 * 1e-9)
+        .collect()
+}
+
+/// Statistics of the points at `times`.
+fn summary(times: &[DateTime<Local>], xy: [&[f64]; 2]) -> Option<Summary> {
+    stats::summarize(&seconds(times), xy, 0)
+}
+
+/// A few lines describing `summary`.
+pub fn describe(summary: Option<&Summary>) -> String {
+    let Some(summary) = summary else {
+        return "Not enough points for statistics".to_owned();
+    };
+    let mut lines = vec![format!(
+        "{} points over {:.1} s ({:.1} Hz)",
+        summary.count,
+        summary.duration,
+        (summary.count - 1) as f64 / summary.duration
+    )];
+    for (axis, name) in ["x", "y"].into_iter().enumerate() {
+        lines.push(format!(
+            "{name}: mean {}, std {}, p-p {}, drift {}/s",
+            angle(summary.mean[axis]),
+            angle(summary.std[axis]),
+            angle(summary.peak_to_peak[axis]),
+            angle(summary.drift[axis]),
+        ));
+    }
+    lines.join("\n")
+}
+
+/// The statistics and plot of the points shown in the waveforms.
+/// They are recomputed by [`PanelView::refresh`] rather than on every draw, since the FFT and
+/// the Allan deviation of a long window are too slow to run at the rate samples arrive.
+#[derive(Default)]
+pub struct PanelView {
+    cache: Cache,
+    summary: Option<Summary>,
+    plot: Option<Plot>,
+    computed: Option<Instant>,
+    /// The points or the kind changed since they were computed
+    stale: bool,
+}
+
+impl PanelView {
+    pub fn invalidate(&mut self) {
+        self.stale = true;
+    }
+
+    pub fn is_stale(&self) -> bool {
+        self.stale
+    }
+
+    /// Recomputes if stale, but at most every [`REFRESH_INTERVAL`] unless `now`.
+    pub fn refresh(
+        &mut self,
+        kind: PanelKind,
+        times: &[DateTime<Local>],
+        xy: [&[f64]; 2],
+        now: bool,
+    ) {
+        let recent = (self.computed).is_some_and(|at| at.elapsed() < REFRESH_INTERVAL);
+        if !self.stale || (recent && !now) {
+            return;
+        }
+        self.summary = summary(times, xy);
+        self.plot = Some(plot(kind, times, xy));
+        self.computed = Some(Instant::now());
+        self.stale = false;
+        self.cache.clear();
+    }
+
+    pub fn summary(&self) -> Option<&Summary> {
+        self.summary.as_ref()
+    }
+}
+
+pub struct PanelViewParam<'a> {
+    pub view: &'a PanelView,
+    pub kind: PanelKind,
+}
+
+/// Curves of x and y to draw.
+struct Plot {
+    series: [Vec<[f64; 2]>; 2],
+    horizontal: Axis,
+    vertical: Axis,
+    /// Draw steps instead of lines through the points
+    steps: bool,
+}
+
+struct Axis {
+    log: bool,
+    label: fn(f64) -> String,
+}
+
+/// Curves of `kind` for the points at `times`.
+fn plot(kind: PanelKind, times: &[DateTime<Local>], xy: [&[f64]; 2]) -> Plot {
+    match kind {
+        PanelKind::Histogram => {
+            // Around the mean, so that x and y share the horizontal axis
+            let series = xy.map(|values| {
+                let mean = stats::mean(values);
+                let deviations = values.iter().map(|v| v - mean).collect::<Vec<_>>();
+                let Some(histogram) = stats::histogram(&deviations, HISTOGRAM_BINS) else {
+                    return vec![];
+                };
+                let mut steps = vec![[histogram.start, 0.]];
+                for (i, &count) in histogram.counts.iter().enumerate() {
+                    let left = histogram.start + i as f64 * histogram.width;
+                    steps.push([left, count as f64]);
+                    steps.push([left + histogram.width, count as f64]);
+                }
+                steps.push([steps[steps.len() - 1][0], 0.]);
+                steps
+            });
+            Plot {
+                series,
+                horizontal: Axis {
+                    log: false,
+                    label: angle,
+                },
+                vertical: Axis {
+                    log: false,
+                    label: |count| format!("{count:.0}"),
+                },
+                steps: true,
+            }
+        }
+        PanelKind::Spectrum => {
+            let t = seconds(times);
+            let duration = t.last().copied().unwrap_or(0.);
+            let sample_rate = (t.len() as f64 - 1.) / duration;
+            let series = xy.map(|values| {
+                (stats::power_spectrum(values, sample_rate, SPECTRUM_SEGMENT).into_iter())
+                    .map(|point| [point.frequency, point.density.sqrt()])
+                    .collect()
+            });
+            Plot {
+                series,
+                horizontal: Axis {
+                    log: false,
+                    label: |f| format!("{} Hz", number(f)),
+                },
+                vertical: Axis {
+                    log: true,
+                    label: |a| format!("{}/√Hz", angle(a)),
+                },
+                steps: false,
+            }
+        }
+        PanelKind::Allan => {
+            let t = seconds(times);
+            let tau0 = t.last().copied().unwrap_or(0.) / (t.len() as f64 - 1.);
+            let series = xy.map(|values| {
+                let m = std::iter::successors(Some(1usize), |m| Some(m * 2));
+                m.map_while(|m| Some([m as f64 * tau0, stats::allan_deviation(values, m)?]))
+                    .collect()
+            });
+            Plot {
+                series,
+                horizontal: Axis {
+                    log: true,
+                    label: |tau| format!("{} s", number(tau)),
+                },
+                vertical: Axis {
+                    log: true,
+                    label: angle,
+                },
+                steps: false,
+            }
+        }
+    }
+}
+
+impl Program<Message> for PanelViewParam<'_> {
+    type State = ();
+
+    fn draw(
+        &self,
+        _state: &(),
+        renderer: &Renderer,
+        _theme: &Theme,
+        bounds: Rectangle,
+        _cursor: iced::mouse::Cursor,
+    ) -> Vec<Geometry> {
+        let geometry = self.view.cache.draw(renderer, bounds.size(), |frame| {
+            frame.fill_rectangle(Point::ORIGIN, bounds.size(), Color::BLACK);
+            let message = |frame: &mut Frame, content: &str| {
+                frame.fill_text(Text {
+                    color: Color::WHITE,
+                    size: 16.0.into(),
+                    position: Point::new(10., 10.),
+                    content: content.to_owned(),
+                    ..Text::default()
+                })
+            };
+
+            let Some(plot) = &self.view.plot else {
+                return;
+            };
+            let valid = |axis: &Axis, v: f64| v.is_finite() && (!axis.log || v > 0.);
+            let points = || {
+                (plot.series.iter().flatten())
+                    .filter(|[h, v]| valid(&plot.horizontal, *h) && valid(&plot.vertical, *v))
+            };
+            let range = |axis: &Axis, i: usize| {
+                let min = points().map(|p| p[i]).fold(f64::INFINITY, f64::min);
+                let max = points().map(|p| p[i]).fold(f64::NEG_INFINITY, f64::max);
+                // Log axes are mapped in log10
+                let (min, max) = if axis.log {
+                    (min.log10(), max.log10())
+                } else {
+                    (min, max)
+                };
+                let margin = ((max - min) * 0.05).max(if axis.log { 0.1 } else { 1e-9 });
+                min - margin..max + margin
+            };
+            if points().nth(1).is_none() {
+                message(frame, &format!("{}: not enough points", self.kind));
+                return;
+            }
+            let (h_range, v_range) = (range(&plot.horizontal, 0), range(&plot.vertical, 1));
+            if !(h_range.start.is_finite() && v_range.start.is_finite()) {
+                message(frame, &format!("{}: not enough points", self.kind));
+                return;
+            }
+            let (w, h) = (bounds.width as f64, bounds.height as f64);
+            let h_pixels = 0. ..w;
+            let v_pixels = (h - 20.)..10.;
+            let map = |axis: &Axis, range: &Range<f64>, pixels: &Range<f64>, v: f64| {
+                let v = if axis.log { v.log10() } else { v };
+                linear_map(v, range.clone(), pixels.clone()) as f32
+            };
+            let to_point = |[a, b]: [f64; 2]| {
+                Point::new(
+                    map(&plot.horizontal, &h_range, &h_pixels, a),
+                    map(&plot.vertical, &v_range, &v_pixels, b),
+                )
+            };
+
+            // Grid
+            let gray = Stroke::default().with_color(Color::from_rgb(0.3, 0.3, 0.3));
+            for v in ticks(&plot.horizontal, h_range.clone(), w) {
+                let x = map(&plot.horizontal, &h_range, &h_pixels, v);
+                frame.stroke(
+                    &Path::line(Point::new(x, 0.), Point::new(x, bounds.height)),
+                    gray.clone(),
+                );
+                frame.fill_text(Text {
+                    color: Color::WHITE,
+                    size: 12.0.into(),
+                    position: Point::new(x + 3., bounds.height - 3.),
+                    vertical_alignment: alignment::Vertical::Bottom,
+                    content: (plot.horizontal.label)(v),
+                    ..Text::default()
+                });
+            }
+            for v in ticks(&plot.vertical, v_range.clone(), h) {
+                let y = map(&plot.vertical, &v_range, &v_pixels, v);
+                frame.stroke(
+                    &Path::line(Point::new(0., y), Point::new(bounds.width, y)),
+                    gray.clone(),
+                );
+                frame.fill_text(Text {
+                    color: Color::WHITE,
+                    size: 12.0.into(),
+                    position: Point::new(bounds.width - 3., y - 3.),
+                    horizontal_alignment: alignment::Horizontal::Right,
+                    vertical_alignment: alignment::Vertical::Bottom,
+                    content: (plot.vertical.label)(v),
+                    ..Text::default()
+                });
+            }
+
+            for (series, color) in plot.series.iter().zip(COLORS) {
+                let points = (series.iter())
+                    .filter(|[a, b]| valid(&plot.horizontal, *a) && valid(&plot.vertical, *b))
+                    .map(|&p| to_point(p));
+                frame.stroke(
+                    &path_from_iter(points.clone()),
+                    Stroke::default().with_color(color).with_width(1.5),
+                );
+                if !plot.steps {
+                    for point in points {
+                        frame.fill_rectangle(
+                            point - iced::Vector::new(1.5, 1.5),
+                            Size::new(3., 3.),
+                            color,
+                        );
+                    }
+                }
+            }
+
+            // Legend
+            for (i, (name, color)) in ["x", "y"].into_iter().zip(COLORS).enumerate() {
+                frame.fill_text(Text {
+                    color,
+                    size: 16.0.into(),
+                    position: Point::new(10. + 30. * i as f32, 10.),
+                    content: name.to_owned(),
+                    ..Text::default()
+                });
+            }
+            frame.fill_text(Text {
+                color: Color::WHITE,
+                size: 16.0.into(),
+                position: Point::new(70., 10.),
+                content: self.kind.to_string(),
+                ..Text::default()
+            });
+        });
+        vec![geometry]
+    }
+}
+
+/// Values of the grid lines within `range`, which is in log10 for log axes.
+fn ticks(axis: &Axis, range: Range<f64>, pixels: f64) -> Vec<f64> {
+    if axis.log {
+        let decades = (range.start.ceil() as i32)..=(range.end.floor() as i32);
+        decades.map(|e| 10f64.powi(e)).collect()
+    } else {
+        let ws = [1., 2., 5., 10., 20., 50.].into_iter();
+        let d = grid_size(range.end - range.start, pixels, 80., ws);
+        // Rounded to get exact zeros
+        iterate_range(d, range)
+            .map(|v| (v / d).round() * d)
+            .collect()
+    }
+}
+
+/// In μrad below 1 mrad, otherwise in mrad.
+fn angle(rad: f64) -> String {
+    if rad.abs() < 1e-3 {
+        format!("{} μrad", number(rad * 1e6))
+    } else {
+        format!("{} mrad", number(rad * 1e3))
+    }
+}
+
+/// With three significant digits, or more before the decimal point.
+fn number(v: f64) -> String {
+    if v == 0. {
+        return "0".to_owned();
+    }
+    let decimals = (2 - v.abs().log10().floor() as i32).max(0) as usize;
+    let s = format!("{v:.decimals$}");
+    if s.contains('.') {
+        s.trim_end_matches('0').trim_end_matches('.').to_owned()
+    } else {
+        s
+    }
+}
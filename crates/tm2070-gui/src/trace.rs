@@ -0,0 +1,173 @@
+//! Saving the samples shown and loading recorded ones.
+//!
+//! Recordings are written in the record schema of [`tm2070::record`]. Loading reads whatever
+//! [`tm2070::recording`] does: the output of `tm2070-continuous-measurement` (single segments
+//! or whole session directories) and of `tm2070-recordings`, and legacy JSON arrays.
+
+use std::{
+    io::{BufWriter, Write},
+    path::{Path, PathBuf},
+};
+
+use anyhow::{bail, ensure, Context};
+use chrono::{DateTime, Local};
+use fs_err::File;
+use tm2070::{
+    angle::Angle,
+    record::Record,
+    recording::{self, Format},
+};
+
+const CSV_HEADER: [&str; 10] = [
+    "version",
+    "time",
+    "seq",
+    "source",
+    "unit",
+    "x",
+    "y",
+    "norm",
+    "direction",
+    "judge",
+];
+
+/// Writes samples as they arrive.
+pub struct TraceWriter {
+    path: PathBuf,
+    output: Output,
+    count: u64,
+}
+
+enum Output {
+    Jsonl(BufWriter<File>),
+    Json(BufWriter<File>),
+    Csv(Box<csv::Writer<File>>),
+}
+
+impl TraceWriter {
+    pub fn create(path: impl Into<PathBuf>) -> anyhow::Result<Self> {
+        let path = path.into();
+        let output = match Format::from_path(&path)? {
+            Format::Jsonl => Output::Jsonl(BufWriter::new(File::create(&path)?)),
+            Format::Json => {
+                let mut writer = BufWriter::new(File::create(&path)?);
+                writer.write_all(b"[\n")?;
+                Output::Json(writer)
+            }
+            Format::Csv => {
+                let mut writer = csv::Writer::from_writer(File::create(&path)?);
+                writer.write_record(CSV_HEADER)?;
+                Output::Csv(Box::new(writer))
+            }
+            Format::Parquet => bail!(
+                "Parquet cannot be written here; use .csv, .json or .jsonl, \
+                 or convert afterwards with `tm2070-recordings convert`"
+            ),
+        };
+        Ok(Self {
+            path,
+            output,
+            count: 0,
+        })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub fn count(&self) -> u64 {
+        self.count
+    }
+
+    pub fn write(&mut self, record: &Record) -> anyhow::Result<()> {
+        match &mut self.output {
+            Output::Jsonl(writer) => {
+                serde_json::to_writer(&mut *writer, record)?;
+                writer.write_all(b"\n")?;
+            }
+            Output::Json(writer) => {
+                if self.count > 0 {
+                    writer.write_all(b",\n")?;
+                }
+                serde_json::to_writer(&mut *writer, record)?;
+            }
+            Output::Csv(writer) => {
+                let angle = |a: Option<Angle>| a.map(|a| a.to_string()).unwrap_or_default();
+                let data = &record.data;
+                writer.write_record([
+                    record.version.to_string(),
+                    record.time.to_rfc3339(),
+                    record.seq.to_string(),
+                    record.source.to_string(),
+                    data.unit.to_string(),
+                    angle(data.x),
+                    angle(data.y),
+                    angle(data.norm),
+                    data.direction.0.val().to_string(),
+                    String::from_utf8_lossy(data.judge.as_bytes()).into_owned(),
+                ])?;
+            }
+        }
+        self.count += 1;
+        Ok(())
+    }
+
+    /// Completes the file and returns the number of samples written.
+    pub fn finish(self) -> anyhow::Result<u64> {
+        let file = match self.output {
+            Output::Jsonl(writer) => writer.into_inner()?,
+            Output::Json(mut writer) => {
+                writer.write_all(b"\n]\n")?;
+                writer.into_inner()?
+            }
+            Output::Csv(writer) => writer.into_inner()?,
+        };
+        file.sync_all()?;
+        Ok(self.count)
+    }
+}
+
+/// Samples of one source read from a file.
+#[derive(Clone, Debug, Default)]
+pub struct Trace {
+    pub path: PathBuf,
+    pub source: String,
+    /// Every source in the file, in order of first appearance
+    pub sources: Vec<String>,
+    pub times: Vec<DateTime<Local>>,
+    pub points: Vec<[f64; 2]>,
+    /// Samples of the source without x or y, e.g. `ND`
+    pub missing: usize,
+}
+
+/// Reads the samples of `source`, or of the first source in the file if `None`.
+pub fn load(path: &Path, source: Option<&str>) -> anyhow::Result<Trace> {
+    let mut rows = recording::read_all(&[path.to_owned()])?;
+    let sources = recording::sources(&rows);
+    let source = match source {
+        Some(source) => source.to_owned(),
+        None => sources.first().context("No samples")?.clone(),
+    };
+    ensure!(
+        sources.contains(&source),
+        "No samples of {source}; sources are {sources:?}"
+    );
+    let mut trace = Trace {
+        path: path.to_owned(),
+        source,
+        sources,
+        ..Trace::default()
+    };
+    rows.retain(|row| !row.is_marker() && row.source == trace.source);
+    rows.sort_by_key(|row| row.time);
+    for row in rows {
+        match row.xy() {
+            Some(xy) => {
+                trace.times.push(row.time);
+                trace.points.push(xy);
+            }
+            None => trace.missing += 1,
+        }
+    }
+    Ok(trace)
+}
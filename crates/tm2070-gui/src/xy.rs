@@ -0,0 +1,262 @@
+//! The tilt plane: y against x, with the points shown in the waveforms fading with age,
+//! a target with a tolerance circle, and a readout of where to go.
+
+use std::ops::Range;
+
+use iced::{
+    alignment,
+    widget::canvas::{Cache, Geometry, Path, Program, Stroke, Text},
+    Color, Point, Rectangle, Renderer, Size, Theme,
+};
+use tm2070::SamplingData1;
+
+use super::{grid_size, iterate_range, Message};
+
+/// Points drawn at most; longer histories are thinned out.
+const MAX_POINTS: usize = 4000;
+
+#[derive(Default)]
+pub struct XyView {
+    pub cache: Cache,
+}
+
+/// In rad.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Target {
+    pub xy: [f64; 2],
+    pub tolerance: Option<f64>,
+}
+
+pub struct XyViewParam<'a> {
+    pub view: &'a XyView,
+    pub x: &'a [f64],
+    pub y: &'a [f64],
+    /// Indices of the points shown in the waveforms
+    pub visible: Range<usize>,
+    /// The current position is the mean of this many latest points
+    pub average_count: usize,
+    pub target: Target,
+    /// The latest sample of the head, whose norm and direction are shown
+    pub latest: Option<&'a SamplingData1>,
+}
+
+impl XyViewParam<'_> {
+    fn point(&self, i: usize) -> [f64; 2] {
+        [self.x[i], self.y[i]]
+    }
+
+    fn current(&self) -> Option<[f64; 2]> {
+        let count = self.average_count.clamp(1, self.visible.len().max(1));
+        let start = self.visible.end.checked_sub(count)?.max(self.visible.start);
+        let points = start..self.visible.end;
+        if points.is_empty() {
+            return None;
+        }
+        let n = points.len() as f64;
+        Some([0, 1].map(|a| points.clone().map(|i| self.point(i)[a]).sum::<f64>() / n))
+    }
+
+    fn readout(&self, current: Option<[f64; 2]>) -> Vec<String> {
+        let Some([x, y]) = current else {
+            return vec!["No samples".to_owned()];
+        };
+        let mut lines = vec![format!("x {}   y {}", angle(x), angle(y))];
+        let head = self
+            .latest
+            .and_then(|data| Some((data.norm?.value().val(), data.direction.0.val())));
+        lines.push(match head {
+            Some((norm, direction)) => format!("norm {}   direction {direction:.1}°", angle(norm)),
+            None => {
+                let direction = y.atan2(x).to_degrees().rem_euclid(360.);
+                format!("norm {}   direction {direction:.1}°", angle(x.hypot(y)))
+            }
+        });
+        let [tx, ty] = self.target.xy;
+        let distance = (tx - x).hypot(ty - y);
+        let mut to_target = format!(
+            "to target: x {}   y {}   ({})",
+            angle(tx - x),
+            angle(ty - y),
+            angle(distance)
+        );
+        if let Some(tolerance) = self.target.tolerance {
+            to_target += if distance <= tolerance {
+                "   within tolerance"
+            } else {
+                "   outside tolerance"
+            };
+        }
+        lines.push(to_target);
+        lines
+    }
+}
+
+impl Program<Message> for XyViewParam<'_> {
+    type State = ();
+
+    fn draw(
+        &self,
+        _state: &(),
+        renderer: &Renderer,
+        _theme: &Theme,
+        bounds: Rectangle,
+        _cursor: iced::mouse::Cursor,
+    ) -> Vec<Geometry> {
+        let geometry = self.view.cache.draw(renderer, bounds.size(), |frame| {
+            frame.fill_rectangle(Point::ORIGIN, bounds.size(), Color::BLACK);
+
+            let stride = self.visible.len().div_ceil(MAX_POINTS).max(1);
+            let shown = self.visible.clone().step_by(stride);
+            let current = self.current();
+            let Target {
+                xy: target,
+                tolerance,
+            } = self.target;
+
+            // Fit the points, the target and its circle, with the same scale on both axes
+            let r = tolerance.unwrap_or(0.);
+            let (mut min, mut max) = (target.map(|t| t - r), target.map(|t| t + r));
+            for i in shown.clone() {
+                for (a, v) in self.point(i).into_iter().enumerate() {
+                    min[a] = min[a].min(v);
+                    max[a] = max[a].max(v);
+                }
+            }
+            let center = [0, 1].map(|a| (min[a] + max[a]) / 2.);
+            let half = ((max[0] - min[0]).max(max[1] - min[1]) / 2.).max(1e-8) * 1.1;
+            let (w, h) = (bounds.width as f64, bounds.height as f64);
+            // Pixels per rad
+            let scale = w.min(h) / 2. / half;
+            let to_point = |[x, y]: [f64; 2]| {
+                Point::new(
+                    (w / 2. + (x - center[0]) * scale) as f32,
+                    (h / 2. - (y - center[1]) * scale) as f32,
+                )
+            };
+            let x_range = center[0] - w / 2. / scale..center[0] + w / 2. / scale;
+            let y_range = center[1] - h / 2. / scale..center[1] + h / 2. / scale;
+
+            // Grid
+            {
+                let ws = [1., 2., 5., 10., 20., 50.].into_iter();
+                let d = grid_size(2. * half, w.min(h), 80., ws);
+                let gray =
+                    |level| Stroke::default().with_color(Color::from_rgb(level, level, level));
+                for a in iterate_range(d, x_range.clone()) {
+                    let x = to_point([a, 0.]).x;
+                    let level = if a.abs() < d / 2. { 0.5 } else { 0.2 };
+                    frame.stroke(
+                        &Path::line(Point::new(x, 0.), Point::new(x, bounds.height)),
+                        gray(level),
+                    );
+                    frame.fill_text(Text {
+                        color: Color::WHITE,
+                        size: 12.0.into(),
+                        position: Point::new(x + 3., bounds.height - 3.),
+                        vertical_alignment: alignment::Vertical::Bottom,
+                        content: angle(a),
+                        ..Text::default()
+                    });
+                }
+                for a in iterate_range(d, y_range.clone()) {
+                    let y = to_point([0., a]).y;
+                    let level = if a.abs() < d / 2. { 0.5 } else { 0.2 };
+                    frame.stroke(
+                        &Path::line(Point::new(0., y), Point::new(bounds.width, y)),
+                        gray(level),
+                    );
+                    frame.fill_text(Text {
+                        color: Color::WHITE,
+                        size: 12.0.into(),
+                        position: Point::new(bounds.width - 3., y - 3.),
+                        horizontal_alignment: alignment::Horizontal::Right,
+                        vertical_alignment: alignment::Vertical::Bottom,
+                        content: angle(a),
+                        ..Text::default()
+                    });
+                }
+            }
+
+            // Target
+            let within = match (current, tolerance) {
+                (Some([x, y]), Some(tolerance)) => {
+                    (target[0] - x).hypot(target[1] - y) <= tolerance
+                }
+                _ => false,
+            };
+            let target_color = Color::from_rgb(0.3, 0.8, 1.);
+            let center_point = to_point(target);
+            let crosshair = Stroke::default().with_color(Color {
+                a: 0.6,
+                ..target_color
+            });
+            frame.stroke(
+                &Path::line(
+                    Point::new(0., center_point.y),
+                    Point::new(bounds.width, center_point.y),
+                ),
+                crosshair.clone(),
+            );
+            frame.stroke(
+                &Path::line(
+                    Point::new(center_point.x, 0.),
+                    Point::new(center_point.x, bounds.height),
+                ),
+                crosshair,
+            );
+            if let Some(tolerance) = tolerance {
+                let color = if within {
+                    Color::from_rgb(0., 1., 0.)
+                } else {
+                    Color::from_rgb(1., 0.3, 0.3)
+                };
+                frame.stroke(
+                    &Path::circle(center_point, (tolerance * scale) as f32),
+                    Stroke::default().with_color(color).with_width(2.),
+                );
+            }
+
+            // History, older points fainter
+            let count = self.visible.len().max(1) as f32;
+            for i in shown {
+                let age = (self.visible.end - i) as f32 / count;
+                let size = 3.;
+                frame.fill_rectangle(
+                    to_point(self.point(i)) - iced::Vector::new(size, size) * 0.5,
+                    Size::new(size, size),
+                    Color::from_rgba(0., 1., 0., 0.05 + 0.6 * (1. - age)),
+                );
+            }
+
+            // Current position and the way to the target
+            if let Some(current) = current {
+                let point = to_point(current);
+                frame.stroke(
+                    &Path::line(point, center_point),
+                    Stroke::default().with_color(Color::from_rgba(1., 1., 1., 0.5)),
+                );
+                frame.fill(&Path::circle(point, 5.), Color::from_rgb(1., 0.8, 0.));
+            }
+
+            for (i, line) in self.readout(current).into_iter().enumerate() {
+                frame.fill_text(Text {
+                    color: Color::WHITE,
+                    size: 16.0.into(),
+                    position: Point::new(10., 10. + 20. * i as f32),
+                    content: line,
+                    ..Text::default()
+                });
+            }
+        });
+        vec![geometry]
+    }
+}
+
+/// In µrad below 1 mrad, otherwise in mrad.
+fn angle(rad: f64) -> String {
+    if rad.abs() < 1e-3 {
+        format!("{:+.3} μrad", rad * 1e6)
+    } else {
+        format!("{:+.4} mrad", rad * 1e3)
+    }
+}
@@ -0,0 +1,201 @@
+//! Resampling onto a common clock and comparison of two heads.
+
+use std::time::Duration;
+
+use chrono::{DateTime, Local};
+use clap::ValueEnum;
+use tm2070::{
+    recording::Row,
+    stats::{self, Summary},
+};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+pub enum Method {
+    /// The sample closest to each point
+    Nearest,
+    /// Linear interpolation between the samples around each point
+    Linear,
+}
+
+/// Resamples each source at the multiples of `period` since the Unix epoch,
+/// so that resampled sources share their timestamps.
+/// Samples farther than `max_gap` from a point are not used;
+/// points without usable samples are left out. Markers are kept as they are.
+pub fn resample(rows: &[Row], period: Duration, method: Method, max_gap: Duration) -> Vec<Row> {
+    let period = period.as_nanos() as i64;
+    let max_gap = max_gap.as_nanos() as i64;
+    let mut resampled = vec![];
+    for source in tm2070::recording::sources(rows) {
+        let mut samples = (rows.iter())
+            .filter(|row| row.source == source)
+            .filter_map(|row| Some((row.time.timestamp_nanos_opt()?, row.xy()?)))
+            .collect::<Vec<_>>();
+        samples.sort_by_key(|&(t, _)| t);
+        let (Some(&(first, _)), Some(&(last, _))) = (samples.first(), samples.last()) else {
+            continue;
+        };
+        // Index of the first sample after the point
+        let mut next = 0;
+        let mut point = first.div_euclid(period) * period;
+        if point < first {
+            point += period;
+        }
+        while point <= last {
+            while next < samples.len() && samples[next].0 <= point {
+                next += 1;
+            }
+            let before = next.checked_sub(1).map(|i| samples[i]);
+            let after = samples.get(next).copied();
+            let near =
+                |s: Option<(i64, [f64; 2])>| s.filter(|&(t, _)| (t - point).abs() <= max_gap);
+            let xy = match (method, near(before), near(after)) {
+                (Method::Linear, Some((tb, b)), Some((ta, a))) => {
+                    let f = (point - tb) as f64 / (ta - tb) as f64;
+                    Some([0, 1].map(|i| b[i] + (a[i] - b[i]) * f))
+                }
+                (Method::Linear, Some((tb, b)), None) if tb == point => Some(b),
+                (Method::Linear, ..) => None,
+                (Method::Nearest, Some((tb, b)), Some((ta, a))) => {
+                    Some(if point - tb <= ta - point { b } else { a })
+                }
+                (Method::Nearest, before, after) => before.or(after).map(|(_, xy)| xy),
+            };
+            if let Some([x, y]) = xy {
+                resampled.push(Row {
+                    time: DateTime::from_timestamp_nanos(point).into(),
+                    source: source.clone(),
+                    seq: None,
+                    unit: None,
+                    x: Some(x),
+                    y: Some(y),
+                    norm: None,
+                    direction: None,
+                    judge: None,
+                    marker: None,
+                });
+            }
+            point += period;
+        }
+    }
+    resampled.extend(rows.iter().filter(|row| row.is_marker()).cloned());
+    resampled.sort_by_key(|row| row.time);
+    resampled
+}
+
+/// Two heads on the same grid of points.
+pub struct Pair {
+    pub start: DateTime<Local>,
+    pub period: Duration,
+    /// [x, y] of each head at each point of the grid, if it had a sample there
+    pub a: Vec<Option<[f64; 2]>>,
+    pub b: Vec<Option<[f64; 2]>>,
+}
+
+impl Pair {
+    /// `a` and `b` must have been resampled with `period`.
+    pub fn new(a: &[Row], b: &[Row], period: Duration) -> Option<Self> {
+        let nanos = |row: &Row| row.time.timestamp_nanos_opt();
+        let times = a.iter().chain(b).filter_map(nanos);
+        let (first, last) = (times.clone().min()?, times.max()?);
+        let step = period.as_nanos() as i64;
+        let len = ((last - first) / step + 1) as usize;
+        let fill = |rows: &[Row]| {
+            let mut values = vec![None; len];
+            for row in rows {
+                if let (Some(t), Some(xy)) = (nanos(row), row.xy()) {
+                    values[((t - first) / step) as usize] = Some(xy);
+                }
+            }
+            values
+        };
+        Some(Self {
+            start: DateTime::from_timestamp_nanos(first).into(),
+            period,
+            a: fill(a),
+            b: fill(b),
+        })
+    }
+
+    fn axis(values: &[Option<[f64; 2]>], axis: usize) -> Vec<Option<f64>> {
+        values.iter().map(|v| v.map(|xy| xy[axis])).collect()
+    }
+
+    /// Correlation of each axis of `a` with `b` delayed by `lag` points.
+    pub fn correlation(&self, lag: isize) -> [Option<f64>; 2] {
+        [0, 1].map(|axis| {
+            let a = Self::axis(&self.a, axis);
+            let b = Self::axis(&self.b, axis);
+            stats::cross_correlation(&a, &b, lag)
+        })
+    }
+
+    /// Statistics of the common mode, `(a + b) / 2`, and the differential mode, `a - b`,
+    /// over the points where both heads have a sample.
+    /// The Allan deviation assumes that the points are contiguous, i.e. recorded without gaps.
+    pub fn modes(&self) -> Option<(Summary, Summary)> {
+        let mut t = vec![];
+        let mut common = [vec![], vec![]];
+        let mut differential = [vec![], vec![]];
+        for (i, (a, b)) in self.a.iter().zip(&self.b).enumerate() {
+            let (Some(a), Some(b)) = (a, b) else {
+                continue;
+            };
+            t.push(i as f64 * self.period.as_secs_f64());
+            for axis in 0..2 {
+                common[axis].push((a[axis] + b[axis]) / 2.);
+                differential[axis].push(a[axis] - b[axis]);
+            }
+        }
+        // Points where only one head had a sample
+        let missing = (self.a.iter().zip(&self.b))
+            .filter(|(a, b)| a.is_some() != b.is_some())
+            .count();
+        Some((
+            stats::summarize(&t, [&common[0], &common[1]], missing)?,
+            stats::summarize(&t, [&differential[0], &differential[1]], missing)?,
+        ))
+    }
+}
+
+/// Motion of one head around a marker.
+pub struct Disturbance {
+    /// Mean of x and y over the window after the marker minus that before
+    pub shift: [f64; 2],
+    pub std_before: [f64; 2],
+    pub std_after: [f64; 2],
+}
+
+/// Compares the samples of `source` within `window` before and after `time`.
+/// `None` unless both sides have at least two samples.
+pub fn disturbance(
+    rows: &[Row],
+    source: &str,
+    time: DateTime<Local>,
+    window: Duration,
+) -> Option<Disturbance> {
+    let window = chrono::Duration::from_std(window).ok()?;
+    let mut before = [vec![], vec![]];
+    let mut after = [vec![], vec![]];
+    for row in rows.iter().filter(|row| row.source == source) {
+        let Some(xy) = row.xy() else {
+            continue;
+        };
+        let side = if time - window <= row.time && row.time < time {
+            &mut before
+        } else if time <= row.time && row.time < time + window {
+            &mut after
+        } else {
+            continue;
+        };
+        side[0].push(xy[0]);
+        side[1].push(xy[1]);
+    }
+    if before[0].len() < 2 || after[0].len() < 2 {
+        return None;
+    }
+    Some(Disturbance {
+        shift: [0, 1].map(|i| stats::mean(&after[i]) - stats::mean(&before[i])),
+        std_before: before.each_ref().map(|v| stats::std_dev(v)),
+        std_after: after.each_ref().map(|v| stats::std_dev(v)),
+    })
+}
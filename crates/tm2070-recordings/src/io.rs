@@ -0,0 +1,118 @@
+//! Writing recordings. They are read by [`tm2070::recording`].
+
+use std::{
+    io::{BufWriter, Write},
+    path::Path,
+    sync::Arc,
+};
+
+use anyhow::Context;
+use arrow_array::{
+    ArrayRef, Float64Array, RecordBatch, StringArray, TimestampNanosecondArray, UInt64Array,
+};
+use arrow_schema::{DataType, Field, Schema, TimeUnit};
+use fs_err::File;
+use log::info;
+use parquet::{
+    arrow::ArrowWriter,
+    basic::{Compression, ZstdLevel},
+    file::properties::WriterProperties,
+};
+use tm2070::recording::{Format, Row, CSV_HEADER};
+
+pub fn write(path: &Path, format: Option<Format>, rows: &[Row]) -> anyhow::Result<()> {
+    let format = match format {
+        Some(format) => format,
+        None => Format::from_path(path)?,
+    };
+    let mut writer = BufWriter::new(File::create(path)?);
+    match format {
+        Format::Json => serde_json::to_writer(&mut writer, rows)?,
+        Format::Jsonl => {
+            for row in rows {
+                serde_json::to_writer(&mut writer, row)?;
+                writer.write_all(b"\n")?;
+            }
+        }
+        Format::Csv => write_csv(&mut writer, rows)?,
+        Format::Parquet => write_parquet(&mut writer, rows)?,
+    }
+    writer.flush()?;
+    writer.get_ref().sync_all()?;
+    info!("Wrote {} rows to {}", rows.len(), path.display());
+    Ok(())
+}
+
+fn write_csv(writer: impl Write, rows: &[Row]) -> anyhow::Result<()> {
+    fn cell<T: ToString>(value: &Option<T>) -> String {
+        value.as_ref().map(T::to_string).unwrap_or_default()
+    }
+    let mut writer = csv::Writer::from_writer(writer);
+    writer.write_record(CSV_HEADER)?;
+    for row in rows {
+        writer.write_record([
+            row.time.to_rfc3339(),
+            row.source.clone(),
+            cell(&row.seq),
+            cell(&row.unit),
+            cell(&row.x),
+            cell(&row.y),
+            cell(&row.norm),
+            cell(&row.direction),
+            cell(&row.judge),
+            cell(&row.marker),
+        ])?;
+    }
+    writer.flush()?;
+    Ok(())
+}
+
+/// Same columns as the Parquet output of `tm2070-continuous-measurement`,
+/// except that those other than time, source, x and y are nullable.
+fn write_parquet(writer: impl Write + Send, rows: &[Row]) -> anyhow::Result<()> {
+    const ROW_GROUP_SIZE: usize = 1 << 17;
+    let time = DataType::Timestamp(TimeUnit::Nanosecond, Some("UTC".into()));
+    let schema = Arc::new(Schema::new(vec![
+        Field::new("time", time, false),
+        Field::new("seq", DataType::UInt64, true),
+        Field::new("source", DataType::Utf8, false),
+        Field::new("unit", DataType::Utf8, true),
+        Field::new("x", DataType::Float64, true),
+        Field::new("y", DataType::Float64, true),
+        Field::new("norm", DataType::Float64, true),
+        Field::new("direction", DataType::Float64, true),
+        Field::new("judge", DataType::Utf8, true),
+        Field::new("marker", DataType::Utf8, true),
+    ]));
+    let props = WriterProperties::builder()
+        .set_compression(Compression::ZSTD(ZstdLevel::default()))
+        .build();
+    let mut writer = ArrowWriter::try_new(writer, schema.clone(), Some(props))?;
+    for chunk in rows.chunks(ROW_GROUP_SIZE) {
+        let time = (chunk.iter())
+            .map(|row| row.time.timestamp_nanos_opt().context("Time out of range"))
+            .collect::<anyhow::Result<Vec<_>>>()?;
+        let floats = |f: fn(&Row) -> Option<f64>| -> ArrayRef {
+            Arc::new(chunk.iter().map(f).collect::<Float64Array>())
+        };
+        let strings = |f: fn(&Row) -> Option<&str>| -> ArrayRef {
+            Arc::new(chunk.iter().map(f).collect::<StringArray>())
+        };
+        let columns: Vec<ArrayRef> = vec![
+            Arc::new(TimestampNanosecondArray::from(time).with_timezone("UTC")),
+            Arc::new(chunk.iter().map(|row| row.seq).collect::<UInt64Array>()),
+            strings(|row| Some(&row.source)),
+            strings(|row| row.unit.as_deref()),
+            floats(|row| row.x),
+            floats(|row| row.y),
+            floats(|row| row.norm),
+            floats(|row| row.direction),
+            strings(|row| row.judge.as_deref()),
+            strings(|row| row.marker.as_deref()),
+        ];
+        writer.write(&RecordBatch::try_new(schema.clone(), columns)?)?;
+        writer.flush()?;
+    }
+    writer.close()?;
+    Ok(())
+}
@@ -0,0 +1,363 @@
+mod analysis;
+mod io;
+mod row;
+
+use std::{path::PathBuf, time::Duration};
+
+use analysis::{Method, Pair};
+use anyhow::{bail, Context};
+use clap::{
+    builder::{PossibleValuesParser, TypedValueParser},
+    Args, Parser, Subcommand,
+};
+use row::Selection;
+use tm2070::{
+    recording::{self, Format, Row},
+    stats::{self, Summary},
+};
+
+/// Post-processing of recordings of tm2070-continuous-measurement.
+///
+/// Inputs are files or directories, including session directories and legacy JSON arrays.
+#[derive(Parser)]
+struct Opts {
+    #[command(subcommand)]
+    sub: Sub,
+}
+
+#[derive(Args)]
+struct Output {
+    #[clap(short, long)]
+    output: PathBuf,
+    /// Defaults to the extension of the output
+    #[clap(long, value_parser = PossibleValuesParser::new(Format::NAMES).map(|s| s.parse::<Format>().unwrap()))]
+    format: Option<Format>,
+}
+
+#[derive(Subcommand)]
+enum Sub {
+    /// Merges recordings into one, sorted by time. Duplicate samples are dropped
+    Merge {
+        #[clap(required = true)]
+        inputs: Vec<PathBuf>,
+        #[command(flatten)]
+        output: Output,
+    },
+    /// Keeps the samples of some sources or within a time range
+    Filter {
+        #[clap(required = true)]
+        inputs: Vec<PathBuf>,
+        #[command(flatten)]
+        output: Output,
+        #[command(flatten)]
+        selection: Selection,
+    },
+    /// Resamples each source at a fixed rate onto a common clock
+    Resample {
+        #[clap(required = true)]
+        inputs: Vec<PathBuf>,
+        #[command(flatten)]
+        output: Output,
+        #[command(flatten)]
+        selection: Selection,
+        /// Hz
+        #[clap(long)]
+        rate: f64,
+        #[clap(long, value_enum, default_value = "linear")]
+        method: Method,
+        /// Seconds; samples farther than this from a point are not used. Defaults to two periods
+        #[clap(long)]
+        max_gap: Option<f64>,
+    },
+    /// Converts a recording to another format
+    Convert {
+        input: PathBuf,
+        #[command(flatten)]
+        output: Output,
+    },
+    /// Prints statistics of each source. The Allan deviation assumes recordings without gaps
+    Stats {
+        #[clap(required = true)]
+        inputs: Vec<PathBuf>,
+        #[command(flatten)]
+        selection: Selection,
+        /// Print one JSON object per source instead
+        #[clap(long)]
+        json: bool,
+    },
+    /// Cross-correlates two heads and separates their common and differential motion
+    Xcorr {
+        #[clap(required = true)]
+        inputs: Vec<PathBuf>,
+        #[command(flatten)]
+        selection: Selection,
+        /// First source; may be omitted if the inputs have exactly two
+        #[clap(long)]
+        a: Option<String>,
+        #[clap(long)]
+        b: Option<String>,
+        /// Hz at which both heads are resampled before correlating
+        #[clap(long, default_value = "10")]
+        rate: f64,
+        /// Seconds of delay of b to try in each direction
+        #[clap(long, default_value = "10")]
+        max_lag: f64,
+        /// Write the correlation at every lag to this CSV file
+        #[clap(short, long)]
+        output: Option<PathBuf>,
+    },
+    /// Lists the markers with the motion of each head around them
+    Markers {
+        #[clap(required = true)]
+        inputs: Vec<PathBuf>,
+        #[command(flatten)]
+        selection: Selection,
+        /// Seconds before and after each marker over which the heads are compared
+        #[clap(long, default_value = "10")]
+        window: f64,
+    },
+}
+
+fn main() -> anyhow::Result<()> {
+    env_logger::builder()
+        .filter_level(log::LevelFilter::Info)
+        .parse_default_env()
+        .init();
+    let opts = Opts::parse();
+
+    match opts.sub {
+        Sub::Merge { inputs, output } => {
+            let mut rows = recording::read_all(&inputs)?;
+            rows.sort_by(|a, b| (a.time, &a.source).cmp(&(b.time, &b.source)));
+            rows.dedup_by(|a, b| a.time == b.time && a.source == b.source);
+            io::write(&output.output, output.format, &rows)?;
+        }
+        Sub::Filter {
+            inputs,
+            output,
+            selection,
+        } => {
+            let rows = select(&inputs, &selection)?;
+            io::write(&output.output, output.format, &rows)?;
+        }
+        Sub::Resample {
+            inputs,
+            output,
+            selection,
+            rate,
+            method,
+            max_gap,
+        } => {
+            let period = period(rate)?;
+            let max_gap = match max_gap {
+                None => period * 2,
+                Some(max_gap) => Duration::try_from_secs_f64(max_gap)
+                    .with_context(|| format!("Invalid max gap {max_gap}"))?,
+            };
+            let rows = analysis::resample(&select(&inputs, &selection)?, period, method, max_gap);
+            io::write(&output.output, output.format, &rows)?;
+        }
+        Sub::Convert { input, output } => {
+            let rows = recording::read_all(&[input])?;
+            io::write(&output.output, output.format, &rows)?;
+        }
+        Sub::Stats {
+            inputs,
+            selection,
+            json,
+        } => {
+            let rows = select(&inputs, &selection)?;
+            for source in recording::sources(&rows) {
+                let rows = rows.iter().filter(|row| row.source == source);
+                let Some(summary) = summarize(rows) else {
+                    eprintln!("{source}: Not enough samples");
+                    continue;
+                };
+                if json {
+                    let value = serde_json::json!({ "source": source, "summary": summary });
+                    println!("{value}");
+                } else {
+                    print_summary(&source, &summary);
+                }
+            }
+        }
+        Sub::Xcorr {
+            inputs,
+            selection,
+            a,
+            b,
+            rate,
+            max_lag,
+            output,
+        } => {
+            let period = period(rate)?;
+            if !(max_lag.is_finite() && max_lag >= 0.) {
+                bail!("Invalid max lag {max_lag}");
+            }
+            let rows = select(&inputs, &selection)?;
+            let sources = recording::sources(&rows);
+            let other = |x: &str| sources.iter().find(|s| *s != x).unwrap().clone();
+            let (a, b) = match (a, b, sources.len()) {
+                (Some(a), Some(b), _) => (a, b),
+                (None, None, 2) => (sources[0].clone(), sources[1].clone()),
+                (Some(a), None, 2) => (other(&a), a),
+                (None, Some(b), 2) => (other(&b), b),
+                _ => bail!("Specify --a and --b; sources are {sources:?}"),
+            };
+            let resample = |source: &str| {
+                let rows = (rows.iter())
+                    .filter(|row| row.source == source)
+                    .cloned()
+                    .collect::<Vec<_>>();
+                analysis::resample(&rows, period, Method::Linear, period * 2)
+            };
+            let Some(pair) = Pair::new(&resample(&a), &resample(&b), period) else {
+                bail!("No samples of {a} or {b}");
+            };
+            xcorr(&a, &b, &pair, max_lag, output)?;
+        }
+        Sub::Markers {
+            inputs,
+            selection,
+            window,
+        } => {
+            if !(window.is_finite() && window > 0.) {
+                bail!("Invalid window {window}");
+            }
+            let window = Duration::from_secs_f64(window);
+            let rows = select(&inputs, &selection)?;
+            let sources = recording::sources(&rows);
+            for marker in rows.iter().filter(|row| row.is_marker()) {
+                println!(
+                    "{} {}: {}",
+                    marker.time.format("%Y-%m-%d %H:%M:%S%.3f"),
+                    marker.source,
+                    marker.marker.as_deref().unwrap_or_default()
+                );
+                for source in &sources {
+                    match analysis::disturbance(&rows, source, marker.time, window) {
+                        Some(d) => println!(
+                            "  {source}: moved {} µrad; std {} µrad before, {} after",
+                            urad(d.shift),
+                            urad(d.std_before),
+                            urad(d.std_after)
+                        ),
+                        None => println!("  {source}: not enough samples around the marker"),
+                    }
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+fn period(rate: f64) -> anyhow::Result<Duration> {
+    if !(rate.is_finite() && rate > 0.) {
+        bail!("Invalid rate {rate}");
+    }
+    let period = Duration::from_secs_f64(1. / rate);
+    // Resampling steps in whole nanoseconds
+    if period < Duration::from_nanos(1) {
+        bail!("Rate {rate} Hz is too high");
+    }
+    Ok(period)
+}
+
+/// Reads, selects and sorts by time.
+fn select(inputs: &[PathBuf], selection: &Selection) -> anyhow::Result<Vec<Row>> {
+    let mut rows = recording::read_all(inputs)?;
+    rows.retain(|row| selection.matches(row));
+    rows.sort_by_key(|row| row.time);
+    Ok(rows)
+}
+
+fn summarize<'a>(rows: impl Iterator<Item = &'a Row>) -> Option<Summary> {
+    let mut first = None;
+    let mut t = vec![];
+    let mut xy = [vec![], vec![]];
+    let mut missing = 0;
+    for row in rows.filter(|row| !row.is_marker()) {
+        let Some([x, y]) = row.xy() else {
+            missing += 1;
+            continue;
+        };
+        let first = *first.get_or_insert(row.time);
+        t.push((row.time - first).num_nanoseconds()? as f64 * 1e-9);
+        xy[0].push(x);
+        xy[1].push(y);
+    }
+    stats::summarize(&t, [&xy[0], &xy[1]], missing)
+}
+
+fn urad([x, y]: [f64; 2]) -> String {
+    format!("x {:.3} y {:.3}", x * 1e6, y * 1e6)
+}
+
+fn print_summary(name: &str, summary: &Summary) {
+    println!(
+        "{name}: {} samples ({} missing) over {:.1} s",
+        summary.count, summary.missing, summary.duration
+    );
+    println!("  mean      {} µrad", urad(summary.mean));
+    println!("  std       {} µrad", urad(summary.std));
+    println!("  p-p       {} µrad", urad(summary.peak_to_peak));
+    println!("  drift     {} µrad/s", urad(summary.drift));
+    for point in &summary.allan {
+        println!(
+            "  ADEV {:>8.3} s: {} µrad",
+            point.tau,
+            urad(point.deviation)
+        );
+    }
+}
+
+fn xcorr(
+    a: &str,
+    b: &str,
+    pair: &Pair,
+    max_lag: f64,
+    output: Option<PathBuf>,
+) -> anyhow::Result<()> {
+    let period = pair.period.as_secs_f64();
+    // Lags beyond the length of the grid have no overlap
+    let max_lag = ((max_lag / period).round() as isize).min(pair.a.len() as isize);
+    let correlations = (-max_lag..=max_lag)
+        .map(|lag| (lag, pair.correlation(lag)))
+        .collect::<Vec<_>>();
+
+    println!(
+        "{a} vs {b}, resampled at {:.3} Hz from {}",
+        1. / period,
+        pair.start
+    );
+    for (axis, name) in ["x", "y"].into_iter().enumerate() {
+        let zero = correlations[max_lag as usize].1[axis];
+        let peak = (correlations.iter())
+            .filter_map(|&(lag, c)| Some((lag, c[axis]?)))
+            .max_by(|p, q| p.1.abs().total_cmp(&q.1.abs()));
+        let format = |c: Option<f64>| c.map_or("-".to_owned(), |c| format!("{c:+.3}"));
+        match peak {
+            Some((lag, c)) => println!(
+                "  {name}: correlation {} at zero lag, peak {c:+.3} with {b} delayed by {:.3} s",
+                format(zero),
+                lag as f64 * period
+            ),
+            None => println!("  {name}: not enough overlapping samples"),
+        }
+    }
+    if let Some((common, differential)) = pair.modes() {
+        print_summary("common mode (a + b) / 2", &common);
+        print_summary("differential mode a - b", &differential);
+    }
+
+    if let Some(output) = output {
+        let mut writer = csv::Writer::from_path(&output)?;
+        writer.write_record(["lag", "x", "y"])?;
+        for (lag, [x, y]) in correlations {
+            let cell = |c: Option<f64>| c.map(|c| c.to_string()).unwrap_or_default();
+            writer.write_record([(lag as f64 * period).to_string(), cell(x), cell(y)])?;
+        }
+        writer.flush()?;
+    }
+    Ok(())
+}
@@ -0,0 +1,40 @@
+//! Selecting rows of recordings.
+
+use anyhow::{bail, Context};
+use chrono::{DateTime, Local, NaiveDateTime, TimeZone};
+use clap::Args;
+use tm2070::recording::Row;
+
+#[derive(Args, Clone, Debug, Default)]
+pub struct Selection {
+    /// Keep only this source (COM port); repeatable. Markers are kept whatever their source
+    #[clap(long = "source")]
+    pub sources: Vec<String>,
+    /// Keep samples at or after this time, in RFC 3339 or local `YYYY-MM-DD HH:MM:SS`
+    #[clap(long, value_parser = parse_time)]
+    pub from: Option<DateTime<Local>>,
+    /// Keep samples before this time
+    #[clap(long, value_parser = parse_time)]
+    pub to: Option<DateTime<Local>>,
+}
+
+impl Selection {
+    pub fn matches(&self, row: &Row) -> bool {
+        (self.sources.is_empty() || self.sources.contains(&row.source) || row.is_marker())
+            && self.from.is_none_or(|from| row.time >= from)
+            && self.to.is_none_or(|to| row.time < to)
+    }
+}
+
+pub fn parse_time(s: &str) -> anyhow::Result<DateTime<Local>> {
+    if let Ok(time) = DateTime::parse_from_rfc3339(s) {
+        return Ok(time.into());
+    }
+    let naive = NaiveDateTime::parse_from_str(s, "%Y-%m-%d %H:%M:%S%.f")
+        .or_else(|_| NaiveDateTime::parse_from_str(s, "%Y-%m-%dT%H:%M:%S%.f"))
+        .with_context(|| format!("Invalid time {s:?}"))?;
+    match Local.from_local_datetime(&naive).earliest() {
+        Some(time) => Ok(time),
+        None => bail!("{s} does not exist in the local time zone"),
+    }
+}
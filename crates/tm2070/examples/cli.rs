@@ -1,8 +1,8 @@
-use std::{sync::mpsc, thread::sleep, time::Duration};
+use std::{sync::mpsc, time::Duration};
 
 use clap::Parser;
 use log::error;
-use tm2070::Tm2070;
+use tm2070::{SamplingData1, Tm2070};
 
 #[derive(Parser)]
 struct Opts {
@@ -10,6 +10,31 @@ struct Opts {
 
     #[clap(long)]
     continuous: bool,
+
+    /// One of min, deg, mdeg, mrad, deg-min-sec, min-sec
+    #[cfg(feature = "unverified-commands")]
+    #[clap(long)]
+    unit: Option<String>,
+
+    #[cfg(feature = "unverified-commands")]
+    #[clap(long)]
+    average_count: Option<u16>,
+
+    /// Print the settings of the head before sampling
+    #[cfg(feature = "unverified-commands")]
+    #[clap(long)]
+    settings: bool,
+
+    #[clap(long, default_value = "38400")]
+    baud_rate: u32,
+
+    /// Response timeout in seconds
+    #[clap(long, default_value = "1.0")]
+    timeout: f64,
+
+    /// Average this many readings as the reference and print readings relative to it
+    #[clap(long)]
+    reference: Option<usize>,
 }
 
 fn main() -> anyhow::Result<()> {
@@ -23,18 +48,39 @@ fn main() -> anyhow::Result<()> {
         }
     })?;
 
-    let mut tm2070 = Tm2070::new(&opts.com_port)?;
+    let mut tm2070 = Tm2070::builder(&opts.com_port)
+        .baud_rate(opts.baud_rate)
+        .timeout(Duration::from_secs_f64(opts.timeout))
+        .open()?;
+    #[cfg(feature = "unverified-commands")]
+    {
+        if let Some(unit) = &opts.unit {
+            tm2070.set_unit(unit.parse()?)?;
+        }
+        if let Some(count) = opts.average_count {
+            tm2070.set_average_count(count)?;
+        }
+        if opts.settings {
+            println!("{:?}", tm2070.settings()?);
+        }
+    }
+    let reference = match opts.reference {
+        Some(count) => Some(tm2070.capture_reference(count)?),
+        None => None,
+    };
+    let print = |data: SamplingData1| match &reference {
+        Some(reference) => println!("{:?}", reference.relative(&data)),
+        None => println!("{data:?}"),
+    };
     if opts.continuous {
         let handle = tm2070.continuous_1(None)?;
         while tx.try_recv().is_err() {
-            for data in handle.iter() {
-                println!("{:?}", data?);
+            if let Some(sample) = handle.recv_timeout(Duration::from_millis(100))? {
+                print(sample.data);
             }
-            sleep(Duration::from_millis(10));
         }
     } else {
-        let res = tm2070.single_1()?;
-        println!("{res:?}");
+        print(tm2070.single_1()?);
     }
 
     Ok(())
@@ -0,0 +1,38 @@
+//! Prints the samples published by `tm2070-continuous-measurement --publish` or the GUI.
+
+use clap::Parser;
+use tm2070::net::{Subscriber, DEFAULT_PORT};
+
+#[derive(Parser)]
+struct Opts {
+    /// Address of the publisher
+    #[clap(default_value_t = format!("127.0.0.1:{DEFAULT_PORT}"))]
+    addr: String,
+
+    /// Print only the samples of this source
+    #[clap(long)]
+    source: Option<String>,
+}
+
+fn main() -> anyhow::Result<()> {
+    env_logger::builder().format_timestamp_nanos().init();
+    let opts = Opts::parse();
+
+    let mut subscriber = Subscriber::connect(&opts.addr)?;
+    for record in subscriber.iter() {
+        let record = record?;
+        if opts.source.as_ref().is_some_and(|s| *s != record.source) {
+            continue;
+        }
+        let angle = |a: Option<tm2070::angle::Angle>| a.map_or("-".to_owned(), |a| a.to_string());
+        println!(
+            "{} {} {}: x {} y {}",
+            record.time.format("%H:%M:%S%.3f"),
+            record.seq,
+            record.source,
+            angle(record.data.x),
+            angle(record.data.y),
+        );
+    }
+    Ok(())
+}
@@ -0,0 +1,453 @@
+//! Alarms raised from a stream of samples.
+//!
+//! Each [`Rule`] evaluates a [`Condition`] per sample. An alarm is raised after the condition
+//! has been violated for [`Rule::raise_after`] consecutive samples, and cleared after it has been
+//! clear for [`Rule::clear_after`] consecutive samples. Threshold conditions have a hysteresis band
+//! in which the state is held, so that a value hovering around the limit does not chatter.
+
+use std::{
+    collections::VecDeque,
+    fmt,
+    sync::mpsc,
+    time::{Duration, Instant},
+};
+
+use bstr::BStr;
+use radians::Rad64;
+
+use crate::{Judge, Sample};
+
+#[derive(Clone, Debug)]
+pub enum Condition {
+    /// The head judged the sample `NG`.
+    JudgeNg,
+    /// No x or y, e.g. judge `ND` or `ER`.
+    BeamLost,
+    /// x or y is farther than `half_width` from `center`.
+    /// Clears once both are within `half_width - hysteresis`.
+    OutsideBox {
+        center: [Rad64; 2],
+        half_width: [Rad64; 2],
+        hysteresis: Rad64,
+    },
+    /// The magnitude of the linear drift over `window` exceeds `limit` (rad/s).
+    /// Clears once it is below `limit - hysteresis`.
+    DriftRate {
+        limit: f64,
+        hysteresis: f64,
+        window: Duration,
+    },
+}
+
+#[derive(Clone, Debug)]
+pub struct Rule {
+    pub name: String,
+    pub condition: Condition,
+    /// Consecutive violating samples needed to raise the alarm.
+    pub raise_after: usize,
+    /// Consecutive clear samples needed to clear the alarm.
+    pub clear_after: usize,
+}
+impl Rule {
+    /// Raises and clears on the first sample.
+    pub fn new(name: impl Into<String>, condition: Condition) -> Self {
+        Self {
+            name: name.into(),
+            condition,
+            raise_after: 1,
+            clear_after: 1,
+        }
+    }
+
+    /// Panics if either count is zero.
+    pub fn debounce(mut self, raise_after: usize, clear_after: usize) -> Self {
+        assert!(raise_after > 0 && clear_after > 0);
+        self.raise_after = raise_after;
+        self.clear_after = clear_after;
+        self
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum EventKind {
+    Raised,
+    Cleared,
+}
+
+#[derive(Clone, Debug)]
+pub struct AlarmEvent {
+    /// Index of the rule in the order it was added.
+    pub rule: usize,
+    pub name: String,
+    pub kind: EventKind,
+    /// [`Sample::seq`] of the sample that changed the state.
+    pub seq: u64,
+    pub time: Instant,
+    /// What the condition saw, e.g. the offending angle.
+    pub detail: String,
+}
+impl fmt::Display for AlarmEvent {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let kind = match self.kind {
+            EventKind::Raised => "raised",
+            EventKind::Cleared => "cleared",
+        };
+        write!(f, "Alarm {:?} {kind}: {}", self.name, self.detail)
+    }
+}
+
+/// Result of evaluating a condition on one sample.
+enum Verdict {
+    Violated(String),
+    Clear(String),
+    /// In the hysteresis band or not enough data; keeps the current state.
+    Hold,
+}
+
+struct RuleState {
+    rule: Rule,
+    active: bool,
+    streak: usize,
+    /// (time, [x, y]) for drift rate
+    history: VecDeque<(Instant, [f64; 2])>,
+}
+
+impl RuleState {
+    fn evaluate(&mut self, sample: &Sample) -> Verdict {
+        let xy = match (sample.data.x, sample.data.y) {
+            (Some(x), Some(y)) => Some([x.value().val(), y.value().val()]),
+            _ => None,
+        };
+        match &self.rule.condition {
+            Condition::JudgeNg => match sample.data.judge {
+                Judge::Ng => Verdict::Violated("judge NG".to_owned()),
+                Judge::Ok => Verdict::Clear("judge OK".to_owned()),
+                _ => Verdict::Hold,
+            },
+            Condition::BeamLost => match xy {
+                None => {
+                    Verdict::Violated(format!("judge {}", BStr::new(sample.data.judge.as_bytes())))
+                }
+                Some(_) => Verdict::Clear("beam detected".to_owned()),
+            },
+            Condition::OutsideBox {
+                center,
+                half_width,
+                hysteresis,
+            } => {
+                let Some(xy) = xy else {
+                    return Verdict::Hold;
+                };
+                let d = [xy[0] - center[0].val(), xy[1] - center[1].val()];
+                let detail = format!(
+                    "x {:+.2} µrad, y {:+.2} µrad from center",
+                    d[0] * 1e6,
+                    d[1] * 1e6
+                );
+                if (0..2).any(|i| d[i].abs() > half_width[i].val()) {
+                    Verdict::Violated(detail)
+                } else if (0..2).all(|i| d[i].abs() <= half_width[i].val() - hysteresis.val()) {
+                    Verdict::Clear(detail)
+                } else {
+                    Verdict::Hold
+                }
+            }
+            &Condition::DriftRate {
+                limit,
+                hysteresis,
+                window,
+            } => {
+                if let Some(xy) = xy {
+                    self.history.push_back((sample.received, xy));
+                }
+                while (self.history.front())
+                    .is_some_and(|&(t, _)| sample.received.saturating_duration_since(t) > window)
+                {
+                    self.history.pop_front();
+                }
+                let Some(rate) = drift_rate(&self.history, window) else {
+                    return Verdict::Hold;
+                };
+                let detail = format!("drift {:.3} µrad/s", rate * 1e6);
+                if rate > limit {
+                    Verdict::Violated(detail)
+                } else if rate <= limit - hysteresis {
+                    Verdict::Clear(detail)
+                } else {
+                    Verdict::Hold
+                }
+            }
+        }
+    }
+}
+
+/// Magnitude of the least-squares slope of x and y, once at least half of `window` is covered.
+fn drift_rate(history: &VecDeque<(Instant, [f64; 2])>, window: Duration) -> Option<f64> {
+    let &(first, _) = history.front()?;
+    let &(last, _) = history.back()?;
+    if history.len() < 3 || last.duration_since(first) < window / 2 {
+        return None;
+    }
+    let n = history.len() as f64;
+    let t = |at: Instant| at.duration_since(first).as_secs_f64();
+    let mean_t = history.iter().map(|&(at, _)| t(at)).sum::<f64>() / n;
+    let var_t = history
+        .iter()
+        .map(|&(at, _)| (t(at) - mean_t).powi(2))
+        .sum::<f64>();
+    let slope = |i: usize| {
+        let mean = history.iter().map(|(_, xy)| xy[i]).sum::<f64>() / n;
+        let cov = (history.iter())
+            .map(|&(at, xy)| (t(at) - mean_t) * (xy[i] - mean))
+            .sum::<f64>();
+        cov / var_t
+    };
+    Some(slope(0).hypot(slope(1)))
+}
+
+type Callback = Box<dyn FnMut(&AlarmEvent) + Send>;
+
+/// Evaluates rules on samples and notifies subscribers of state changes.
+#[derive(Default)]
+pub struct AlarmMonitor {
+    rules: Vec<RuleState>,
+    callbacks: Vec<Callback>,
+    senders: Vec<mpsc::Sender<AlarmEvent>>,
+}
+
+impl AlarmMonitor {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Returns the index used in [`AlarmEvent::rule`].
+    pub fn add_rule(&mut self, rule: Rule) -> usize {
+        self.rules.push(RuleState {
+            rule,
+            active: false,
+            streak: 0,
+            history: VecDeque::new(),
+        });
+        self.rules.len() - 1
+    }
+
+    /// Calls `callback` on every event, from the thread calling [`AlarmMonitor::check`].
+    pub fn on_event(&mut self, callback: impl FnMut(&AlarmEvent) + Send + 'static) {
+        self.callbacks.push(Box::new(callback));
+    }
+
+    /// Returns a receiver of every event. Dropping the receiver unsubscribes.
+    pub fn subscribe(&mut self) -> mpsc::Receiver<AlarmEvent> {
+        let (tx, rx) = mpsc::channel();
+        self.senders.push(tx);
+        rx
+    }
+
+    /// Evaluates every rule on `sample` and returns the resulting events,
+    /// which are also passed to the callbacks and subscribers.
+    pub fn check(&mut self, sample: &Sample) -> Vec<AlarmEvent> {
+        let mut events = vec![];
+        for (index, state) in self.rules.iter_mut().enumerate() {
+            let (violated, detail) = match state.evaluate(sample) {
+                Verdict::Violated(detail) => (true, detail),
+                Verdict::Clear(detail) => (false, detail),
+                Verdict::Hold => {
+                    state.streak = 0;
+                    continue;
+                }
+            };
+            if violated == state.active {
+                state.streak = 0;
+                continue;
+            }
+            state.streak += 1;
+            let needed = match violated {
+                true => state.rule.raise_after,
+                false => state.rule.clear_after,
+            };
+            if state.streak < needed {
+                continue;
+            }
+            state.active = violated;
+            state.streak = 0;
+            let event = AlarmEvent {
+                rule: index,
+                name: state.rule.name.clone(),
+                kind: match violated {
+                    true => EventKind::Raised,
+                    false => EventKind::Cleared,
+                },
+                seq: sample.seq,
+                time: sample.received,
+                detail,
+            };
+            events.push(event);
+        }
+        for event in &events {
+            for callback in &mut self.callbacks {
+                callback(event);
+            }
+            self.senders.retain(|tx| tx.send(event.clone()).is_ok());
+        }
+        events
+    }
+
+    /// Names of the alarms currently raised.
+    pub fn active(&self) -> impl Iterator<Item = &str> {
+        (self.rules.iter())
+            .filter(|state| state.active)
+            .map(|state| state.rule.name.as_str())
+    }
+
+    pub fn is_active(&self, rule: usize) -> bool {
+        self.rules[rule].active
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        angle::{Angle, Unit},
+        SamplingData1, TiltDirection,
+    };
+    use radians::Deg64;
+
+    /// Sample `seq`, received `seq` seconds after `start`, with x and y in µrad.
+    fn sample(start: Instant, seq: u64, xy: Option<[f64; 2]>, judge: Judge) -> Sample {
+        let angle = |v: f64| Angle::from_rad(Rad64::new(v * 1e-6), Unit::MRad);
+        Sample {
+            seq,
+            received: start + Duration::from_secs(seq),
+            data: SamplingData1 {
+                unit: Unit::MRad,
+                x: xy.map(|[x, _]| angle(x)),
+                y: xy.map(|[_, y]| angle(y)),
+                norm: xy.map(|[x, y]| angle(x.hypot(y))),
+                direction: TiltDirection(Deg64::new(0.)),
+                judge,
+            },
+        }
+    }
+
+    /// Feeds `judges` in order and returns the sequence numbers and kinds of the events.
+    fn judge_events(monitor: &mut AlarmMonitor, judges: &[Judge]) -> Vec<(u64, EventKind)> {
+        let start = Instant::now();
+        (judges.iter().enumerate())
+            .flat_map(|(seq, judge)| {
+                monitor.check(&sample(start, seq as u64, Some([0., 0.]), judge.clone()))
+            })
+            .map(|event| (event.seq, event.kind))
+            .collect()
+    }
+
+    #[test]
+    fn raises_and_clears_on_the_first_sample_without_debounce() {
+        let mut monitor = AlarmMonitor::new();
+        let rule = monitor.add_rule(Rule::new("NG", Condition::JudgeNg));
+        let events = judge_events(&mut monitor, &[Judge::Ok, Judge::Ng, Judge::Ok]);
+        assert_eq!(events, [(1, EventKind::Raised), (2, EventKind::Cleared)]);
+        assert!(!monitor.is_active(rule));
+    }
+
+    #[test]
+    fn debounce_needs_consecutive_samples() {
+        let mut monitor = AlarmMonitor::new();
+        let rule = monitor.add_rule(Rule::new("NG", Condition::JudgeNg).debounce(3, 2));
+        use Judge::{Ng, Ok};
+        // The OK at 2 restarts the count, so the alarm is raised at 5; the NG at 7 restarts
+        // the count of clear samples, so it is cleared at 9
+        let judges = [Ng, Ng, Ok, Ng, Ng, Ng, Ok, Ng, Ok, Ok];
+        let events = judge_events(&mut monitor, &judges);
+        assert_eq!(events, [(5, EventKind::Raised), (9, EventKind::Cleared)]);
+        assert!(!monitor.is_active(rule));
+    }
+
+    #[test]
+    fn hold_restarts_the_count() {
+        let mut monitor = AlarmMonitor::new();
+        monitor.add_rule(Rule::new("NG", Condition::JudgeNg).debounce(2, 2));
+        // ND neither violates nor clears the NG condition
+        let events = judge_events(&mut monitor, &[Judge::Ng, Judge::Nd, Judge::Ng]);
+        assert_eq!(events, []);
+    }
+
+    #[test]
+    fn beam_lost_without_x_and_y() {
+        let mut monitor = AlarmMonitor::new();
+        monitor.add_rule(Rule::new("beam lost", Condition::BeamLost));
+        let start = Instant::now();
+        let events = monitor.check(&sample(start, 0, None, Judge::Nd));
+        assert_eq!(events.len(), 1);
+        assert_eq!(events[0].kind, EventKind::Raised);
+        assert_eq!(events[0].detail, "judge ND");
+        assert_eq!(monitor.active().collect::<Vec<_>>(), ["beam lost"]);
+    }
+
+    #[test]
+    fn box_clears_below_the_hysteresis_band() {
+        let mut monitor = AlarmMonitor::new();
+        let rule = monitor.add_rule(Rule::new(
+            "box",
+            Condition::OutsideBox {
+                center: [Rad64::new(0.); 2],
+                half_width: [Rad64::new(10e-6); 2],
+                hysteresis: Rad64::new(1e-6),
+            },
+        ));
+        let start = Instant::now();
+        let mut check = |seq, x| monitor.check(&sample(start, seq, Some([x, 0.]), Judge::Ok));
+        assert_eq!(check(0, 9.5).len(), 0);
+        assert_eq!(check(1, -11.)[0].kind, EventKind::Raised);
+        // Within the box but in the band
+        assert_eq!(check(2, 9.5).len(), 0);
+        assert_eq!(check(3, 8.)[0].kind, EventKind::Cleared);
+        assert!(!monitor.is_active(rule));
+    }
+
+    #[test]
+    fn drift_rate_once_half_the_window_is_covered() {
+        let mut monitor = AlarmMonitor::new();
+        monitor.add_rule(Rule::new(
+            "drift",
+            Condition::DriftRate {
+                limit: 2e-6,
+                hysteresis: 0.2e-6,
+                window: Duration::from_secs(10),
+            },
+        ));
+        let start = Instant::now();
+        // x moves 3 µrad/s and y 4 µrad/s: 5 µrad/s in total
+        let events = (0..10)
+            .flat_map(|t| {
+                let xy = [3. * t as f64, 4. * t as f64];
+                monitor.check(&sample(start, t, Some(xy), Judge::Ok))
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(events.len(), 1);
+        assert_eq!(events[0].seq, 5);
+        assert_eq!(events[0].detail, "drift 5.000 µrad/s");
+    }
+
+    #[test]
+    fn notifies_callbacks_and_subscribers() {
+        let mut monitor = AlarmMonitor::new();
+        monitor.add_rule(Rule::new("NG", Condition::JudgeNg));
+        let (tx, rx) = mpsc::channel();
+        monitor.on_event(move |event| tx.send(event.kind).unwrap());
+        let subscriber = monitor.subscribe();
+        judge_events(&mut monitor, &[Judge::Ng, Judge::Ok]);
+        let kinds = [EventKind::Raised, EventKind::Cleared];
+        assert_eq!(rx.try_iter().collect::<Vec<_>>(), kinds);
+        assert_eq!(
+            subscriber.try_iter().map(|e| e.kind).collect::<Vec<_>>(),
+            kinds
+        );
+    }
+
+    #[test]
+    #[should_panic]
+    fn rejects_a_debounce_of_zero() {
+        Rule::new("NG", Condition::JudgeNg).debounce(0, 1);
+    }
+}
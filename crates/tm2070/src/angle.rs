@@ -1,6 +1,13 @@
+use std::{
+    fmt,
+    ops::{Add, Div, Mul, Neg, Sub},
+    str::FromStr,
+};
+
+use anyhow::{bail, Context};
 use radians::{Deg64, Rad64};
 
-#[derive(Clone, Copy, Debug)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
 pub enum Unit {
     Min,
     Deg,
@@ -9,14 +16,32 @@ pub enum Unit {
     DegMinSec,
     MinSec,
 }
+impl fmt::Display for Unit {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(crate::unit_word(*self))
+    }
+}
+/// Accepts the notation used in sampling data, e.g. `deg-min-sec`.
+impl FromStr for Unit {
+    type Err = anyhow::Error;
 
-#[derive(Clone, Copy, Debug)]
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        crate::parse_unit(s.as_bytes())
+    }
+}
+
+/// An angle as displayed by the instrument in one of the [`Unit`]s.
+///
+/// Arithmetic converts the right-hand side and returns the result in the unit of the left-hand side.
+#[derive(Clone, Copy, Debug, PartialEq)]
 pub enum Angle {
     Min(f64),
     Deg(f64),
     MDeg(f64),
     MRad(f64),
+    /// Degrees, minutes and seconds are not negative; the sign is kept separately.
     DegMinSec(Sign, f64, f64, f64),
+    /// Minutes and seconds are not negative; the sign is kept separately.
     MinSec(Sign, f64, f64),
 }
 impl Angle {
@@ -30,14 +55,264 @@ impl Angle {
             Angle::MinSec(sign, m, s) => (deg_from_dms(0., m, s) * sign.as_f64()).rad(),
         }
     }
+
+    /// Expresses `value` in `unit`.
+    pub fn from_rad(value: Rad64, unit: Unit) -> Self {
+        let rad = value.val();
+        let deg = rad.to_degrees();
+        match unit {
+            Unit::Min => Angle::Min(deg * 60.),
+            Unit::Deg => Angle::Deg(deg),
+            Unit::MDeg => Angle::MDeg(deg * 1e3),
+            Unit::MRad => Angle::MRad(rad * 1e3),
+            Unit::DegMinSec => {
+                let (sign, d, m, s) = dms_from_deg(Deg64::new(deg));
+                Angle::DegMinSec(sign, d, m, s)
+            }
+            Unit::MinSec => {
+                let (sign, d, m, s) = dms_from_deg(Deg64::new(deg));
+                Angle::MinSec(sign, d * 60. + m, s)
+            }
+        }
+    }
+
+    pub fn unit(&self) -> Unit {
+        match self {
+            Angle::Min(_) => Unit::Min,
+            Angle::Deg(_) => Unit::Deg,
+            Angle::MDeg(_) => Unit::MDeg,
+            Angle::MRad(_) => Unit::MRad,
+            Angle::DegMinSec(..) => Unit::DegMinSec,
+            Angle::MinSec(..) => Unit::MinSec,
+        }
+    }
+
+    /// The same angle expressed in `unit`.
+    pub fn to_unit(self, unit: Unit) -> Self {
+        if self.unit() == unit {
+            self
+        } else {
+            Self::from_rad(self.value(), unit)
+        }
+    }
+
+    /// Comma-separated words as in sampling data, e.g. `P,0,1,23.45`, for the simulator.
+    /// The number of decimals is the simulator's choice; parsing accepts any.
+    pub(crate) fn to_words(self) -> String {
+        match self {
+            Angle::Min(m) => format!("{m:.3}"),
+            Angle::Deg(d) => format!("{d:.5}"),
+            Angle::MDeg(md) => format!("{md:.3}"),
+            Angle::MRad(mr) => format!("{mr:.4}"),
+            Angle::DegMinSec(sign, ..) | Angle::MinSec(sign, ..) => {
+                let [d, m, s] = self.rounded_dms(2);
+                let sign = match sign {
+                    Sign::Positive => 'P',
+                    Sign::Negative => 'M',
+                };
+                match self {
+                    Angle::DegMinSec(..) => format!("{sign},{d},{m},{s:.2}"),
+                    _ => format!("{sign},{},{s:.2}", d * 60. + m),
+                }
+            }
+        }
+    }
+
+    /// Degrees, minutes and seconds with the seconds rounded to `precision` decimals,
+    /// carrying into minutes and degrees so that `60` is never shown.
+    fn rounded_dms(&self, precision: usize) -> [f64; 3] {
+        let seconds = match *self {
+            Angle::DegMinSec(_, d, m, s) => d * 3600. + m * 60. + s,
+            Angle::MinSec(_, m, s) => m * 60. + s,
+            _ => self.value().val().to_degrees().abs() * 3600.,
+        };
+        let scale = 10f64.powi(precision as i32);
+        let seconds = (seconds * scale).round() / scale;
+        let d = (seconds / 3600.).floor();
+        let m = ((seconds - d * 3600.) / 60.).floor();
+        [d, m, (seconds - d * 3600. - m * 60.).max(0.)]
+    }
+}
+
+/// Formats in the notation of the instrument panel, e.g. `1.2345 mrad` or `+0°01'23.45"`.
+/// The precision, if given, applies to the last component.
+impl fmt::Display for Angle {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let number = |f: &mut fmt::Formatter<'_>, x: f64| match f.precision() {
+            Some(p) => write!(f, "{x:.p$}"),
+            None => write!(f, "{x}"),
+        };
+        match *self {
+            Angle::Min(m) => {
+                number(f, m)?;
+                f.write_str("'")
+            }
+            Angle::Deg(d) => {
+                number(f, d)?;
+                f.write_str("°")
+            }
+            Angle::MDeg(md) => {
+                number(f, md)?;
+                f.write_str(" mdeg")
+            }
+            Angle::MRad(mr) => {
+                number(f, mr)?;
+                f.write_str(" mrad")
+            }
+            Angle::DegMinSec(sign, d, m, s) => {
+                let [d, m, s] = match f.precision() {
+                    Some(p) => self.rounded_dms(p),
+                    None => [d, m, s],
+                };
+                write!(f, "{sign}{d}°{m:02}'")?;
+                if s < 10. {
+                    f.write_str("0")?;
+                }
+                number(f, s)?;
+                f.write_str("\"")
+            }
+            Angle::MinSec(sign, m, s) => {
+                let [m, s] = match f.precision() {
+                    Some(p) => {
+                        let [d, m, s] = self.rounded_dms(p);
+                        [d * 60. + m, s]
+                    }
+                    None => [m, s],
+                };
+                write!(f, "{sign}{m}'")?;
+                if s < 10. {
+                    f.write_str("0")?;
+                }
+                number(f, s)?;
+                f.write_str("\"")
+            }
+        }
+    }
+}
+
+/// Accepts the output of [`Display`](fmt::Display), and a number followed by a unit word,
+/// e.g. `1.5 mrad`, `-0.2deg` or `1e-3 mrad`. Values that are not finite are rejected.
+impl FromStr for Angle {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        (|| {
+            let s = s.trim();
+            let number = |s: &str| {
+                let value = s.trim().parse::<f64>()?;
+                if !value.is_finite() {
+                    bail!("Not finite: {value}");
+                }
+                anyhow::Ok(value)
+            };
+            if let Some(rest) = s.strip_suffix('"') {
+                let (sign, rest) = match rest.chars().next() {
+                    Some('+') => (Sign::Positive, &rest[1..]),
+                    Some('-') => (Sign::Negative, &rest[1..]),
+                    _ => (Sign::Positive, rest),
+                };
+                let (rest, sec) = rest.split_once('\'').context("Minutes not found")?;
+                let sec = number(sec)?;
+                return Ok(match rest.split_once('°') {
+                    Some((d, m)) => Angle::DegMinSec(sign, number(d)?, number(m)?, sec),
+                    None => Angle::MinSec(sign, number(rest)?, sec),
+                });
+            }
+            if let Some(m) = s.strip_suffix('\'') {
+                return Ok(Angle::Min(number(m)?));
+            }
+            if let Some(d) = s.strip_suffix('°') {
+                return Ok(Angle::Deg(number(d)?));
+            }
+            // The unit word is the trailing run of letters, so that an exponent stays in the number
+            let split = s
+                .trim_end_matches(|c: char| c.is_ascii_alphabetic() || c == '-')
+                .len();
+            if split == s.len() {
+                bail!("Unit not found");
+            }
+            let value = number(&s[..split])?;
+            Ok(match s[split..].parse::<Unit>()? {
+                Unit::Min => Angle::Min(value),
+                Unit::Deg => Angle::Deg(value),
+                Unit::MDeg => Angle::MDeg(value),
+                Unit::MRad => Angle::MRad(value),
+                Unit::DegMinSec | Unit::MinSec => bail!("Use the notation of Display"),
+            })
+        })()
+        .with_context(|| format!("Failed to parse as Angle: {s:?}"))
+    }
+}
+
+impl Neg for Angle {
+    type Output = Self;
+
+    fn neg(self) -> Self {
+        match self {
+            Angle::Min(m) => Angle::Min(-m),
+            Angle::Deg(d) => Angle::Deg(-d),
+            Angle::MDeg(md) => Angle::MDeg(-md),
+            Angle::MRad(mr) => Angle::MRad(-mr),
+            Angle::DegMinSec(sign, d, m, s) => Angle::DegMinSec(-sign, d, m, s),
+            Angle::MinSec(sign, m, s) => Angle::MinSec(-sign, m, s),
+        }
+    }
+}
+
+impl Add for Angle {
+    type Output = Self;
+
+    fn add(self, rhs: Self) -> Self {
+        let sum = self.value().val() + rhs.value().val();
+        Self::from_rad(Rad64::new(sum), self.unit())
+    }
+}
+
+impl Sub for Angle {
+    type Output = Self;
+
+    fn sub(self, rhs: Self) -> Self {
+        self + -rhs
+    }
+}
+
+impl Mul<f64> for Angle {
+    type Output = Self;
+
+    fn mul(self, rhs: f64) -> Self {
+        Self::from_rad(Rad64::new(self.value().val() * rhs), self.unit())
+    }
+}
+
+impl Div<f64> for Angle {
+    type Output = Self;
+
+    fn div(self, rhs: f64) -> Self {
+        Self::from_rad(Rad64::new(self.value().val() / rhs), self.unit())
+    }
 }
 
 /// A helper method that should exist in `radians` crate.
-fn deg_from_dms(d: f64, m: f64, s: f64) -> Deg64 {
+pub fn deg_from_dms(d: f64, m: f64, s: f64) -> Deg64 {
     Deg64::new(d + m / 60. + s / 3600.)
 }
 
-#[derive(Clone, Copy, Debug)]
+/// Inverse of [`deg_from_dms`]: sign and the magnitudes of degrees, minutes and seconds.
+pub fn dms_from_deg(deg: Deg64) -> (Sign, f64, f64, f64) {
+    let deg = deg.val();
+    let sign = if deg < 0. {
+        Sign::Negative
+    } else {
+        Sign::Positive
+    };
+    let abs = deg.abs();
+    let d = abs.floor();
+    let m = ((abs - d) * 60.).floor();
+    let s = ((abs - d) * 60. - m) * 60.;
+    (sign, d, m, s)
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
 pub enum Sign {
     Positive,
     Negative,
@@ -50,3 +325,211 @@ impl Sign {
         }
     }
 }
+impl Neg for Sign {
+    type Output = Self;
+
+    fn neg(self) -> Self {
+        match self {
+            Self::Positive => Self::Negative,
+            Self::Negative => Self::Positive,
+        }
+    }
+}
+impl fmt::Display for Sign {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::Positive => "+",
+            Self::Negative => "-",
+        })
+    }
+}
+
+#[cfg(feature = "serde")]
+mod serde_impl {
+    use serde::{de, Deserialize, Deserializer, Serialize, Serializer};
+
+    use super::{Angle, Unit};
+
+    /// Serialized as the unit word, e.g. `"deg-min-sec"`.
+    impl Serialize for Unit {
+        fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+            serializer.collect_str(self)
+        }
+    }
+
+    impl<'de> Deserialize<'de> for Unit {
+        fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+            let s = String::deserialize(deserializer)?;
+            s.parse().map_err(de::Error::custom)
+        }
+    }
+
+    /// Serialized in the panel notation without loss of precision, e.g. `"1.2345 mrad"`.
+    impl Serialize for Angle {
+        fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+            serializer.collect_str(self)
+        }
+    }
+
+    impl<'de> Deserialize<'de> for Angle {
+        fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+            let s = String::deserialize(deserializer)?;
+            s.parse().map_err(de::Error::custom)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn assert_close(a: f64, b: f64) {
+        assert!((a - b).abs() < 1e-9, "{a} != {b}");
+    }
+
+    #[test]
+    fn display_and_parse_round_trip() {
+        let angles = [
+            (Angle::Min(1.5), "1.5'"),
+            (Angle::Deg(-0.25), "-0.25°"),
+            (Angle::MDeg(12.345), "12.345 mdeg"),
+            (Angle::MRad(1.2345), "1.2345 mrad"),
+            (
+                Angle::DegMinSec(Sign::Negative, 0., 1., 23.45),
+                "-0°01'23.45\"",
+            ),
+            (Angle::MinSec(Sign::Positive, 12., 3.5), "+12'03.5\""),
+        ];
+        for (angle, text) in angles {
+            assert_eq!(angle.to_string(), text);
+            assert_eq!(text.parse::<Angle>().unwrap(), angle);
+        }
+    }
+
+    #[test]
+    fn parses_unit_words() {
+        assert_eq!("1.5 mrad".parse::<Angle>().unwrap(), Angle::MRad(1.5));
+        assert_eq!("-0.2deg".parse::<Angle>().unwrap(), Angle::Deg(-0.2));
+        assert_eq!(" 3 min ".parse::<Angle>().unwrap(), Angle::Min(3.));
+        assert_eq!("10 mdeg".parse::<Angle>().unwrap(), Angle::MDeg(10.));
+        assert_eq!("1e-3 mrad".parse::<Angle>().unwrap(), Angle::MRad(1e-3));
+        assert_eq!("-2.5E2mdeg".parse::<Angle>().unwrap(), Angle::MDeg(-250.));
+        for unit in [
+            Unit::Min,
+            Unit::Deg,
+            Unit::MDeg,
+            Unit::MRad,
+            Unit::DegMinSec,
+            Unit::MinSec,
+        ] {
+            assert_eq!(unit.to_string().parse::<Unit>().unwrap(), unit);
+        }
+    }
+
+    #[test]
+    fn rejects_bad_input() {
+        for text in [
+            "",
+            "1.5",
+            "abc mrad",
+            "1.5 furlong",
+            "1 deg-min-sec",
+            "+0°xx'01\"",
+            "+0°01\"",
+            "NaN mrad",
+            "inf deg",
+            "-infinity'",
+            "+0°01'nan\"",
+        ] {
+            assert!(text.parse::<Angle>().is_err(), "{text:?} was accepted");
+        }
+        assert!("rad".parse::<Unit>().is_err());
+    }
+
+    #[test]
+    fn converts_between_units() {
+        let deg = Angle::Deg(1.5);
+        assert_close(deg.value().val(), 1.5f64.to_radians());
+        match deg.to_unit(Unit::Min) {
+            Angle::Min(m) => assert_close(m, 90.),
+            other => panic!("{other:?}"),
+        }
+        match deg.to_unit(Unit::MDeg) {
+            Angle::MDeg(md) => assert_close(md, 1500.),
+            other => panic!("{other:?}"),
+        }
+        match deg.to_unit(Unit::MRad) {
+            Angle::MRad(mr) => assert_close(mr, 1.5f64.to_radians() * 1e3),
+            other => panic!("{other:?}"),
+        }
+        match deg.to_unit(Unit::MinSec) {
+            Angle::MinSec(Sign::Positive, m, s) => {
+                assert_close(m, 90.);
+                assert_close(s, 0.);
+            }
+            other => panic!("{other:?}"),
+        }
+        match Angle::Deg(-1.5).to_unit(Unit::DegMinSec) {
+            Angle::DegMinSec(Sign::Negative, d, m, s) => {
+                assert_close(d, 1.);
+                assert_close(m, 30.);
+                assert_close(s, 0.);
+            }
+            other => panic!("{other:?}"),
+        }
+        assert_close(
+            Angle::MinSec(Sign::Negative, 1., 30.).value().val(),
+            -1.5f64.to_radians() / 60.,
+        );
+        assert_eq!(deg.to_unit(Unit::Deg), deg);
+    }
+
+    #[test]
+    fn arithmetic_keeps_the_left_unit() {
+        let sum = Angle::MRad(1.) + Angle::Deg(1.);
+        match sum {
+            Angle::MRad(mr) => assert_close(mr, 1. + 1f64.to_radians() * 1e3),
+            other => panic!("{other:?}"),
+        }
+        match Angle::Deg(1.) - Angle::Deg(3.) {
+            Angle::Deg(d) => assert_close(d, -2.),
+            other => panic!("{other:?}"),
+        }
+        match Angle::Min(3.) * 2. / 4. {
+            Angle::Min(m) => assert_close(m, 1.5),
+            other => panic!("{other:?}"),
+        }
+        assert_eq!(
+            -Angle::DegMinSec(Sign::Positive, 1., 2., 3.),
+            Angle::DegMinSec(Sign::Negative, 1., 2., 3.)
+        );
+    }
+
+    #[test]
+    fn dms_round_trip() {
+        for deg in [0., 0.5, 1.2345, -1.2345, 359.99, -0.0001] {
+            let (sign, d, m, s) = dms_from_deg(Deg64::new(deg));
+            assert_eq!(
+                sign,
+                if deg < 0. {
+                    Sign::Negative
+                } else {
+                    Sign::Positive
+                }
+            );
+            assert!(m < 60. && (0. ..60.).contains(&s));
+            let back = deg_from_dms(d, m, s).val() * sign.as_f64();
+            assert_close(back, deg);
+        }
+    }
+
+    #[test]
+    fn rounding_carries_into_minutes() {
+        let angle = Angle::DegMinSec(Sign::Positive, 0., 0., 59.999);
+        assert_eq!(format!("{angle:.2}"), "+0°01'00.00\"");
+        assert_eq!(angle.to_words(), "P,0,1,0.00");
+        let angle = Angle::MinSec(Sign::Negative, 12., 3.5);
+        assert_eq!(angle.to_words(), "M,12,3.50");
+        assert_eq!(Angle::MRad(1.23456789).to_words(), "1.2346");
+    }
+}
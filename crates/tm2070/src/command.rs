@@ -0,0 +1,248 @@
+//! Commands that configure the measurement head.
+//!
+//! Only `G`, `L` and `S` are known to work on a real head. The syntax of the configuration
+//! commands here (`U`, `A`, `Z`, `Z,C`, `O`, `J`, `F` and `Q`), their `OK` acknowledgement
+//! and the exchange of offsets and judge tolerances in mrad have not been checked against
+//! the TM-2070 manual; only [`crate::sim`] implements them. They are therefore only available
+//! with the `unverified-commands` feature. Check them against the manual of the head
+//! before enabling it.
+
+#[cfg(feature = "unverified-commands")]
+use std::ops::RangeInclusive;
+
+#[cfg(feature = "unverified-commands")]
+use anyhow::{bail, Context};
+#[cfg(feature = "unverified-commands")]
+use bstr::{BStr, ByteSlice};
+#[cfg(feature = "unverified-commands")]
+use radians::Rad64;
+
+#[cfg(feature = "unverified-commands")]
+use crate::{angle::Unit, parse_unit, unit_word};
+
+pub trait Command {
+    type Response;
+
+    fn encode(&self) -> Vec<u8>;
+    fn decode(&self, line: &[u8]) -> anyhow::Result<Self::Response>;
+}
+
+#[cfg(feature = "unverified-commands")]
+/// Selects the unit of the sampling data.
+#[derive(Clone, Copy, Debug)]
+pub struct SetUnit(pub Unit);
+#[cfg(feature = "unverified-commands")]
+impl Command for SetUnit {
+    type Response = ();
+
+    fn encode(&self) -> Vec<u8> {
+        format!("U,{}", unit_word(self.0)).into_bytes()
+    }
+
+    fn decode(&self, line: &[u8]) -> anyhow::Result<()> {
+        expect_ok(line)
+    }
+}
+
+#[cfg(feature = "unverified-commands")]
+/// Number of measurements averaged into one sample (1 to 999).
+#[derive(Clone, Copy, Debug)]
+pub struct SetAverageCount(pub u16);
+#[cfg(feature = "unverified-commands")]
+impl SetAverageCount {
+    pub const RANGE: RangeInclusive<u16> = 1..=999;
+}
+#[cfg(feature = "unverified-commands")]
+impl Command for SetAverageCount {
+    type Response = ();
+
+    fn encode(&self) -> Vec<u8> {
+        assert!(Self::RANGE.contains(&self.0));
+        format!("A,{}", self.0).into_bytes()
+    }
+
+    fn decode(&self, line: &[u8]) -> anyhow::Result<()> {
+        expect_ok(line)
+    }
+}
+
+#[cfg(feature = "unverified-commands")]
+/// Takes the current reading as the zero point.
+#[derive(Clone, Copy, Debug)]
+pub struct SetZero;
+#[cfg(feature = "unverified-commands")]
+impl Command for SetZero {
+    type Response = ();
+
+    fn encode(&self) -> Vec<u8> {
+        b"Z".to_vec()
+    }
+
+    fn decode(&self, line: &[u8]) -> anyhow::Result<()> {
+        expect_ok(line)
+    }
+}
+
+#[cfg(feature = "unverified-commands")]
+/// Clears the zero point and offset.
+#[derive(Clone, Copy, Debug)]
+pub struct ClearZero;
+#[cfg(feature = "unverified-commands")]
+impl Command for ClearZero {
+    type Response = ();
+
+    fn encode(&self) -> Vec<u8> {
+        b"Z,C".to_vec()
+    }
+
+    fn decode(&self, line: &[u8]) -> anyhow::Result<()> {
+        expect_ok(line)
+    }
+}
+
+#[cfg(feature = "unverified-commands")]
+/// Offset added to x and y.
+#[derive(Clone, Copy, Debug)]
+pub struct SetOffset {
+    pub x: Rad64,
+    pub y: Rad64,
+}
+#[cfg(feature = "unverified-commands")]
+impl Command for SetOffset {
+    type Response = ();
+
+    fn encode(&self) -> Vec<u8> {
+        format!("O,{},{}", mrad(self.x), mrad(self.y)).into_bytes()
+    }
+
+    fn decode(&self, line: &[u8]) -> anyhow::Result<()> {
+        expect_ok(line)
+    }
+}
+
+#[cfg(feature = "unverified-commands")]
+/// Judge reports `NG` when |x| or |y| exceeds the tolerance.
+#[derive(Clone, Copy, Debug)]
+pub struct SetJudgeTolerance {
+    pub x: Rad64,
+    pub y: Rad64,
+}
+#[cfg(feature = "unverified-commands")]
+impl Command for SetJudgeTolerance {
+    type Response = ();
+
+    fn encode(&self) -> Vec<u8> {
+        format!("J,{},{}", mrad(self.x), mrad(self.y)).into_bytes()
+    }
+
+    fn decode(&self, line: &[u8]) -> anyhow::Result<()> {
+        expect_ok(line)
+    }
+}
+
+/// Layout of the sampling data returned by `G` and `L`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum OutputFormat {
+    /// unit, x, y, norm, direction, judge
+    Format1,
+    /// unit, x, y, judge
+    Format2,
+}
+#[cfg(feature = "unverified-commands")]
+impl OutputFormat {
+    fn number(self) -> u8 {
+        match self {
+            Self::Format1 => 1,
+            Self::Format2 => 2,
+        }
+    }
+
+    fn from_number(number: &[u8]) -> anyhow::Result<Self> {
+        Ok(match number {
+            b"1" => Self::Format1,
+            b"2" => Self::Format2,
+            _ => bail!("Unknown output format: {:?}", BStr::new(number)),
+        })
+    }
+}
+
+#[cfg(feature = "unverified-commands")]
+#[derive(Clone, Copy, Debug)]
+pub struct SetOutputFormat(pub OutputFormat);
+#[cfg(feature = "unverified-commands")]
+impl Command for SetOutputFormat {
+    type Response = ();
+
+    fn encode(&self) -> Vec<u8> {
+        format!("F,{}", self.0.number()).into_bytes()
+    }
+
+    fn decode(&self, line: &[u8]) -> anyhow::Result<()> {
+        expect_ok(line)
+    }
+}
+
+#[cfg(feature = "unverified-commands")]
+/// Queries the current settings of the head.
+#[derive(Clone, Copy, Debug)]
+pub struct QuerySettings;
+
+#[cfg(feature = "unverified-commands")]
+#[derive(Clone, Copy, Debug)]
+pub struct Settings {
+    pub unit: Unit,
+    pub average_count: u16,
+    pub offset: [Rad64; 2],
+    pub judge_tolerance: [Rad64; 2],
+    pub output_format: OutputFormat,
+}
+
+#[cfg(feature = "unverified-commands")]
+impl Command for QuerySettings {
+    type Response = Settings;
+
+    fn encode(&self) -> Vec<u8> {
+        b"Q".to_vec()
+    }
+
+    /// `Q,<unit>,<average>,<offset x>,<offset y>,<tolerance x>,<tolerance y>,<format>`
+    fn decode(&self, line: &[u8]) -> anyhow::Result<Settings> {
+        (|| {
+            let mut words = line.split(|&c| c == b',').map(|s| s.trim());
+            let mut next = || words.next().context("Missing entry");
+            if next()? != b"Q" {
+                bail!("Invalid entry found");
+            }
+            let unit = parse_unit(next()?)?;
+            let average_count = next()?.to_str()?.parse()?;
+            let mut angle = || anyhow::Ok(Rad64::new(next()?.to_str()?.parse::<f64>()? / 1e3));
+            let offset = [angle()?, angle()?];
+            let judge_tolerance = [angle()?, angle()?];
+            let output_format = OutputFormat::from_number(next()?)?;
+            if words.next().is_some() {
+                bail!("Excessive entry found");
+            }
+            Ok(Settings {
+                unit,
+                average_count,
+                offset,
+                judge_tolerance,
+                output_format,
+            })
+        })()
+        .with_context(|| format!("Failed to parse as Settings: {:?}", BStr::new(line)))
+    }
+}
+
+#[cfg(feature = "unverified-commands")]
+fn expect_ok(line: &[u8]) -> anyhow::Result<()> {
+    if line != b"OK" {
+        bail!("Expected OK, found {:?}", BStr::new(line));
+    }
+    Ok(())
+}
+
+#[cfg(feature = "unverified-commands")]
+fn mrad(angle: Rad64) -> String {
+    format!("{:.6}", angle.val() * 1e3)
+}
@@ -0,0 +1,39 @@
+use bstr::BString;
+use thiserror::Error;
+
+#[derive(Debug, Error)]
+pub enum Tm2070Error {
+    #[error("Failed to open the serial port")]
+    Open(#[from] serialport::Error),
+    /// A setting outside the range accepted by the head, e.g. an average count of 0.
+    #[error("{what} out of range: {value}")]
+    OutOfRange { what: &'static str, value: i64 },
+    /// The head answered `ERR`.
+    #[error("Error response received")]
+    Device,
+    #[error("Failed to parse response: {line:?}")]
+    Parse {
+        line: BString,
+        #[source]
+        source: Box<dyn std::error::Error + Send + Sync>,
+    },
+    /// No sample had valid x and y, e.g. because the beam was lost.
+    #[error("No valid reading")]
+    Unavailable,
+    #[error("Timed out waiting for a response")]
+    Timeout,
+    /// The serial port thread has terminated, e.g. because the device was unplugged.
+    #[error("Connection to the serial port lost")]
+    LinkLost,
+}
+
+pub type Result<T, E = Tm2070Error> = std::result::Result<T, E>;
+
+impl Tm2070Error {
+    pub(crate) fn parse(line: &[u8], source: anyhow::Error) -> Self {
+        Self::Parse {
+            line: line.into(),
+            source: source.into(),
+        }
+    }
+}
@@ -0,0 +1,417 @@
+//! Simultaneous continuous sampling on several heads, aligned onto a common clock.
+//!
+//! Each head is read by its own thread, and samples are stamped on arrival at the host.
+//! Frames are emitted at a fixed period, by default the sampling period estimated from the first
+//! samples; each frame holds, for every head,
+//! the value at the frame time taken from the samples around it.
+
+use std::{
+    collections::VecDeque,
+    sync::{
+        atomic::{AtomicBool, Ordering::SeqCst},
+        mpsc::{self, RecvTimeoutError},
+        Arc,
+    },
+    thread::{self, JoinHandle},
+    time::{Duration, Instant, SystemTime},
+};
+
+use log::{error, warn};
+use radians::Rad64;
+
+use crate::{estimate_period, Judge, Result, Sample, Tm2070, Tm2070Error, PERIOD_ESTIMATE_SAMPLES};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Alignment {
+    /// The sample closest to the frame time.
+    Nearest,
+    /// Linear interpolation between the samples just before and after the frame time.
+    Linear,
+}
+
+/// The value of one head in a [`Frame`].
+#[derive(Clone, Debug)]
+pub struct HeadFrame {
+    /// [x, y]; `None` if there is no valid sample within the maximum skew of the frame time.
+    pub xy: Option<[Rad64; 2]>,
+    /// Judge of the sample closest to the frame time.
+    pub judge: Option<Judge>,
+    /// Distance from the frame time to the closest sample.
+    pub skew: Option<Duration>,
+}
+impl HeadFrame {
+    pub fn is_valid(&self) -> bool {
+        self.xy.is_some()
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct Frame {
+    /// Sequential number of the frame on the common clock.
+    pub index: u64,
+    pub time: Instant,
+    /// In the order the heads were given.
+    pub heads: Vec<HeadFrame>,
+}
+impl Frame {
+    /// Wall-clock time corresponding to [`Frame::time`].
+    pub fn system_time(&self) -> SystemTime {
+        SystemTime::now() - self.time.elapsed()
+    }
+
+    /// Difference of [x, y] between two heads, if both are valid.
+    pub fn difference(&self, a: usize, b: usize) -> Option<[Rad64; 2]> {
+        let [ax, ay] = self.heads[a].xy?;
+        let [bx, by] = self.heads[b].xy?;
+        Some([
+            Rad64::new(ax.val() - bx.val()),
+            Rad64::new(ay.val() - by.val()),
+        ])
+    }
+}
+
+pub struct Tm2070Group {
+    names: Vec<String>,
+    rx: mpsc::Receiver<(usize, Result<Sample>)>,
+    buffers: Vec<VecDeque<Sample>>,
+    stop: Arc<AtomicBool>,
+    threads: Vec<JoinHandle<()>>,
+    alignment: Alignment,
+    /// `None` until estimated.
+    period: Option<Duration>,
+    /// `None` for twice the period.
+    max_skew: Option<Duration>,
+    next: Option<(u64, Instant)>,
+}
+
+impl Tm2070Group {
+    /// Starts continuous sampling on every head, sampling every `interval`-th measurement
+    /// (see [`Tm2070::continuous_1`]).
+    /// Frames are emitted at the sampling period by default. It is estimated from the arrival times
+    /// of the fastest head, so the first frame waits until a head has sent
+    /// [`PERIOD_ESTIMATE_SAMPLES`] samples.
+    pub fn start(
+        heads: impl IntoIterator<Item = (String, Tm2070)>,
+        interval: impl Into<Option<usize>>,
+        alignment: Alignment,
+    ) -> Result<Self> {
+        let interval = interval.into();
+        let (tx, rx) = mpsc::channel();
+        let stop = Arc::new(AtomicBool::new(false));
+        // Every head is started before any thread, so that on failure the handles
+        // already started are dropped, which stops their sampling.
+        let (names, handles): (Vec<_>, Vec<_>) = (heads.into_iter())
+            .map(|(name, tm2070)| Ok((name, tm2070.into_continuous_1(interval)?)))
+            .collect::<Result<Vec<_>>>()?
+            .into_iter()
+            .unzip();
+        let mut threads = vec![];
+        for (index, (name, handle)) in names.iter().zip(handles).enumerate() {
+            let tx = tx.clone();
+            let stop = stop.clone();
+            let thread_name = name.clone();
+            threads.push(thread::spawn(move || {
+                while !stop.load(SeqCst) {
+                    let res = match handle.recv_timeout(Duration::from_millis(100)) {
+                        Ok(None) => continue,
+                        Ok(Some(sample)) => Ok(sample),
+                        Err(e) => Err(e),
+                    };
+                    let lost = matches!(res, Err(Tm2070Error::LinkLost));
+                    if tx.send((index, res)).is_err() || lost {
+                        break;
+                    }
+                }
+                if let Err(e) = handle.close() {
+                    error!("{thread_name}: Failed to close continuous fetch: {e:#}");
+                }
+            }));
+        }
+        Ok(Self {
+            buffers: names.iter().map(|_| VecDeque::new()).collect(),
+            names,
+            rx,
+            stop,
+            threads,
+            alignment,
+            period: None,
+            max_skew: None,
+            next: None,
+        })
+    }
+
+    pub fn names(&self) -> &[String] {
+        &self.names
+    }
+
+    /// Period of the common clock. Defaults to the sampling period estimated from the first samples.
+    pub fn set_period(&mut self, period: Duration) {
+        assert!(!period.is_zero());
+        self.period = Some(period);
+    }
+
+    /// Period of the common clock; `None` until estimated.
+    pub fn period(&self) -> Option<Duration> {
+        self.period
+    }
+
+    /// Samples farther than this from the frame time are not used.
+    /// Frames are also delayed by up to this long while waiting for late samples.
+    /// Defaults to twice the sampling period.
+    pub fn set_max_skew(&mut self, max_skew: Duration) {
+        self.max_skew = Some(max_skew);
+    }
+
+    /// Blocks until the next frame is complete, returning `None` if that takes longer than `timeout`.
+    /// Fails with [`Tm2070Error::LinkLost`] once every head has stopped and all samples have been used.
+    pub fn recv_timeout(&mut self, timeout: Duration) -> Result<Option<Frame>> {
+        let deadline = Instant::now() + timeout;
+        let mut disconnected = false;
+        loop {
+            while let Ok((index, res)) = self.rx.try_recv() {
+                self.push(index, res);
+            }
+            if self.period.is_none() {
+                self.period = self.estimate_period();
+            }
+            if self.next.is_none() && self.period.is_some() {
+                let first = (self.buffers.iter())
+                    .filter_map(|buffer| buffer.front())
+                    .map(|sample| sample.received)
+                    .min();
+                self.next = first.map(|first| (0, first));
+            }
+            if let Some((index, time)) = self.next {
+                if self.is_ready(time) {
+                    let frame = self.align(index, time);
+                    let period = self.period.unwrap();
+                    self.next = Some((index + 1, time + period));
+                    self.prune(time + period);
+                    return Ok(Some(frame));
+                }
+                let exhausted = !(self.buffers.iter())
+                    .any(|buffer| buffer.back().is_some_and(|s| s.received >= time));
+                if disconnected && exhausted {
+                    return Err(Tm2070Error::LinkLost);
+                }
+            } else if disconnected {
+                // Too few samples to estimate the period make no frames
+                return Err(Tm2070Error::LinkLost);
+            }
+
+            let now = Instant::now();
+            if now >= deadline {
+                return Ok(None);
+            }
+            let mut wait = deadline - now;
+            if let Some((_, time)) = self.next {
+                wait = wait.min((time + self.max_skew()).saturating_duration_since(now));
+            }
+            match self.rx.recv_timeout(wait) {
+                Ok((index, res)) => self.push(index, res),
+                Err(RecvTimeoutError::Timeout) => {}
+                Err(RecvTimeoutError::Disconnected) => {
+                    if disconnected {
+                        // Only frames from buffered samples remain; do not spin.
+                        thread::sleep(wait);
+                    }
+                    disconnected = true;
+                }
+            }
+        }
+    }
+
+    /// Blocks for each frame. The iterator ends after reporting that every head has stopped.
+    pub fn blocking_iter(&mut self) -> impl Iterator<Item = Result<Frame>> + '_ {
+        let mut lost = false;
+        std::iter::from_fn(move || loop {
+            if lost {
+                return None;
+            }
+            match self.recv_timeout(Duration::from_secs(1)) {
+                Ok(Some(frame)) => return Some(Ok(frame)),
+                Ok(None) => continue,
+                Err(e) => {
+                    lost = matches!(e, Tm2070Error::LinkLost);
+                    return Some(Err(e));
+                }
+            }
+        })
+    }
+
+    /// Stops sampling on every head and waits for the threads to finish.
+    pub fn close(mut self) {
+        self.stop.store(true, SeqCst);
+        for thread in self.threads.drain(..) {
+            if thread.join().is_err() {
+                error!("Sampling thread panicked");
+            }
+        }
+    }
+
+    /// Period of the fastest head among those that sent enough samples to estimate it.
+    fn estimate_period(&self) -> Option<Duration> {
+        (self.buffers.iter())
+            .filter(|buffer| buffer.len() >= PERIOD_ESTIMATE_SAMPLES)
+            .filter_map(|buffer| {
+                let arrivals = buffer.iter().map(|s| s.received).collect::<Vec<_>>();
+                estimate_period(&arrivals)
+            })
+            .min()
+    }
+
+    fn max_skew(&self) -> Duration {
+        (self.max_skew)
+            .or(self.period.map(|period| period * 2))
+            .unwrap_or_default()
+    }
+
+    /// A head failure is logged and the head is reported invalid until it recovers.
+    fn push(&mut self, index: usize, res: Result<Sample>) {
+        match res {
+            Ok(sample) => self.buffers[index].push_back(sample),
+            Err(e) => warn!("{}: {e:#}", self.names[index]),
+        }
+    }
+
+    /// Every head has a sample at or after `time`, or late samples are no longer waited for.
+    fn is_ready(&self, time: Instant) -> bool {
+        Instant::now() >= time + self.max_skew()
+            || (self.buffers.iter()).all(|buffer| buffer.back().is_some_and(|s| s.received >= time))
+    }
+
+    /// Drops samples that are too old for the frame at `next`.
+    fn prune(&mut self, next: Instant) {
+        let max_skew = self.max_skew();
+        for buffer in &mut self.buffers {
+            while buffer.front().is_some_and(|s| s.received + max_skew < next) {
+                buffer.pop_front();
+            }
+        }
+    }
+
+    fn align(&self, index: u64, time: Instant) -> Frame {
+        let heads = (self.buffers.iter())
+            .map(|buffer| self.align_head(buffer, time))
+            .collect();
+        Frame { index, time, heads }
+    }
+
+    fn align_head(&self, buffer: &VecDeque<Sample>, time: Instant) -> HeadFrame {
+        let skew = |s: &Sample| abs_diff(s.received, time);
+        let nearest = (buffer.iter())
+            .filter(|s| skew(s) <= self.max_skew())
+            .min_by_key(|s| skew(s));
+        let Some(nearest) = nearest else {
+            return HeadFrame {
+                xy: None,
+                judge: None,
+                skew: None,
+            };
+        };
+        let xy = match self.alignment {
+            Alignment::Nearest => xy(nearest),
+            Alignment::Linear => {
+                let before = buffer.iter().rev().find(|s| s.received <= time);
+                let after = buffer.iter().find(|s| s.received >= time);
+                match (before, after) {
+                    (Some(before), Some(after))
+                        if skew(before) <= self.max_skew() && skew(after) <= self.max_skew() =>
+                    {
+                        interpolate(before, after, time)
+                    }
+                    _ => None,
+                }
+            }
+        };
+        HeadFrame {
+            xy,
+            judge: Some(nearest.data.judge.clone()),
+            skew: Some(skew(nearest)),
+        }
+    }
+}
+
+impl Drop for Tm2070Group {
+    fn drop(&mut self) {
+        self.stop.store(true, SeqCst);
+    }
+}
+
+fn abs_diff(a: Instant, b: Instant) -> Duration {
+    a.saturating_duration_since(b)
+        .max(b.saturating_duration_since(a))
+}
+
+fn xy(sample: &Sample) -> Option<[Rad64; 2]> {
+    Some([sample.data.x?.value(), sample.data.y?.value()])
+}
+
+fn interpolate(before: &Sample, after: &Sample, time: Instant) -> Option<[Rad64; 2]> {
+    let [bx, by] = xy(before)?;
+    let [ax, ay] = xy(after)?;
+    let span = after.received.saturating_duration_since(before.received);
+    if span.is_zero() {
+        return Some([bx, by]);
+    }
+    let t = time
+        .saturating_duration_since(before.received)
+        .as_secs_f64()
+        / span.as_secs_f64();
+    let lerp = |b: Rad64, a: Rad64| Rad64::new(b.val() + (a.val() - b.val()) * t);
+    Some([lerp(bx, ax), lerp(by, ay)])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn heads(ports: &[&str]) -> Vec<(String, Tm2070)> {
+        (ports.iter())
+            .map(|&port| (port.to_owned(), Tm2070::new(port).unwrap()))
+            .collect()
+    }
+
+    #[test]
+    fn frames_hold_every_head_at_its_tilt() {
+        let ports = [
+            "sim:x=10,y=-20,noise=0,drift=0",
+            "sim:x=-5,y=3,noise=0,drift=0",
+        ];
+        let mut group = Tm2070Group::start(heads(&ports), None, Alignment::Linear).unwrap();
+        assert_eq!(group.names(), ports);
+        let frames = (group.blocking_iter())
+            .take(10)
+            .collect::<Result<Vec<_>>>()
+            .unwrap();
+        group.close();
+
+        let indices = frames.iter().map(|frame| frame.index).collect::<Vec<_>>();
+        assert_eq!(indices, (0..10).collect::<Vec<_>>());
+        let urad = |xy: [Rad64; 2]| xy.map(|v| (v.val() * 1e6).round());
+        // Frames at the edges may miss a head that started late
+        let complete = frames
+            .iter()
+            .filter(|frame| frame.heads.iter().all(HeadFrame::is_valid));
+        let mut count = 0;
+        for frame in complete {
+            assert_eq!(urad(frame.heads[0].xy.unwrap()), [10., -20.]);
+            assert_eq!(urad(frame.heads[1].xy.unwrap()), [-5., 3.]);
+            assert_eq!(urad(frame.difference(0, 1).unwrap()), [15., -23.]);
+            count += 1;
+        }
+        assert!(count >= 5, "only {count} complete frames");
+    }
+
+    #[test]
+    fn frames_follow_the_interval() {
+        let mut group = Tm2070Group::start(heads(&["sim"]), 3, Alignment::Nearest).unwrap();
+        let first = group.blocking_iter().next().unwrap().unwrap();
+        let second = group.blocking_iter().next().unwrap().unwrap();
+        assert_eq!(Some(second.time - first.time), group.period());
+        group.close();
+        let period = (second.time - first.time).as_secs_f64();
+        let expected = (crate::sim::BASE_PERIOD * 3).as_secs_f64();
+        assert!((period / expected - 1.).abs() < 0.05, "period {period} s");
+    }
+}
@@ -1,58 +1,212 @@
+pub mod alarm;
 pub mod angle;
+pub mod command;
+mod error;
+pub mod group;
+#[cfg(feature = "net")]
+pub mod net;
+#[cfg(feature = "serde")]
+pub mod record;
+#[cfg(feature = "recording")]
+pub mod recording;
+pub mod reference;
+pub mod sim;
+pub mod stats;
 
-use std::{borrow::Cow, sync::mpsc::TryRecvError, time::Duration};
+use std::{
+    borrow::Cow,
+    cell::RefCell,
+    collections::VecDeque,
+    mem,
+    ops::{Deref, DerefMut},
+    sync::mpsc::{RecvTimeoutError, TryRecvError},
+    time::{Duration, Instant, SystemTime},
+};
 
 use angle::Angle;
 use anyhow::{bail, Context};
 use bstr::{BStr, ByteSlice};
+use command::Command;
+#[cfg(feature = "unverified-commands")]
+use command::{
+    ClearZero, OutputFormat, QuerySettings, SetAverageCount, SetJudgeTolerance, SetOffset,
+    SetOutputFormat, SetUnit, SetZero, Settings,
+};
+pub use error::{Result, Tm2070Error};
 use log::{error, info, warn};
 use radians::Deg64;
-use serial_wrapper::SerialWrapper;
-use serialport::{DataBits, Parity, StopBits};
+#[cfg(feature = "unverified-commands")]
+use radians::Rad64;
+use serial_wrapper::{Line, SerialWrapper};
+use serialport::SerialPort;
+pub use serialport::{DataBits, FlowControl, Parity, StopBits};
 
 pub struct Tm2070 {
     serial_wrapper: SerialWrapper,
+    timeout: Duration,
 }
 
-impl Tm2070 {
-    pub fn new(port: &str) -> anyhow::Result<Self> {
-        let serial = serialport::new(port, 38400)
-            .data_bits(DataBits::Eight)
-            .parity(Parity::None)
-            .stop_bits(StopBits::One)
-            .flow_control(serialport::FlowControl::None)
-            .timeout(Duration::from_secs(1))
+/// Settings of the connection to a head. The defaults match the factory settings.
+#[derive(Clone, Debug)]
+pub struct Tm2070Builder {
+    port: String,
+    baud_rate: u32,
+    data_bits: DataBits,
+    parity: Parity,
+    stop_bits: StopBits,
+    flow_control: FlowControl,
+    timeout: Duration,
+}
+
+impl Tm2070Builder {
+    /// Defaults to 38400.
+    pub fn baud_rate(mut self, baud_rate: u32) -> Self {
+        self.baud_rate = baud_rate;
+        self
+    }
+
+    /// Defaults to 8.
+    pub fn data_bits(mut self, data_bits: DataBits) -> Self {
+        self.data_bits = data_bits;
+        self
+    }
+
+    /// Defaults to none.
+    pub fn parity(mut self, parity: Parity) -> Self {
+        self.parity = parity;
+        self
+    }
+
+    /// Defaults to 1.
+    pub fn stop_bits(mut self, stop_bits: StopBits) -> Self {
+        self.stop_bits = stop_bits;
+        self
+    }
+
+    /// Defaults to none.
+    pub fn flow_control(mut self, flow_control: FlowControl) -> Self {
+        self.flow_control = flow_control;
+        self
+    }
+
+    /// How long to wait for a response. Defaults to 1 s.
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    /// Opens the port, or a [`sim::Simulator`] if the port is `sim` or `sim:<settings>`
+    /// (see [`sim::SimConfig`] for the settings).
+    pub fn open(self) -> Result<Tm2070> {
+        if let Some(settings) = self.port.strip_prefix("sim") {
+            if settings.is_empty() || settings.starts_with(':') {
+                let config =
+                    (settings.trim_start_matches(':').parse()).map_err(|e: anyhow::Error| {
+                        Tm2070Error::Open(serialport::Error::new(
+                            serialport::ErrorKind::InvalidInput,
+                            format!("{e:#}"),
+                        ))
+                    })?;
+                let mut ret = Tm2070::simulated(config);
+                ret.timeout = self.timeout;
+                return Ok(ret);
+            }
+        }
+        let serial = serialport::new(&self.port, self.baud_rate)
+            .data_bits(self.data_bits)
+            .parity(self.parity)
+            .stop_bits(self.stop_bits)
+            .flow_control(self.flow_control)
+            .timeout(self.timeout)
             .open()?;
-        let serial_wrapper = SerialWrapper::new(serial);
-        let ret = Self { serial_wrapper };
+        let mut ret = Tm2070::from_serial(serial);
+        ret.timeout = self.timeout;
         Ok(ret)
     }
+}
+
+impl Tm2070 {
+    /// Opens `port` with the default settings. See [`Tm2070Builder::open`].
+    pub fn new(port: &str) -> Result<Self> {
+        Self::builder(port).open()
+    }
+
+    pub fn builder(port: impl Into<String>) -> Tm2070Builder {
+        Tm2070Builder {
+            port: port.into(),
+            baud_rate: 38400,
+            data_bits: DataBits::Eight,
+            parity: Parity::None,
+            stop_bits: StopBits::One,
+            flow_control: FlowControl::None,
+            timeout: Duration::from_secs(1),
+        }
+    }
+
+    /// Wraps a port that has already been opened and configured.
+    pub fn from_serial(serial: Box<dyn SerialPort>) -> Self {
+        Self {
+            serial_wrapper: SerialWrapper::new(serial),
+            timeout: Duration::from_secs(1),
+        }
+    }
+
+    pub fn simulated(config: sim::SimConfig) -> Self {
+        Self::from_serial(sim::Simulator::new(config).into_port())
+    }
 
-    fn writeln(&mut self, contents: impl Into<Cow<'static, [u8]>>) -> anyhow::Result<()> {
+    fn writeln(&mut self, contents: impl Into<Cow<'static, [u8]>>) -> Result<()> {
         let contents = contents.into();
         info!("Write: {:?}", BStr::new(&contents));
-        self.serial_wrapper.write_rx.send(contents)?;
-        self.serial_wrapper.write_rx.send(Cow::Borrowed(b"\r\n"))?;
-        Ok(())
+        let write_rx = &self.serial_wrapper.write_rx;
+        (write_rx.send(contents))
+            .and_then(|_| write_rx.send(Cow::Borrowed(b"\r\n")))
+            .map_err(|_| Tm2070Error::LinkLost)
     }
 
-    fn read(&mut self) -> anyhow::Result<Vec<u8>> {
+    fn read(&mut self, timeout: Duration) -> Result<Line> {
         // Blocking read
-        let read = self.serial_wrapper.read_tx.recv()?;
-        read_preprocess(&read)?;
+        let read = (self.serial_wrapper.read_tx.recv_timeout(timeout)).map_err(|e| match e {
+            RecvTimeoutError::Timeout => Tm2070Error::Timeout,
+            RecvTimeoutError::Disconnected => Tm2070Error::LinkLost,
+        })?;
+        read_preprocess(&read.data)?;
         Ok(read)
     }
+
+    /// Reads lines until one of them is accepted by `parse`.
+    /// Lines that fail to parse are skipped, so that a corrupt line does not
+    /// shift responses against their requests.
+    fn read_parsed<T>(&mut self, parse: impl Fn(&[u8]) -> anyhow::Result<T>) -> Result<T> {
+        let deadline = Instant::now() + self.timeout;
+        let mut last_error = None;
+        loop {
+            let line = match self.read(deadline.saturating_duration_since(Instant::now())) {
+                Err(Tm2070Error::Timeout) => return Err(last_error.unwrap_or(Tm2070Error::Timeout)),
+                res => res?,
+            };
+            match parse(&line.data) {
+                Ok(ret) => return Ok(ret),
+                Err(e) => {
+                    warn!("Skipping unexpected line: {e:#}");
+                    last_error = Some(Tm2070Error::parse(&line.data, e));
+                }
+            }
+        }
+    }
 }
 
-fn read_preprocess(read: &[u8]) -> anyhow::Result<()> {
+fn read_preprocess(read: &[u8]) -> Result<()> {
     info!("Read: {:?}", BStr::new(&read));
-    if read == b"ERR\r\n" {
-        bail!("Error response received");
+    // SerialWrapper strips CRLF
+    if read == b"ERR" {
+        return Err(Tm2070Error::Device);
     }
     Ok(())
 }
 
-#[derive(Debug)]
+#[derive(Clone, Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct SamplingData1 {
     pub unit: angle::Unit,
     pub x: Option<Angle>,
@@ -95,57 +249,432 @@ fn parse_sampling_data_1(line: &[u8]) -> anyhow::Result<SamplingData1> {
     .with_context(|| format!("Failed to parse as SamplingData1: {response:?}"))
 }
 
+/// Sampling data in [`OutputFormat::Format2`], whose layout has not been checked against
+/// the manual; see [`command`].
+#[cfg(feature = "unverified-commands")]
+#[derive(Clone, Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct SamplingData2 {
+    pub unit: angle::Unit,
+    pub x: Option<Angle>,
+    pub y: Option<Angle>,
+    pub judge: Judge,
+}
+#[cfg(feature = "unverified-commands")]
+fn parse_sampling_data_2(line: &[u8]) -> anyhow::Result<SamplingData2> {
+    let response = BStr::new(line);
+    let mut words = response.split(|&c| c == b',').map(|s| s.trim());
+    (|| {
+        if words.next() != Some(b"2") {
+            bail!("Invalid entry found");
+        }
+        let unit = parse_unit(words.next().context("Unit not found")?)?;
+        let x = parse_angle(unit, &mut words)?;
+        let y = parse_angle(unit, &mut words)?;
+        let judge = parse_judge(words.next().context("Judge not found")?);
+        if words.next().is_some() {
+            bail!("Excessive entry found");
+        }
+        Ok(SamplingData2 { unit, x, y, judge })
+    })()
+    .with_context(|| format!("Failed to parse as SamplingData2: {response:?}"))
+}
+
+impl Tm2070 {
+    /// Sends `command` and waits for its response.
+    pub fn execute<C: Command>(&mut self, command: &C) -> Result<C::Response> {
+        self.writeln(command.encode())?;
+        self.read_parsed(|line| command.decode(line))
+    }
+
+    #[cfg(feature = "unverified-commands")]
+    pub fn set_unit(&mut self, unit: angle::Unit) -> Result<()> {
+        self.execute(&SetUnit(unit))
+    }
+
+    #[cfg(feature = "unverified-commands")]
+    /// Fails with [`Tm2070Error::OutOfRange`] unless 1 <= count <= 999.
+    pub fn set_average_count(&mut self, count: u16) -> Result<()> {
+        if !SetAverageCount::RANGE.contains(&count) {
+            return Err(Tm2070Error::OutOfRange {
+                what: "Average count",
+                value: count.into(),
+            });
+        }
+        self.execute(&SetAverageCount(count))
+    }
+
+    #[cfg(feature = "unverified-commands")]
+    pub fn set_zero(&mut self) -> Result<()> {
+        self.execute(&SetZero)
+    }
+
+    #[cfg(feature = "unverified-commands")]
+    pub fn clear_zero(&mut self) -> Result<()> {
+        self.execute(&ClearZero)
+    }
+
+    #[cfg(feature = "unverified-commands")]
+    pub fn set_offset(&mut self, x: Rad64, y: Rad64) -> Result<()> {
+        self.execute(&SetOffset { x, y })
+    }
+
+    #[cfg(feature = "unverified-commands")]
+    pub fn set_judge_tolerance(&mut self, x: Rad64, y: Rad64) -> Result<()> {
+        self.execute(&SetJudgeTolerance { x, y })
+    }
+
+    #[cfg(feature = "unverified-commands")]
+    /// Selects the format of sampling data.
+    /// Use the `*_1` methods for [`OutputFormat::Format1`] and `*_2` for [`OutputFormat::Format2`].
+    pub fn set_output_format(&mut self, format: OutputFormat) -> Result<()> {
+        self.execute(&SetOutputFormat(format))
+    }
+
+    #[cfg(feature = "unverified-commands")]
+    pub fn settings(&mut self) -> Result<Settings> {
+        self.execute(&QuerySettings)
+    }
+}
+
 impl Tm2070 {
-    pub fn single_1(&mut self) -> anyhow::Result<SamplingData1> {
+    pub fn single_1(&mut self) -> Result<SamplingData1> {
+        self.writeln(b"G")?;
+        self.read_parsed(parse_sampling_data_1)
+    }
+
+    #[cfg(feature = "unverified-commands")]
+    pub fn single_2(&mut self) -> Result<SamplingData2> {
         self.writeln(b"G")?;
-        parse_sampling_data_1(&self.read()?)
+        self.read_parsed(parse_sampling_data_2)
     }
 
+    /// Samples every `interval`-th measurement (every measurement if `None`).
     /// Panics if interval == 0.
     pub fn continuous_1(
         &mut self,
         interval: impl Into<Option<usize>>,
-    ) -> anyhow::Result<Continuous1Handle> {
-        let interval = interval.into();
-        assert!(interval.map_or(true, |i| i > 0));
+    ) -> Result<Continuous1Handle<'_>> {
+        self.start_continuous(interval.into())?;
+        Ok(Continuous1Handle::new(Tm2070Ref::Borrowed(self)))
+    }
+
+    /// Same as [`Tm2070::continuous_1`], but the handle owns the connection
+    /// so that it can be moved to another thread.
+    pub fn into_continuous_1(
+        mut self,
+        interval: impl Into<Option<usize>>,
+    ) -> Result<Continuous1Handle<'static>> {
+        self.start_continuous(interval.into())?;
+        Ok(Continuous1Handle::new(Tm2070Ref::Owned(self)))
+    }
+
+    fn start_continuous(&mut self, interval: Option<usize>) -> Result<()> {
+        assert_ne!(interval, Some(0));
         match interval {
-            None => self.writeln(b"L")?,
-            Some(interval) => self.writeln(format!("L,{interval}").as_bytes().to_owned())?,
+            None => self.writeln(b"L"),
+            Some(interval) => self.writeln(format!("L,{interval}").as_bytes().to_owned()),
         }
-        Ok(Continuous1Handle(self))
     }
 }
 
-pub struct Continuous1Handle<'a>(&'a mut Tm2070);
+enum Tm2070Ref<'a> {
+    Borrowed(&'a mut Tm2070),
+    Owned(Tm2070),
+}
+impl Deref for Tm2070Ref<'_> {
+    type Target = Tm2070;
+
+    fn deref(&self) -> &Tm2070 {
+        match self {
+            Self::Borrowed(tm2070) => tm2070,
+            Self::Owned(tm2070) => tm2070,
+        }
+    }
+}
+impl DerefMut for Tm2070Ref<'_> {
+    fn deref_mut(&mut self) -> &mut Tm2070 {
+        match self {
+            Self::Borrowed(tm2070) => tm2070,
+            Self::Owned(tm2070) => tm2070,
+        }
+    }
+}
+
+/// Sampling data stamped on arrival at the host.
+#[derive(Debug)]
+pub struct Sample {
+    /// Line counter of the serial port.
+    /// Numbers are skipped where corrupt lines were dropped.
+    pub seq: u64,
+    /// Monotonic time at which the line was read from the port.
+    pub received: Instant,
+    pub data: SamplingData1,
+}
+impl Sample {
+    /// Wall-clock time corresponding to [`Sample::received`].
+    pub fn system_time(&self) -> SystemTime {
+        SystemTime::now() - self.received.elapsed()
+    }
+}
+
+/// Number of arrivals the sampling period is estimated from.
+pub(crate) const PERIOD_ESTIMATE_SAMPLES: usize = 128;
+/// Samples spanned by each interval the period is estimated from.
+/// Batching by USB adapters shifts the estimate by up to the batch size over this.
+const PERIOD_ESTIMATE_SPAN: usize = 32;
+
+/// Estimates the sampling period from arrival times.
+///
+/// The period is the median over the intervals spanning [`PERIOD_ESTIMATE_SPAN`] samples,
+/// so that a gap only lengthens a minority of them. `None` if there are too few arrivals
+/// or they are not spread out in time.
+pub(crate) fn estimate_period(arrivals: &[Instant]) -> Option<Duration> {
+    let mut periods = (arrivals
+        .iter()
+        .zip(arrivals.iter().skip(PERIOD_ESTIMATE_SPAN)))
+    .map(|(&start, &end)| (end - start) / PERIOD_ESTIMATE_SPAN as u32)
+    .collect::<Vec<_>>();
+    if periods.is_empty() {
+        return None;
+    }
+    periods.sort_unstable();
+    Some(periods[periods.len() / 2]).filter(|period| !period.is_zero())
+}
+
+/// A sample arriving this many periods later than the recent samples imply counts as a gap.
+/// USB adapters deliver lines in batches, which delays some by up to about a period.
+const GAP_PERIODS: f64 = 3.;
+/// Number of recent samples whose arrival times the expected arrival is taken from.
+const GAP_WINDOW: usize = 8;
+
+/// Estimates missed samples from arrival times.
+///
+/// Unless given, the period is estimated from the first [`PERIOD_ESTIMATE_SAMPLES`] arrivals,
+/// which are then checked against it. Each sample is expected one period after the previous one; batching delays samples,
+/// but the later ones in a batch catch up. The lateness of a sample is therefore measured
+/// from the earliest timeline of the recent samples, which also keeps an error in the period
+/// from accumulating.
+struct GapDetector {
+    /// `None` until estimated.
+    expected_period: Option<Duration>,
+    /// Arrivals held back while the period is estimated.
+    arrivals: Vec<Instant>,
+    first: Option<Instant>,
+    /// Samples since the first, including those estimated missed.
+    index: u64,
+    /// Arrival time minus the nominal time of each recent sample, in s.
+    offsets: VecDeque<f64>,
+    missed: u64,
+}
+impl GapDetector {
+    fn new(expected_period: Option<Duration>) -> Self {
+        Self {
+            expected_period,
+            arrivals: vec![],
+            first: None,
+            index: 0,
+            offsets: VecDeque::with_capacity(GAP_WINDOW + 1),
+            missed: 0,
+        }
+    }
+
+    /// Starts a new timeline, e.g. after the period changed.
+    fn restart(&mut self) {
+        self.first = None;
+        self.index = 0;
+        self.offsets.clear();
+    }
+
+    fn check(&mut self, received: Instant) {
+        let Some(period) = self.expected_period else {
+            self.arrivals.push(received);
+            if self.arrivals.len() >= PERIOD_ESTIMATE_SAMPLES {
+                let arrivals = mem::take(&mut self.arrivals);
+                // Arrivals all at once tell nothing about the period; start over
+                if let Some(period) = estimate_period(&arrivals) {
+                    info!("Estimated a sampling period of {period:?}");
+                    self.expected_period = Some(period);
+                    for received in arrivals {
+                        self.check(received);
+                    }
+                }
+            }
+            return;
+        };
+        let period = period.as_secs_f64();
+        let first = *self.first.get_or_insert(received);
+        let mut offset =
+            received.saturating_duration_since(first).as_secs_f64() - self.index as f64 * period;
+        let earliest = self.offsets.iter().copied().fold(offset, f64::min);
+        let late = (offset - earliest) / period;
+        if late >= GAP_PERIODS {
+            let missed = late.round() as u64;
+            self.missed += missed;
+            self.index += missed;
+            offset -= missed as f64 * period;
+            warn!(
+                "Sample {late:.1} periods late, about {missed} samples missed ({} in total)",
+                self.missed
+            );
+        }
+        self.offsets.push_back(offset);
+        if self.offsets.len() > GAP_WINDOW {
+            self.offsets.pop_front();
+        }
+        self.index += 1;
+    }
+}
+
+pub struct Continuous1Handle<'a> {
+    tm2070: Tm2070Ref<'a>,
+    gap: RefCell<GapDetector>,
+}
+impl<'a> Continuous1Handle<'a> {
+    fn new(tm2070: Tm2070Ref<'a>) -> Self {
+        Self {
+            tm2070,
+            gap: RefCell::new(GapDetector::new(None)),
+        }
+    }
+}
 impl Continuous1Handle<'_> {
-    /// Non-blocking
-    pub fn recv(&self) -> anyhow::Result<Option<SamplingData1>> {
-        match self.0.serial_wrapper.read_tx.try_recv() {
-            Ok(line) => {
-                read_preprocess(&line)?;
-                Ok(Some(parse_sampling_data_1(&line)?))
+    /// Overrides the period estimated from the first samples, which is used to detect missed samples.
+    pub fn set_expected_period(&mut self, period: Duration) {
+        let gap = self.gap.get_mut();
+        gap.expected_period = Some(period);
+        gap.arrivals.clear();
+        gap.restart();
+    }
+
+    /// Period used to detect missed samples; `None` until enough samples arrived to estimate it.
+    pub fn sampling_period(&self) -> Option<Duration> {
+        self.gap.borrow().expected_period
+    }
+
+    /// Estimated number of samples missed so far, judging from arrival times.
+    pub fn missed_samples(&self) -> u64 {
+        self.gap.borrow().missed
+    }
+
+    /// Non-blocking. Corrupt lines are logged and skipped.
+    pub fn recv(&self) -> Result<Option<Sample>> {
+        loop {
+            match self.tm2070.serial_wrapper.read_tx.try_recv() {
+                Ok(line) => {
+                    if let Some(sample) = self.accept(line)? {
+                        return Ok(Some(sample));
+                    }
+                }
+                Err(TryRecvError::Empty) => return Ok(None),
+                Err(TryRecvError::Disconnected) => return Err(Tm2070Error::LinkLost),
             }
-            Err(TryRecvError::Empty) => Ok(None),
-            Err(e) => Err(e.into()),
         }
     }
 
-    /// Non-blocking
-    pub fn iter(&self) -> impl Iterator<Item = anyhow::Result<SamplingData1>> + '_ {
-        self.0.serial_wrapper.read_tx.try_iter().map(|line| {
-            read_preprocess(&line)?;
-            parse_sampling_data_1(&line)
+    /// Blocks until a sample arrives, returning `None` if none arrives within `timeout`.
+    /// Corrupt lines are logged and skipped.
+    pub fn recv_timeout(&self, timeout: Duration) -> Result<Option<Sample>> {
+        let deadline = Instant::now() + timeout;
+        loop {
+            let timeout = deadline.saturating_duration_since(Instant::now());
+            match self.tm2070.serial_wrapper.read_tx.recv_timeout(timeout) {
+                Ok(line) => {
+                    if let Some(sample) = self.accept(line)? {
+                        return Ok(Some(sample));
+                    }
+                }
+                Err(RecvTimeoutError::Timeout) => return Ok(None),
+                Err(RecvTimeoutError::Disconnected) => return Err(Tm2070Error::LinkLost),
+            }
+        }
+    }
+
+    /// Non-blocking. Corrupt lines are logged and skipped.
+    pub fn iter(&self) -> impl Iterator<Item = Result<Sample>> + '_ {
+        (self.tm2070.serial_wrapper.read_tx.try_iter())
+            .filter_map(|line| self.accept(line).transpose())
+    }
+
+    /// Blocks for each sample. The iterator ends after reporting a lost connection.
+    /// Corrupt lines are logged and skipped.
+    pub fn blocking_iter(&self) -> impl Iterator<Item = Result<Sample>> + '_ {
+        let mut lost = false;
+        std::iter::from_fn(move || {
+            if lost {
+                return None;
+            }
+            loop {
+                let Ok(line) = self.tm2070.serial_wrapper.read_tx.recv() else {
+                    lost = true;
+                    return Some(Err(Tm2070Error::LinkLost));
+                };
+                if let Some(res) = self.accept(line).transpose() {
+                    return Some(res);
+                }
+            }
         })
     }
 
-    pub fn close(mut self) -> anyhow::Result<()> {
+    pub fn close(mut self) -> Result<()> {
         self.close_impl()
     }
 
-    fn close_impl(&mut self) -> anyhow::Result<()> {
-        self.0.writeln(b"S")
+    fn close_impl(&mut self) -> Result<()> {
+        self.tm2070.writeln(b"S")
+    }
+
+    /// Returns `None` for a corrupt line, which should not stop continuous sampling.
+    fn accept(&self, line: Line) -> Result<Option<Sample>> {
+        read_preprocess(&line.data)?;
+        let data = match parse_sampling_data_1(&line.data) {
+            Ok(data) => data,
+            Err(e) => {
+                warn!("Skipping corrupt line: {e:#}");
+                return Ok(None);
+            }
+        };
+        self.gap.borrow_mut().check(line.received);
+        Ok(Some(Sample {
+            seq: line.seq,
+            received: line.received,
+            data,
+        }))
     }
 }
+
+#[cfg(feature = "stream")]
+impl Continuous1Handle<'static> {
+    /// Forwards samples to an async stream from a dedicated thread.
+    /// Sampling stops when the stream is dropped.
+    pub fn into_stream(
+        self,
+    ) -> impl futures::Stream<Item = Result<Sample>> + Send + Unpin + 'static {
+        let (tx, rx) = futures::channel::mpsc::unbounded();
+        std::thread::spawn(move || loop {
+            match self.recv_timeout(Duration::from_millis(100)) {
+                Ok(Some(sample)) => {
+                    if tx.unbounded_send(Ok(sample)).is_err() {
+                        break;
+                    }
+                }
+                Ok(None) => {
+                    if tx.is_closed() {
+                        break;
+                    }
+                }
+                Err(e) => {
+                    let lost = matches!(e, Tm2070Error::LinkLost);
+                    if tx.unbounded_send(Err(e)).is_err() || lost {
+                        break;
+                    }
+                }
+            }
+        });
+        rx
+    }
+}
+
 impl Drop for Continuous1Handle<'_> {
     fn drop(&mut self) {
         if let Err(e) = self.close_impl() {
@@ -154,6 +683,18 @@ impl Drop for Continuous1Handle<'_> {
     }
 }
 
+fn unit_word(unit: angle::Unit) -> &'static str {
+    use angle::Unit::*;
+    match unit {
+        Min => "min",
+        Deg => "deg",
+        MDeg => "mdeg",
+        MRad => "mrad",
+        DegMinSec => "deg-min-sec",
+        MinSec => "min-sec",
+    }
+}
+
 fn parse_unit(unit: &[u8]) -> anyhow::Result<angle::Unit> {
     use angle::Unit::*;
     Ok(match unit {
@@ -220,6 +761,21 @@ fn parse_sign(word: &[u8]) -> anyhow::Result<Option<angle::Sign>> {
 #[derive(Clone, Copy, Debug)]
 pub struct TiltDirection(pub Deg64);
 
+/// Serialized as a number in degrees.
+#[cfg(feature = "serde")]
+impl serde::Serialize for TiltDirection {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_f64(self.0.val())
+    }
+}
+
+#[cfg(feature = "serde")]
+impl<'de> serde::Deserialize<'de> for TiltDirection {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        Ok(Self(Deg64::new(f64::deserialize(deserializer)?)))
+    }
+}
+
 #[derive(Clone, Debug)]
 pub enum Judge {
     Ok,
@@ -228,6 +784,33 @@ pub enum Judge {
     Er,
     Unknown(Vec<u8>),
 }
+impl Judge {
+    /// The word in sampling data, e.g. `OK`.
+    pub fn as_bytes(&self) -> &[u8] {
+        match self {
+            Judge::Ok => b"OK",
+            Judge::Ng => b"NG",
+            Judge::Nd => b"ND",
+            Judge::Er => b"ER",
+            Judge::Unknown(word) => word,
+        }
+    }
+}
+
+/// Serialized as the word in sampling data, e.g. `"OK"`.
+#[cfg(feature = "serde")]
+impl serde::Serialize for Judge {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(BStr::new(self.as_bytes()))
+    }
+}
+
+#[cfg(feature = "serde")]
+impl<'de> serde::Deserialize<'de> for Judge {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        Ok(parse_judge(String::deserialize(deserializer)?.as_bytes()))
+    }
+}
 fn parse_judge(word: &[u8]) -> Judge {
     use Judge::*;
     match word {
@@ -241,3 +824,85 @@ fn parse_judge(word: &[u8]) -> Judge {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Missed samples estimated for arrivals at `times`, in periods of 10 ms.
+    fn missed(times: impl IntoIterator<Item = f64>) -> u64 {
+        let period = Duration::from_millis(10);
+        let mut gap = GapDetector::new(Some(period));
+        let start = Instant::now();
+        for t in times {
+            gap.check(start + period.mul_f64(t));
+        }
+        gap.missed
+    }
+
+    #[test]
+    fn no_gap_in_evenly_spaced_samples() {
+        assert_eq!(missed((0..1000).map(f64::from)), 0);
+    }
+
+    #[test]
+    fn no_gap_in_batches() {
+        // Delivered three at a time, the first two held back by up to two periods
+        assert_eq!(missed((0..999).map(|i| f64::from(i / 3 * 3 + 2))), 0);
+    }
+
+    #[test]
+    fn counts_the_samples_of_a_gap() {
+        let times = (0..100).chain(105..200).chain(300..400).map(f64::from);
+        assert_eq!(missed(times), 105);
+    }
+
+    #[test]
+    fn tolerates_an_error_in_the_period() {
+        assert_eq!(missed((0..10_000).map(|i| f64::from(i) * 1.2)), 0);
+        assert_eq!(missed((0..10_000).map(|i| f64::from(i) * 0.8)), 0);
+    }
+
+    #[test]
+    fn estimates_the_period_of_batches_with_a_gap() {
+        let start = Instant::now();
+        let at = |t: u32| start + Duration::from_millis(25) * t;
+        // Delivered three at a time
+        let arrivals = (0..30)
+            .chain(40..150)
+            .map(|i| at(i / 3 * 3 + 2))
+            .collect::<Vec<_>>();
+        let period = estimate_period(&arrivals).unwrap().as_secs_f64();
+        assert!((period / 25e-3 - 1.).abs() < 0.07, "period {period} s");
+        assert_eq!(estimate_period(&arrivals[..PERIOD_ESTIMATE_SPAN]), None);
+        assert_eq!(estimate_period(&[start; 150]), None);
+    }
+
+    #[test]
+    fn counts_gaps_against_the_estimated_period() {
+        let start = Instant::now();
+        let mut gap = GapDetector::new(None);
+        for i in (0..20).chain(25..200).chain(300..400) {
+            gap.check(start + Duration::from_millis(25) * i);
+        }
+        assert_eq!(gap.expected_period, Some(Duration::from_millis(25)));
+        assert_eq!(gap.missed, 105);
+    }
+
+    #[cfg(feature = "unverified-commands")]
+    #[test]
+    fn average_count_is_checked_before_sending() {
+        let mut tm2070 = Tm2070::new("sim").unwrap();
+        for count in [0, 1000] {
+            let error = tm2070.set_average_count(count).unwrap_err();
+            assert!(
+                matches!(error, Tm2070Error::OutOfRange { value, .. } if value == i64::from(count))
+            );
+        }
+        tm2070.set_average_count(999).unwrap();
+        assert_eq!(tm2070.settings().unwrap().average_count, 999);
+        // The head still answers after the rejected values
+        tm2070.set_average_count(1).unwrap();
+        assert_eq!(tm2070.settings().unwrap().average_count, 1);
+    }
+}
@@ -0,0 +1,292 @@
+//! Sharing live samples over TCP.
+//!
+//! A [`Publisher`] sends every published [`Record`] to each connected client as one JSON object
+//! per line, in the schema of [`crate::record`]. Clients only read; anything that reads lines from
+//! a socket can be one, e.g. `nc localhost 7070`. Each client has a bounded queue, so a client
+//! that falls behind misses records instead of holding up the measurement.
+//! [`Subscriber`] is the matching client.
+//!
+//! [`Annotator`] goes the other way: it sends [`Marker`]s to a recording program that accepts
+//! them, e.g. `tm2070-continuous-measurement --marker-socket`.
+
+use std::{
+    io::{self, BufRead, BufReader, ErrorKind, Write},
+    net::{SocketAddr, TcpListener, TcpStream, ToSocketAddrs},
+    sync::{
+        atomic::{AtomicBool, Ordering::SeqCst},
+        mpsc::{self, TrySendError},
+        Arc, Mutex,
+    },
+    thread::{self, JoinHandle},
+    time::Duration,
+};
+
+use log::{error, info, warn};
+
+use crate::record::{Marker, Record};
+
+pub const DEFAULT_PORT: u16 = 7070;
+
+/// Lines queued per client before records are dropped for it.
+const QUEUE_LENGTH: usize = 1024;
+
+struct Client {
+    addr: SocketAddr,
+    tx: mpsc::SyncSender<Arc<[u8]>>,
+    dropped: u64,
+}
+
+pub struct Publisher {
+    local_addr: SocketAddr,
+    clients: Arc<Mutex<Vec<Client>>>,
+    stop: Arc<AtomicBool>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl Publisher {
+    /// Listens on `addr`, e.g. `127.0.0.1:7070` for this machine only or `0.0.0.0:7070` for the
+    /// local network. Port 0 picks a free port; see [`Publisher::local_addr`].
+    pub fn bind(addr: impl ToSocketAddrs) -> io::Result<Self> {
+        let listener = TcpListener::bind(addr)?;
+        listener.set_nonblocking(true)?;
+        let local_addr = listener.local_addr()?;
+        let clients = Arc::new(Mutex::new(Vec::new()));
+        let stop = Arc::new(AtomicBool::new(false));
+        let thread = {
+            let clients = clients.clone();
+            let stop = stop.clone();
+            thread::spawn(move || accept(listener, clients, stop))
+        };
+        info!("Publishing samples on {local_addr}");
+        Ok(Self {
+            local_addr,
+            clients,
+            stop,
+            thread: Some(thread),
+        })
+    }
+
+    pub fn local_addr(&self) -> SocketAddr {
+        self.local_addr
+    }
+
+    /// Number of connected clients.
+    pub fn clients(&self) -> usize {
+        self.clients.lock().unwrap().len()
+    }
+
+    /// Queues `record` for every client without blocking.
+    pub fn publish(&self, record: &Record) -> io::Result<()> {
+        let mut line = serde_json::to_vec(record)?;
+        line.push(b'\n');
+        let line: Arc<[u8]> = line.into();
+        self.clients
+            .lock()
+            .unwrap()
+            .retain_mut(|client| match client.tx.try_send(line.clone()) {
+                Ok(()) => true,
+                Err(TrySendError::Full(_)) => {
+                    if client.dropped == 0 {
+                        warn!("{}: Client is too slow; dropping records", client.addr);
+                    }
+                    client.dropped += 1;
+                    true
+                }
+                Err(TrySendError::Disconnected(_)) => false,
+            });
+        Ok(())
+    }
+}
+
+impl Drop for Publisher {
+    /// Stops accepting and disconnects every client.
+    fn drop(&mut self) {
+        self.stop.store(true, SeqCst);
+        if let Some(thread) = self.thread.take() {
+            if thread.join().is_err() {
+                error!("Publisher thread panicked");
+            }
+        }
+        self.clients.lock().unwrap().clear();
+    }
+}
+
+fn accept(listener: TcpListener, clients: Arc<Mutex<Vec<Client>>>, stop: Arc<AtomicBool>) {
+    while !stop.load(SeqCst) {
+        let (stream, addr) = match listener.accept() {
+            Ok(accepted) => accepted,
+            Err(e) if e.kind() == ErrorKind::WouldBlock => {
+                thread::sleep(Duration::from_millis(50));
+                continue;
+            }
+            Err(e) => {
+                error!("Failed to accept a client: {e}");
+                thread::sleep(Duration::from_millis(50));
+                continue;
+            }
+        };
+        if let Err(e) = stream
+            .set_nonblocking(false)
+            .and_then(|()| stream.set_nodelay(true))
+        {
+            error!("{addr}: {e}");
+            continue;
+        }
+        info!("{addr}: Client connected");
+        let (tx, rx) = mpsc::sync_channel::<Arc<[u8]>>(QUEUE_LENGTH);
+        thread::spawn(move || {
+            let mut stream = stream;
+            for line in rx {
+                if let Err(e) = stream.write_all(&line) {
+                    info!("{addr}: Client disconnected: {e}");
+                    return;
+                }
+            }
+        });
+        clients.lock().unwrap().push(Client {
+            addr,
+            tx,
+            dropped: 0,
+        });
+    }
+}
+
+/// Receives the records of a [`Publisher`].
+pub struct Subscriber {
+    reader: BufReader<TcpStream>,
+    line: Vec<u8>,
+}
+
+impl Subscriber {
+    pub fn connect(addr: impl ToSocketAddrs) -> io::Result<Self> {
+        let stream = TcpStream::connect(addr)?;
+        Ok(Self {
+            reader: BufReader::new(stream),
+            line: vec![],
+        })
+    }
+
+    /// Makes [`Subscriber::recv`] fail with [`ErrorKind::WouldBlock`] or [`ErrorKind::TimedOut`]
+    /// (depending on the platform) if no record arrives within `timeout`.
+    /// A record cut by the timeout is completed by the next call.
+    pub fn set_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        self.reader.get_ref().set_read_timeout(timeout)
+    }
+
+    /// Blocks for the next record. `None` once the publisher has closed the connection;
+    /// [`ErrorKind::UnexpectedEof`] if it was closed in the middle of a record.
+    pub fn recv(&mut self) -> io::Result<Option<Record<'static>>> {
+        loop {
+            // Keeps a partial line on a timeout
+            self.reader.read_until(b'\n', &mut self.line)?;
+            if self.line.last() != Some(&b'\n') {
+                if self.line.is_empty() {
+                    return Ok(None);
+                }
+                let len = std::mem::take(&mut self.line).len();
+                return Err(io::Error::new(
+                    ErrorKind::UnexpectedEof,
+                    format!("Connection closed after {len} bytes of a record"),
+                ));
+            }
+            let line = std::mem::take(&mut self.line);
+            if line.iter().all(u8::is_ascii_whitespace) {
+                continue;
+            }
+            let record: Record = serde_json::from_slice(&line)?;
+            record
+                .check_version()
+                .map_err(|e| io::Error::new(ErrorKind::InvalidData, e))?;
+            return Ok(Some(record));
+        }
+    }
+
+    /// Blocks for each record until the connection is closed.
+    pub fn iter(&mut self) -> impl Iterator<Item = io::Result<Record<'static>>> + '_ {
+        std::iter::from_fn(|| self.recv().transpose())
+    }
+}
+
+/// Sends markers, one JSON object per line.
+pub struct Annotator {
+    stream: TcpStream,
+    source: String,
+}
+
+impl Annotator {
+    /// `source` names the markers, e.g. `pamc112`.
+    pub fn connect(addr: impl ToSocketAddrs, source: impl Into<String>) -> io::Result<Self> {
+        let stream = TcpStream::connect(addr)?;
+        stream.set_nodelay(true)?;
+        Ok(Self {
+            stream,
+            source: source.into(),
+        })
+    }
+
+    /// Sends a marker stamped with the current time.
+    pub fn mark(&mut self, text: &str) -> io::Result<()> {
+        let mut line = serde_json::to_vec(&Marker::new(self.source.as_str(), text))?;
+        line.push(b'\n');
+        self.stream.write_all(&line)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SAMPLE: &str = r#"{"version":2,"time":"2024-06-01T12:34:56.789+09:00","seq":42,"source":"COM3","unit":"mrad","x":"0.0123 mrad","y":"-0.0045 mrad","norm":"0.0131 mrad","direction":339.9,"judge":"OK"}"#;
+
+    #[test]
+    fn publishes_to_a_subscriber() {
+        let publisher = Publisher::bind("127.0.0.1:0").unwrap();
+        let mut subscriber = Subscriber::connect(publisher.local_addr()).unwrap();
+        subscriber
+            .set_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        // The publisher accepts in the background
+        for _ in 0..100 {
+            if publisher.clients() == 1 {
+                break;
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+        assert_eq!(publisher.clients(), 1);
+
+        let record: Record = serde_json::from_str(SAMPLE).unwrap();
+        for seq in 0..3 {
+            publisher
+                .publish(&Record {
+                    seq,
+                    ..record.clone()
+                })
+                .unwrap();
+        }
+        for seq in 0..3 {
+            let received = subscriber.recv().unwrap().unwrap();
+            assert_eq!(received.seq, seq);
+            assert_eq!(received.source, "COM3");
+            assert_eq!(received.time, record.time);
+        }
+        drop(publisher);
+        assert!(subscriber.recv().unwrap().is_none());
+    }
+
+    #[test]
+    fn fails_on_a_truncated_record() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut subscriber = Subscriber::connect(listener.local_addr().unwrap()).unwrap();
+        subscriber
+            .set_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        let (mut stream, _) = listener.accept().unwrap();
+        write!(stream, "{SAMPLE}\n{}", &SAMPLE[..40]).unwrap();
+        drop(stream);
+
+        assert_eq!(subscriber.recv().unwrap().unwrap().seq, 42);
+        let e = subscriber.recv().unwrap_err();
+        assert_eq!(e.kind(), ErrorKind::UnexpectedEof);
+        assert!(subscriber.recv().unwrap().is_none());
+    }
+}
@@ -0,0 +1,131 @@
+//! The record schema shared by tools that save sampling data.
+//!
+//! Version 2 serializes one sample as follows (shown as JSON):
+//!
+//! ```json
+//! {
+//!   "version": 2,
+//!   "time": "2024-06-01T12:34:56.789012345+09:00",
+//!   "seq": 42,
+//!   "source": "COM3",
+//!   "unit": "mrad",
+//!   "x": "0.0123 mrad",
+//!   "y": "-0.0045 mrad",
+//!   "norm": "0.0131 mrad",
+//!   "direction": 339.9,
+//!   "judge": "OK"
+//! }
+//! ```
+//!
+//! * `time`: RFC 3339 time at which the line arrived at the host.
+//! * `seq`: line counter of the port, see [`Sample::seq`].
+//! * `source`: the port or other name identifying the head.
+//! * `x`, `y`, `norm`: in the notation of [`Angle`](crate::angle::Angle)'s `Display`,
+//!   or `null` when not available (e.g. `ND`).
+//! * `direction`: tilt direction in degrees.
+//! * `judge`: `OK`, `NG`, `ND`, `ER`, or whatever else the head sent.
+//!
+//! Version 2 adds [`Marker`]s, annotations such as a tap on the table or a move of a mount,
+//! written among the samples:
+//!
+//! ```json
+//! {
+//!   "version": 2,
+//!   "time": "2024-06-01T12:35:02.100000000+09:00",
+//!   "source": "pamc112",
+//!   "marker": "drive A cw 100 pulses at 1500 Hz"
+//! }
+//! ```
+//!
+//! `source` names whatever made the marker. Readers tell markers from samples by the `marker`
+//! field; see [`Entry`].
+//!
+//! Fields may be added without bumping the version; readers should ignore unknown fields.
+
+use std::borrow::Cow;
+
+use anyhow::ensure;
+use chrono::{DateTime, Local};
+use serde::{Deserialize, Serialize};
+
+use crate::{Sample, SamplingData1};
+
+pub const SCHEMA_VERSION: u32 = 2;
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Record<'a> {
+    /// [`SCHEMA_VERSION`] at the time of writing.
+    pub version: u32,
+    pub time: DateTime<Local>,
+    pub seq: u64,
+    pub source: Cow<'a, str>,
+    #[serde(flatten)]
+    pub data: SamplingData1,
+}
+
+impl<'a> Record<'a> {
+    pub fn new(source: impl Into<Cow<'a, str>>, sample: Sample) -> Self {
+        Self {
+            version: SCHEMA_VERSION,
+            time: sample.system_time().into(),
+            seq: sample.seq,
+            source: source.into(),
+            data: sample.data,
+        }
+    }
+
+    /// Fails if the record was written with a schema this version cannot read.
+    pub fn check_version(&self) -> anyhow::Result<()> {
+        check_version(self.version)
+    }
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Marker<'a> {
+    /// [`SCHEMA_VERSION`] at the time of writing.
+    pub version: u32,
+    pub time: DateTime<Local>,
+    pub source: Cow<'a, str>,
+    pub marker: Cow<'a, str>,
+}
+
+impl<'a> Marker<'a> {
+    /// A marker at the current time.
+    pub fn new(source: impl Into<Cow<'a, str>>, text: impl Into<Cow<'a, str>>) -> Self {
+        Self {
+            version: SCHEMA_VERSION,
+            time: Local::now(),
+            source: source.into(),
+            marker: text.into(),
+        }
+    }
+
+    pub fn check_version(&self) -> anyhow::Result<()> {
+        check_version(self.version)
+    }
+}
+
+/// Any line of a recording.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum Entry<'a> {
+    Marker(Marker<'a>),
+    Sample(Record<'a>),
+}
+
+impl Entry<'_> {
+    pub fn check_version(&self) -> anyhow::Result<()> {
+        match self {
+            Entry::Marker(marker) => marker.check_version(),
+            Entry::Sample(record) => record.check_version(),
+        }
+    }
+}
+
+pub(crate) fn check_version(version: u32) -> anyhow::Result<()> {
+    ensure!(
+        version <= SCHEMA_VERSION,
+        "Unsupported record version {version} (supported up to {SCHEMA_VERSION})",
+    );
+    Ok(())
+}
@@ -0,0 +1,640 @@
+//! Reading recordings in any format the tools write.
+//!
+//! Inputs may be files (`.json` arrays, including legacy ones, `.jsonl`, `.csv`, `.parquet`)
+//! or directories. A session directory is read through its `index.toml` in segment order;
+//! any other directory is read file by file in name order.
+//!
+//! A Parquet file is unreadable until its footer is written on close, so the recorder logs the
+//! metadata of each row group as it is written (`*.parquet.rowgroups`, see [`log_row_group`]).
+//! A segment that is still being recorded or whose recording crashed is read through that log.
+//! Earlier versions kept a JSON Lines journal of every record instead (`*.parquet.journal`),
+//! which is read in place of such a segment.
+
+use std::{
+    fmt,
+    io::{BufRead, BufReader, Write},
+    path::{Path, PathBuf},
+    str::FromStr,
+    sync::Arc,
+};
+
+use anyhow::{bail, Context};
+use arrow_array::{
+    Array, Float64Array, RecordBatch, StringArray, TimestampNanosecondArray, UInt64Array,
+};
+use chrono::{DateTime, Local};
+use fs_err::File;
+use log::{info, warn};
+use parquet::{
+    arrow::arrow_reader::{ArrowReaderMetadata, ParquetRecordBatchReaderBuilder},
+    file::metadata::{
+        FileMetaData, ParquetMetaData, ParquetMetaDataReader, ParquetMetaDataWriter,
+        RowGroupMetaData,
+    },
+};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    angle::Angle,
+    record::{self, Marker, Record},
+};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Format {
+    /// A JSON array
+    Json,
+    /// One JSON object per line
+    Jsonl,
+    /// Comma-separated values with angles in rad or in the notation of the record schema
+    Csv,
+    Parquet,
+}
+
+impl Format {
+    pub const NAMES: [&'static str; 4] = ["json", "jsonl", "csv", "parquet"];
+
+    pub fn from_path(path: &Path) -> anyhow::Result<Self> {
+        let extension = path.extension().and_then(|e| e.to_str()).unwrap_or("");
+        match extension.to_ascii_lowercase().as_str() {
+            "journal" => Ok(Format::Jsonl),
+            extension => extension
+                .parse()
+                .with_context(|| format!("Unknown format of {}", path.display())),
+        }
+    }
+}
+
+impl FromStr for Format {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        Ok(match s {
+            "json" => Format::Json,
+            "jsonl" => Format::Jsonl,
+            "csv" => Format::Csv,
+            "parquet" => Format::Parquet,
+            _ => bail!("Unknown format {s:?}; expected one of {:?}", Format::NAMES),
+        })
+    }
+}
+
+impl fmt::Display for Format {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(Format::NAMES[*self as usize])
+    }
+}
+
+/// One sample or marker. Angles are in rad and the direction in degrees.
+/// Fields other than time, source, x and y are missing when the input did not have them,
+/// e.g. in legacy files or after resampling.
+/// A marker only has time, source and marker.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Row {
+    pub time: DateTime<Local>,
+    pub source: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub seq: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub unit: Option<String>,
+    pub x: Option<f64>,
+    pub y: Option<f64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub norm: Option<f64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub direction: Option<f64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub judge: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub marker: Option<String>,
+}
+
+impl Row {
+    pub fn xy(&self) -> Option<[f64; 2]> {
+        Some([self.x?, self.y?])
+    }
+
+    pub fn is_marker(&self) -> bool {
+        self.marker.is_some()
+    }
+}
+
+impl From<Record<'_>> for Row {
+    fn from(record: Record) -> Self {
+        let rad = |a: Option<Angle>| a.map(|a| a.value().val());
+        let data = record.data;
+        Self {
+            time: record.time,
+            source: record.source.into_owned(),
+            seq: Some(record.seq),
+            unit: Some(data.unit.to_string()),
+            x: rad(data.x),
+            y: rad(data.y),
+            norm: rad(data.norm),
+            direction: Some(data.direction.0.val()),
+            judge: Some(String::from_utf8_lossy(data.judge.as_bytes()).into_owned()),
+            marker: None,
+        }
+    }
+}
+
+impl From<Marker<'_>> for Row {
+    fn from(marker: Marker) -> Self {
+        Self {
+            time: marker.time,
+            source: marker.source.into_owned(),
+            seq: None,
+            unit: None,
+            x: None,
+            y: None,
+            norm: None,
+            direction: None,
+            judge: None,
+            marker: Some(marker.marker.into_owned()),
+        }
+    }
+}
+
+/// Written by `tm2070-continuous-measurement` before the record schema, as one JSON array per run.
+#[derive(Deserialize)]
+struct Legacy {
+    time: DateTime<Local>,
+    com_port: String,
+    x: Option<f64>,
+    y: Option<f64>,
+}
+
+impl From<Legacy> for Row {
+    fn from(legacy: Legacy) -> Self {
+        Self {
+            time: legacy.time,
+            source: legacy.com_port,
+            seq: None,
+            unit: None,
+            x: legacy.x,
+            y: legacy.y,
+            norm: None,
+            direction: None,
+            judge: None,
+            marker: None,
+        }
+    }
+}
+
+/// Any JSON object found in the inputs.
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum AnyRecord {
+    Record(Record<'static>),
+    Marker(Marker<'static>),
+    Legacy(Legacy),
+    Row(Row),
+}
+
+impl TryFrom<AnyRecord> for Row {
+    type Error = anyhow::Error;
+
+    fn try_from(record: AnyRecord) -> anyhow::Result<Self> {
+        Ok(match record {
+            AnyRecord::Record(record) => {
+                record.check_version()?;
+                record.into()
+            }
+            AnyRecord::Marker(marker) => {
+                marker.check_version()?;
+                marker.into()
+            }
+            AnyRecord::Legacy(legacy) => legacy.into(),
+            AnyRecord::Row(row) => row,
+        })
+    }
+}
+
+/// Names of the sources of samples in order of first appearance.
+pub fn sources(rows: &[Row]) -> Vec<String> {
+    let mut sources: Vec<String> = vec![];
+    for row in rows.iter().filter(|row| !row.is_marker()) {
+        if !sources.contains(&row.source) {
+            sources.push(row.source.clone());
+        }
+    }
+    sources
+}
+
+/// Reads every input in order.
+pub fn read_all(inputs: &[PathBuf]) -> anyhow::Result<Vec<Row>> {
+    let mut rows = vec![];
+    for input in inputs {
+        for file in files(input)? {
+            let read = read(&file).with_context(|| format!("Failed to read {}", file.display()))?;
+            info!("Read {} rows from {}", read.len(), file.display());
+            rows.extend(read);
+        }
+    }
+    Ok(rows)
+}
+
+#[derive(Deserialize)]
+struct Index {
+    segments: Vec<Segment>,
+}
+#[derive(Deserialize)]
+struct Segment {
+    file: String,
+}
+
+/// The files of `input` in reading order. The journal of a Parquet file in the same directory
+/// is left out, as it is only read in place of that file.
+pub fn files(input: &Path) -> anyhow::Result<Vec<PathBuf>> {
+    if !input.is_dir() {
+        return Ok(vec![input.to_owned()]);
+    }
+    let index = input.join("index.toml");
+    if index.is_file() {
+        let index: Index = toml::from_str(&fs_err::read_to_string(&index)?)
+            .with_context(|| format!("Failed to read {}", index.display()))?;
+        return Ok((index.segments.into_iter())
+            .map(|segment| input.join(segment.file))
+            .collect());
+    }
+    let mut files = vec![];
+    for entry in fs_err::read_dir(input)? {
+        let path = entry?.path();
+        let is_journal =
+            path.extension().is_some_and(|e| e == "journal") && path.with_extension("").is_file();
+        if path.is_file() && Format::from_path(&path).is_ok() && !is_journal {
+            files.push(path);
+        }
+    }
+    files.sort();
+    Ok(files)
+}
+
+/// Reads one file.
+pub fn read(path: &Path) -> anyhow::Result<Vec<Row>> {
+    match Format::from_path(path)? {
+        Format::Json => {
+            let records: Vec<AnyRecord> =
+                serde_json::from_reader(BufReader::new(File::open(path)?))?;
+            records.into_iter().map(Row::try_from).collect()
+        }
+        Format::Jsonl => read_jsonl(path),
+        Format::Csv => read_csv(path),
+        Format::Parquet => {
+            let log = row_group_log_path(path);
+            let mut journal = path.as_os_str().to_owned();
+            journal.push(".journal");
+            let journal = PathBuf::from(journal);
+            match read_parquet(path, None) {
+                Err(e) if log.is_file() => {
+                    warn!("{}: {e:#}; reading its row group log", path.display());
+                    let row_groups = read_row_group_log(&log)?;
+                    match row_groups.first() {
+                        Some(first) => {
+                            let metadata = parquet_metadata(first.schema_descr_ptr(), row_groups);
+                            read_parquet(path, Some(metadata))
+                        }
+                        None => Ok(vec![]),
+                    }
+                }
+                Err(e) if journal.is_file() => {
+                    warn!("{}: {e:#}; reading its journal", path.display());
+                    read_jsonl(&journal)
+                }
+                res => res,
+            }
+        }
+    }
+}
+
+/// A partially written last line, as left by a crash or a recording in progress, is skipped.
+fn read_jsonl(path: &Path) -> anyhow::Result<Vec<Row>> {
+    let mut lines = BufReader::new(File::open(path)?).lines().peekable();
+    let mut rows = vec![];
+    while let Some(line) = lines.next() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        match serde_json::from_str::<AnyRecord>(&line) {
+            Ok(record) => rows.push(record.try_into()?),
+            Err(e) if lines.peek().is_none() => warn!("Skipping incomplete last line: {e}"),
+            Err(e) => return Err(e.into()),
+        }
+    }
+    Ok(rows)
+}
+
+/// Columns of CSV recordings. Readers also accept `com_port` for `source`
+/// and an optional `version`, and ignore unknown columns.
+pub const CSV_HEADER: [&str; 10] = [
+    "time",
+    "source",
+    "seq",
+    "unit",
+    "x",
+    "y",
+    "norm",
+    "direction",
+    "judge",
+    "marker",
+];
+
+fn read_csv(path: &Path) -> anyhow::Result<Vec<Row>> {
+    let mut reader = csv::Reader::from_path(path)?;
+    let headers = reader.headers()?.clone();
+    let column = |name| headers.iter().position(|h| h == name);
+    let [time, source, seq, unit, x, y, norm, direction, judge, marker] = CSV_HEADER.map(column);
+    let (Some(time), Some(source)) = (time, source.or(column("com_port"))) else {
+        bail!("Missing the time or source column");
+    };
+    let version = column("version");
+    let mut rows = vec![];
+    for record in reader.records() {
+        let record = record?;
+        let get = |i: Option<usize>| i.and_then(|i| record.get(i)).filter(|s| !s.is_empty());
+        if let Some(version) = get(version) {
+            record::check_version(version.parse()?)?;
+        }
+        let number = |i| get(i).map(str::parse::<f64>).transpose();
+        let angle = |i| {
+            get(i)
+                .map(|s| match s.parse::<f64>() {
+                    Ok(rad) => Ok(rad),
+                    Err(_) => s.parse::<Angle>().map(|a| a.value().val()),
+                })
+                .transpose()
+        };
+        rows.push(Row {
+            time: DateTime::parse_from_rfc3339(&record[time])?.into(),
+            source: record[source].to_owned(),
+            seq: get(seq).map(str::parse).transpose()?,
+            unit: get(unit).map(str::to_owned),
+            x: angle(x)?,
+            y: angle(y)?,
+            norm: angle(norm)?,
+            direction: number(direction)?,
+            judge: get(judge).map(str::to_owned),
+            marker: get(marker).map(str::to_owned),
+        });
+    }
+    Ok(rows)
+}
+
+/// Reads a Parquet file through `metadata` if given, or else through its footer.
+fn read_parquet(path: &Path, metadata: Option<ParquetMetaData>) -> anyhow::Result<Vec<Row>> {
+    let (file, _) = File::open(path)?.into_parts();
+    let builder = match metadata {
+        Some(metadata) => {
+            let metadata = ArrowReaderMetadata::try_new(Arc::new(metadata), Default::default())?;
+            ParquetRecordBatchReaderBuilder::new_with_metadata(file, metadata)
+        }
+        None => ParquetRecordBatchReaderBuilder::try_new(file)?,
+    };
+    let reader = builder.build()?;
+    let mut rows = vec![];
+    for batch in reader {
+        let batch = batch?;
+        fn column<'a, T: 'static>(
+            batch: &'a RecordBatch,
+            name: &str,
+        ) -> anyhow::Result<Option<&'a T>> {
+            let Some(column) = batch.column_by_name(name) else {
+                return Ok(None);
+            };
+            let column = (column.as_any().downcast_ref::<T>())
+                .with_context(|| format!("Unexpected type of column {name}"))?;
+            Ok(Some(column))
+        }
+        let time = column::<TimestampNanosecondArray>(&batch, "time")?.context("Missing time")?;
+        let source = column::<StringArray>(&batch, "source")?.context("Missing source")?;
+        let seq = column::<UInt64Array>(&batch, "seq")?;
+        let [unit, judge, marker] = [
+            column::<StringArray>(&batch, "unit")?,
+            column::<StringArray>(&batch, "judge")?,
+            column::<StringArray>(&batch, "marker")?,
+        ];
+        let [x, y, norm, direction] = [
+            column::<Float64Array>(&batch, "x")?,
+            column::<Float64Array>(&batch, "y")?,
+            column::<Float64Array>(&batch, "norm")?,
+            column::<Float64Array>(&batch, "direction")?,
+        ];
+        let float = |c: Option<&Float64Array>, i| c.filter(|c| c.is_valid(i)).map(|c| c.value(i));
+        let string =
+            |c: Option<&StringArray>, i| c.filter(|c| c.is_valid(i)).map(|c| c.value(i).to_owned());
+        for i in 0..batch.num_rows() {
+            rows.push(Row {
+                time: DateTime::from_timestamp_nanos(time.value(i)).into(),
+                source: source.value(i).to_owned(),
+                seq: seq.filter(|c| c.is_valid(i)).map(|c| c.value(i)),
+                unit: string(unit, i),
+                x: float(x, i),
+                y: float(y, i),
+                norm: float(norm, i),
+                direction: float(direction, i),
+                judge: string(judge, i),
+                marker: string(marker, i),
+            });
+        }
+    }
+    Ok(rows)
+}
+
+/// The row group log of the Parquet file at `path`.
+pub fn row_group_log_path(path: &Path) -> PathBuf {
+    let mut path = path.as_os_str().to_owned();
+    path.push(".rowgroups");
+    path.into()
+}
+
+/// Appends the metadata of a row group that was written to a Parquet file to its log.
+///
+/// Each entry is the length (`u32`, little endian) of a Thrift-encoded Parquet file metadata
+/// holding only this row group, followed by that metadata.
+pub fn log_row_group(log: &mut impl Write, row_group: &RowGroupMetaData) -> anyhow::Result<()> {
+    let metadata = parquet_metadata(row_group.schema_descr_ptr(), vec![row_group.clone()]);
+    let mut buf = vec![];
+    ParquetMetaDataWriter::new(&mut buf, &metadata).finish()?;
+    // Without the trailing length and magic number of a footer
+    buf.truncate(buf.len() - 8);
+    log.write_all(&(buf.len() as u32).to_le_bytes())?;
+    log.write_all(&buf)?;
+    Ok(())
+}
+
+/// Reads the row groups logged by [`log_row_group`].
+/// A partially written last entry, as left by a crash, is skipped.
+pub fn read_row_group_log(path: &Path) -> anyhow::Result<Vec<RowGroupMetaData>> {
+    let bytes = fs_err::read(path)?;
+    let mut rest = &bytes[..];
+    let mut row_groups = vec![];
+    while !rest.is_empty() {
+        let entry = (rest.get(..4))
+            .map(|len| u32::from_le_bytes(len.try_into().unwrap()) as usize)
+            .and_then(|len| rest.get(4..4 + len));
+        let Some(entry) = entry else {
+            warn!("{}: Skipping a partial entry", path.display());
+            break;
+        };
+        let metadata = ParquetMetaDataReader::decode_metadata(entry)
+            .with_context(|| format!("Corrupt row group log {}", path.display()))?;
+        row_groups.extend(metadata.row_groups().iter().cloned());
+        rest = &rest[4 + entry.len()..];
+    }
+    Ok(row_groups)
+}
+
+/// Minimal file metadata for `row_groups`, enough to read them.
+fn parquet_metadata(
+    schema: parquet::schema::types::SchemaDescPtr,
+    row_groups: Vec<RowGroupMetaData>,
+) -> ParquetMetaData {
+    let rows = row_groups.iter().map(RowGroupMetaData::num_rows).sum();
+    ParquetMetaData::new(
+        FileMetaData::new(1, rows, None, None, schema, None),
+        row_groups,
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A fresh directory under the system temporary directory.
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir =
+            std::env::temp_dir().join(format!("tm2070-recording-{}-{name}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    const SAMPLE: &str = r#"{"version":2,"time":"2024-06-01T12:34:56.789+09:00","seq":42,"source":"COM3","unit":"mrad","x":"0.0123 mrad","y":"-0.0045 mrad","norm":"0.0131 mrad","direction":339.9,"judge":"OK"}"#;
+    const MARKER: &str =
+        r#"{"version":2,"time":"2024-06-01T12:34:57+09:00","source":"pamc112","marker":"tap"}"#;
+    const LEGACY: &str =
+        r#"[{"time":"2024-06-01T12:00:00+09:00","com_port":"COM4","x":1e-6,"y":null}]"#;
+
+    #[test]
+    fn reads_jsonl_skipping_an_incomplete_last_line() {
+        let dir = temp_dir("jsonl");
+        let path = dir.join("a.jsonl");
+        std::fs::write(&path, format!("{SAMPLE}\n\n{MARKER}\n{{\"version\":2,\"ti")).unwrap();
+        let rows = read(&path).unwrap();
+        assert_eq!(rows.len(), 2);
+        assert_eq!(rows[0].seq, Some(42));
+        assert!((rows[0].x.unwrap() - 0.0123e-3).abs() < 1e-12);
+        assert_eq!(rows[1].marker.as_deref(), Some("tap"));
+        assert_eq!(sources(&rows), ["COM3"]);
+
+        let broken = dir.join("b.jsonl");
+        std::fs::write(&broken, format!("{{\"version\":2,\"ti\n{SAMPLE}\n")).unwrap();
+        assert!(read(&broken).is_err());
+    }
+
+    #[test]
+    fn reads_legacy_json_and_csv() {
+        let dir = temp_dir("legacy");
+        let json = dir.join("a.json");
+        std::fs::write(&json, LEGACY).unwrap();
+        let rows = read(&json).unwrap();
+        assert_eq!(rows[0].source, "COM4");
+        assert_eq!((rows[0].x, rows[0].y), (Some(1e-6), None));
+
+        let csv = dir.join("b.csv");
+        std::fs::write(
+            &csv,
+            "version,time,com_port,x,y\n2,2024-06-01T12:00:00+09:00,COM4,1e-6,0.002 mrad\n",
+        )
+        .unwrap();
+        let rows = read(&csv).unwrap();
+        assert_eq!(rows[0].xy(), Some([1e-6, 2e-6]));
+
+        std::fs::write(
+            &csv,
+            "version,time,source\n99,2024-06-01T12:00:00+09:00,COM4\n",
+        )
+        .unwrap();
+        assert!(read(&csv).is_err());
+    }
+
+    #[test]
+    fn reads_an_unfinished_parquet_segment_from_its_journal() {
+        let dir = temp_dir("session");
+        std::fs::write(
+            dir.join("index.toml"),
+            "[[segments]]\nfile = \"b.jsonl\"\n\n[[segments]]\nfile = \"a.parquet\"\n",
+        )
+        .unwrap();
+        std::fs::write(dir.join("b.jsonl"), format!("{MARKER}\n")).unwrap();
+        std::fs::write(dir.join("a.parquet"), b"PAR1").unwrap();
+        std::fs::write(dir.join("a.parquet.journal"), format!("{SAMPLE}\n")).unwrap();
+        let rows = read_all(std::slice::from_ref(&dir)).unwrap();
+        assert_eq!(rows.len(), 2);
+        assert!(rows[0].is_marker());
+        assert_eq!(rows[1].source, "COM3");
+
+        // Without the index, the journal is not read a second time on its own
+        std::fs::remove_file(dir.join("index.toml")).unwrap();
+        assert_eq!(
+            files(&dir).unwrap(),
+            [dir.join("a.parquet"), dir.join("b.jsonl")]
+        );
+        assert_eq!(read_all(&[dir]).unwrap().len(), 2);
+    }
+
+    #[test]
+    fn reads_an_unfinished_parquet_file_through_its_row_group_log() {
+        use arrow_array::ArrayRef;
+        use parquet::arrow::ArrowWriter;
+
+        let dir = temp_dir("row-groups");
+        let path = dir.join("a.parquet");
+        let batches = ["COM3", "COM4"].map(|source| {
+            let time = TimestampNanosecondArray::from(vec![0]).with_timezone("UTC");
+            RecordBatch::try_from_iter([
+                ("time", Arc::new(time) as ArrayRef),
+                ("source", Arc::new(StringArray::from(vec![source]))),
+                ("x", Arc::new(Float64Array::from(vec![Some(1e-6)]))),
+            ])
+            .unwrap()
+        });
+        let mut buf = vec![];
+        let mut writer = ArrowWriter::try_new(&mut buf, batches[0].schema(), None).unwrap();
+        let mut log = vec![];
+        let mut end = 0;
+        for batch in &batches {
+            writer.write(batch).unwrap();
+            writer.flush().unwrap();
+            let row_group = writer.flushed_row_groups().last().unwrap();
+            log_row_group(&mut log, row_group).unwrap();
+            let (start, len) = row_group.columns().last().unwrap().byte_range();
+            end = start + len;
+        }
+        writer.close().unwrap();
+        // Cut after the row groups, as on a crash
+        std::fs::write(&path, &buf[..end as usize]).unwrap();
+        // With a partial entry at the end
+        log.extend([1, 0]);
+        std::fs::write(row_group_log_path(&path), &log).unwrap();
+
+        assert!(read_parquet(&path, None).is_err());
+        let rows = read(&path).unwrap();
+        assert_eq!(sources(&rows), ["COM3", "COM4"]);
+        assert_eq!(rows[1].x, Some(1e-6));
+        // The log is not read on its own
+        assert_eq!(files(&dir).unwrap(), [path]);
+    }
+
+    #[test]
+    fn parses_format_names() {
+        for name in Format::NAMES {
+            assert_eq!(name.parse::<Format>().unwrap().to_string(), name);
+        }
+        assert_eq!(
+            Format::from_path(Path::new("a.parquet.JOURNAL")).unwrap(),
+            Format::Jsonl
+        );
+        assert!(Format::from_path(Path::new("a.txt")).is_err());
+    }
+}
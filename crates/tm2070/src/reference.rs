@@ -0,0 +1,133 @@
+//! Angles relative to a reference reading captured on the host.
+//!
+//! Unlike zeroing the head, the reference does not change the head settings,
+//! so several consumers can use different references on the same head.
+
+use radians::{Deg64, Rad64};
+
+use crate::{Result, SamplingData1, TiltDirection, Tm2070, Tm2070Error};
+
+/// The average of valid readings, subtracted from later readings.
+#[derive(Clone, Copy, Debug)]
+pub struct Reference {
+    pub x: Rad64,
+    pub y: Rad64,
+    /// Number of valid readings averaged.
+    pub count: usize,
+}
+
+/// A reading relative to a [`Reference`].
+#[derive(Clone, Copy, Debug)]
+pub struct Relative {
+    pub x: Rad64,
+    pub y: Rad64,
+    pub norm: Rad64,
+    /// Counterclockwise from +x.
+    pub direction: TiltDirection,
+}
+
+impl Reference {
+    /// Averages `count` single readings. Readings without x or y are skipped, up to `count`
+    /// of them; skipping one more fails with [`Tm2070Error::Unavailable`].
+    /// Panics if count == 0.
+    pub fn capture(tm2070: &mut Tm2070, count: usize) -> Result<Self> {
+        assert!(count > 0);
+        let mut valid = vec![];
+        let mut invalid = 0;
+        while valid.len() < count {
+            let data = tm2070.single_1()?;
+            if xy(&data).is_some() {
+                valid.push(data);
+            } else {
+                invalid += 1;
+                if invalid > count {
+                    return Err(Tm2070Error::Unavailable);
+                }
+            }
+        }
+        Self::from_samples(&valid).ok_or(Tm2070Error::Unavailable)
+    }
+
+    /// Averages the valid readings in `samples`, or returns `None` if there are none.
+    pub fn from_samples<'a>(samples: impl IntoIterator<Item = &'a SamplingData1>) -> Option<Self> {
+        let mut sum = [0.; 2];
+        let mut count = 0;
+        for [x, y] in samples.into_iter().filter_map(xy) {
+            sum[0] += x;
+            sum[1] += y;
+            count += 1;
+        }
+        (count > 0).then(|| Self {
+            x: Rad64::new(sum[0] / count as f64),
+            y: Rad64::new(sum[1] / count as f64),
+            count,
+        })
+    }
+
+    /// Returns `None` if `data` has no x or y.
+    pub fn relative(&self, data: &SamplingData1) -> Option<Relative> {
+        let [x, y] = xy(data)?;
+        let x = x - self.x.val();
+        let y = y - self.y.val();
+        Some(Relative {
+            x: Rad64::new(x),
+            y: Rad64::new(y),
+            norm: Rad64::new(x.hypot(y)),
+            direction: TiltDirection(Deg64::new(y.atan2(x).to_degrees().rem_euclid(360.))),
+        })
+    }
+}
+
+impl Tm2070 {
+    /// See [`Reference::capture`].
+    pub fn capture_reference(&mut self, count: usize) -> Result<Reference> {
+        Reference::capture(self, count)
+    }
+}
+
+fn xy(data: &SamplingData1) -> Option<[f64; 2]> {
+    Some([data.x?.value().val(), data.y?.value().val()])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn capture_averages_valid_readings() {
+        let mut tm2070 = Tm2070::new("sim:x=30,y=-40,noise=0,drift=0").unwrap();
+        let reference = Reference::capture(&mut tm2070, 3).unwrap();
+        assert_eq!(reference.count, 3);
+        assert!((reference.x.val() - 30e-6).abs() < 1e-9);
+        assert!((reference.y.val() + 40e-6).abs() < 1e-9);
+
+        let relative = reference.relative(&tm2070.single_1().unwrap()).unwrap();
+        assert!(relative.norm.val() < 1e-9);
+    }
+
+    #[test]
+    fn capture_fails_without_a_beam() {
+        // The tilt is out of range, so every reading is ND
+        let mut tm2070 = Tm2070::new("sim:x=100,range=50").unwrap();
+        assert!(matches!(
+            Reference::capture(&mut tm2070, 2),
+            Err(Tm2070Error::Unavailable)
+        ));
+        // The head still answers after the skipped readings
+        assert!(tm2070.single_1().unwrap().x.is_none());
+    }
+
+    #[test]
+    fn relative_to_a_reference() {
+        let reference = Reference {
+            x: Rad64::new(1e-3),
+            y: Rad64::new(2e-3),
+            count: 1,
+        };
+        let mut tm2070 = Tm2070::new("sim:x=1000,y=3000,noise=0,drift=0").unwrap();
+        let relative = reference.relative(&tm2070.single_1().unwrap()).unwrap();
+        assert!(relative.x.val().abs() < 1e-9);
+        assert!((relative.y.val() - 1e-3).abs() < 1e-9);
+        assert!((relative.direction.0.val() - 90.).abs() < 1e-3);
+    }
+}
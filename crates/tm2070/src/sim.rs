@@ -0,0 +1,536 @@
+//! A virtual TM2070 for testing tools without the autocollimator.
+//!
+//! The simulator speaks the serial protocol: it answers `G`, streams for `L`/`S`
+//! and accepts the configuration commands in [`crate::command`] in the syntax assumed there.
+//! Readings are the sum of a base tilt (or an external [`TiltSource`]),
+//! a random-walk drift, vibration tones and white noise.
+//! `ND` is reported during simulated dropouts and when the tilt is out of range.
+
+use std::{
+    f64::consts::PI,
+    fmt::Write as _,
+    str::FromStr,
+    time::{Duration, Instant},
+};
+
+use anyhow::{bail, Context};
+use bstr::ByteSlice;
+use radians::Rad64;
+use serial_wrapper::virtual_port::{VirtualDevice, VirtualPort};
+use serialport::SerialPort;
+
+use crate::{
+    angle::{Angle, Unit},
+    command::OutputFormat,
+};
+
+/// Period of the simulated output without decimation.
+///
+/// The rate of a real head is not taken from here; it is estimated from the arrival times.
+pub(crate) const BASE_PERIOD: Duration = Duration::from_micros(16_667);
+
+/// Returns the true tilt [x, y] in µrad, e.g. derived from the position of a simulated mirror mount.
+pub type TiltSource = Box<dyn FnMut() -> [f64; 2] + Send>;
+
+#[derive(Clone, Debug)]
+pub struct SimConfig {
+    /// Unit selected at power-on.
+    pub unit: Unit,
+    pub seed: u64,
+    /// Tilt [x, y] in µrad when no [`TiltSource`] is given.
+    pub tilt: [f64; 2],
+    /// Standard deviation of white noise on each axis in µrad, before averaging.
+    pub noise: f64,
+    /// Random-walk drift in µrad/√s.
+    pub drift: f64,
+    pub tones: Vec<Tone>,
+    /// Probability that a dropout starts at a given sample.
+    pub dropout_rate: f64,
+    /// Mean duration of a dropout.
+    pub dropout_duration: Duration,
+    /// Probability that a sample is judged `ER`.
+    pub error_rate: f64,
+    /// The beam misses the sensor beyond this tilt on either axis (µrad).
+    pub range: f64,
+}
+
+impl Default for SimConfig {
+    fn default() -> Self {
+        Self {
+            unit: Unit::MRad,
+            seed: 0,
+            tilt: [0., 0.],
+            noise: 0.5,
+            drift: 0.05,
+            tones: vec![],
+            dropout_rate: 0.,
+            dropout_duration: Duration::from_millis(200),
+            error_rate: 0.,
+            range: 5000.,
+        }
+    }
+}
+
+/// A sinusoidal vibration.
+#[derive(Clone, Copy, Debug)]
+pub struct Tone {
+    /// Hz
+    pub frequency: f64,
+    /// Amplitude on [x, y] in µrad.
+    pub amplitude: [f64; 2],
+}
+
+/// Parses comma-separated `key=value` pairs, e.g. `noise=0.2,drift=0,tone=50:1:0.5`.
+///
+/// Keys: `unit`, `seed`, `x`, `y`, `noise`, `drift`, `tone` (`Hz:x:y`, repeatable),
+/// `dropout` (rate), `dropout-ms`, `error` (rate) and `range`.
+/// Angles are in µrad.
+impl FromStr for SimConfig {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut config = Self::default();
+        for pair in s.split(',').map(str::trim).filter(|s| !s.is_empty()) {
+            (|| {
+                let (key, value) = pair.split_once('=').context("Expected key=value")?;
+                match key {
+                    "unit" => config.unit = value.parse()?,
+                    "seed" => config.seed = value.parse()?,
+                    "x" => config.tilt[0] = value.parse()?,
+                    "y" => config.tilt[1] = value.parse()?,
+                    "noise" => config.noise = value.parse()?,
+                    "drift" => config.drift = value.parse()?,
+                    "tone" => {
+                        let mut words = value.split(':');
+                        let mut next =
+                            || anyhow::Ok(words.next().context("Expected Hz:x:y")?.parse());
+                        config.tones.push(Tone {
+                            frequency: next()??,
+                            amplitude: [next()??, next()??],
+                        });
+                    }
+                    "dropout" => config.dropout_rate = value.parse()?,
+                    "dropout-ms" => {
+                        config.dropout_duration =
+                            Duration::try_from_secs_f64(value.parse::<f64>()? / 1e3)?
+                    }
+                    "error" => config.error_rate = value.parse()?,
+                    "range" => config.range = value.parse()?,
+                    _ => bail!("Unknown key"),
+                }
+                anyhow::Ok(())
+            })()
+            .with_context(|| format!("Invalid simulator setting: {pair:?}"))?;
+        }
+        Ok(config)
+    }
+}
+
+pub struct Simulator {
+    config: SimConfig,
+    tilt_source: Option<TiltSource>,
+    rng: Rng,
+    start: Instant,
+    input: Vec<u8>,
+    output: Vec<u8>,
+
+    unit: Unit,
+    average_count: u16,
+    /// Displayed = true - zero + offset, in rad.
+    zero: [f64; 2],
+    offset: [f64; 2],
+    judge_tolerance: [f64; 2],
+    output_format: OutputFormat,
+    /// Period and time of the next sample while streaming.
+    streaming: Option<(Duration, Instant)>,
+
+    drift: [f64; 2],
+    drift_updated: Instant,
+    dropout_until: Option<Instant>,
+}
+
+impl Simulator {
+    pub fn new(config: SimConfig) -> Self {
+        let now = Instant::now();
+        Self {
+            unit: config.unit,
+            rng: Rng::new(config.seed),
+            config,
+            tilt_source: None,
+            start: now,
+            input: vec![],
+            output: vec![],
+            average_count: 1,
+            zero: [0., 0.],
+            offset: [0., 0.],
+            judge_tolerance: [1e-3, 1e-3],
+            output_format: OutputFormat::Format1,
+            streaming: None,
+            drift: [0., 0.],
+            drift_updated: now,
+            dropout_until: None,
+        }
+    }
+
+    /// Takes the tilt from `source` instead of [`SimConfig::tilt`].
+    pub fn with_tilt_source(mut self, source: TiltSource) -> Self {
+        self.tilt_source = Some(source);
+        self
+    }
+
+    pub fn into_port(self) -> Box<dyn SerialPort> {
+        VirtualPort::new("sim", self).boxed()
+    }
+
+    fn execute(&mut self, line: &[u8]) {
+        let words = line
+            .split(|&c| c == b',')
+            .map(|s| s.trim())
+            .collect::<Vec<_>>();
+        let response = match self.try_execute(&words) {
+            Ok(Some(response)) => response,
+            Ok(None) => return,
+            Err(_) => "ERR".to_owned(),
+        };
+        self.output.extend(response.as_bytes());
+        self.output.extend(b"\r\n");
+    }
+
+    fn try_execute(&mut self, words: &[&[u8]]) -> anyhow::Result<Option<String>> {
+        let number = |word: &[u8]| anyhow::Ok(word.to_str()?.parse::<f64>()?);
+        let mrad_pair =
+            |words: &[&[u8]]| anyhow::Ok([number(words[1])? / 1e3, number(words[2])? / 1e3]);
+        Ok(Some(match words {
+            [b"G"] => self.sample(Instant::now()),
+            [b"L"] | [b"L", _] => {
+                let interval = match words.get(1) {
+                    Some(n) => n.to_str()?.parse::<u32>()?,
+                    None => 1,
+                };
+                if interval == 0 {
+                    bail!("Invalid interval");
+                }
+                let period = BASE_PERIOD * interval;
+                self.streaming = Some((period, Instant::now() + period));
+                return Ok(None);
+            }
+            [b"S"] => {
+                self.streaming = None;
+                return Ok(None);
+            }
+            [b"U", unit] => {
+                self.unit = crate::parse_unit(unit)?;
+                "OK".to_owned()
+            }
+            [b"A", count] => {
+                let count = count.to_str()?.parse()?;
+                if !(1..=999).contains(&count) {
+                    bail!("Average count out of range");
+                }
+                self.average_count = count;
+                "OK".to_owned()
+            }
+            [b"Z"] => {
+                self.zero = self.true_tilt(Instant::now());
+                "OK".to_owned()
+            }
+            [b"Z", b"C"] => {
+                self.zero = [0., 0.];
+                self.offset = [0., 0.];
+                "OK".to_owned()
+            }
+            [b"O", _, _] => {
+                self.offset = mrad_pair(words)?;
+                "OK".to_owned()
+            }
+            [b"J", _, _] => {
+                self.judge_tolerance = mrad_pair(words)?;
+                "OK".to_owned()
+            }
+            [b"F", b"1"] => {
+                self.output_format = OutputFormat::Format1;
+                "OK".to_owned()
+            }
+            [b"F", b"2"] => {
+                self.output_format = OutputFormat::Format2;
+                "OK".to_owned()
+            }
+            [b"Q"] => format!(
+                "Q,{},{},{:.6},{:.6},{:.6},{:.6},{}",
+                self.unit,
+                self.average_count,
+                self.offset[0] * 1e3,
+                self.offset[1] * 1e3,
+                self.judge_tolerance[0] * 1e3,
+                self.judge_tolerance[1] * 1e3,
+                match self.output_format {
+                    OutputFormat::Format1 => 1,
+                    OutputFormat::Format2 => 2,
+                }
+            ),
+            _ => bail!("Unknown command"),
+        }))
+    }
+
+    /// Tilt in rad without noise.
+    fn true_tilt(&mut self, at: Instant) -> [f64; 2] {
+        let dt = at
+            .saturating_duration_since(self.drift_updated)
+            .as_secs_f64();
+        if dt > 0. {
+            for d in &mut self.drift {
+                *d += self.config.drift * dt.sqrt() * self.rng.normal();
+            }
+            self.drift_updated = at;
+        }
+        let t = at.saturating_duration_since(self.start).as_secs_f64();
+        let base = match &mut self.tilt_source {
+            Some(source) => source(),
+            None => self.config.tilt,
+        };
+        let mut ret = [0.; 2];
+        for (i, r) in ret.iter_mut().enumerate() {
+            let tones = (self.config.tones.iter())
+                .map(|tone| tone.amplitude[i] * (2. * PI * tone.frequency * t).sin())
+                .sum::<f64>();
+            *r = (base[i] + self.drift[i] + tones) * 1e-6;
+        }
+        ret
+    }
+
+    /// Formats the sample taken at `at` in the current output format.
+    fn sample(&mut self, at: Instant) -> String {
+        let true_tilt = self.true_tilt(at);
+        let noise = self.config.noise * 1e-6 / f64::from(self.average_count).sqrt();
+        let mut shown = [0.; 2];
+        for i in 0..2 {
+            shown[i] = true_tilt[i] + noise * self.rng.normal() - self.zero[i] + self.offset[i];
+        }
+
+        if self.dropout_until.is_some_and(|until| at >= until) {
+            self.dropout_until = None;
+        }
+        if self.dropout_until.is_none() && self.rng.uniform() < self.config.dropout_rate {
+            let mean = self.config.dropout_duration.as_secs_f64();
+            let duration = -mean * (1. - self.rng.uniform()).ln();
+            self.dropout_until = Some(at + Duration::from_secs_f64(duration));
+        }
+        let out_of_range = true_tilt.iter().any(|t| t.abs() * 1e6 > self.config.range);
+        let judge = if self.dropout_until.is_some() || out_of_range {
+            "ND"
+        } else if self.rng.uniform() < self.config.error_rate {
+            "ER"
+        } else if (0..2).any(|i| shown[i].abs() > self.judge_tolerance[i]) {
+            "NG"
+        } else {
+            "OK"
+        };
+        let valid = matches!(judge, "OK" | "NG");
+
+        let mut line = String::new();
+        let angle = |line: &mut String, value: f64| {
+            line.push(',');
+            if valid {
+                line.push_str(&Angle::from_rad(Rad64::new(value), self.unit).to_words());
+            } else {
+                line.push_str(format_missing(self.unit));
+            }
+        };
+        match self.output_format {
+            OutputFormat::Format1 => {
+                write!(line, "1,{}", self.unit).unwrap();
+                angle(&mut line, shown[0]);
+                angle(&mut line, shown[1]);
+                angle(&mut line, shown[0].hypot(shown[1]));
+                let direction = if valid {
+                    shown[1].atan2(shown[0]).to_degrees().rem_euclid(360.)
+                } else {
+                    0.
+                };
+                write!(line, ",{direction:.1},{judge}").unwrap();
+            }
+            OutputFormat::Format2 => {
+                write!(line, "2,{}", self.unit).unwrap();
+                angle(&mut line, shown[0]);
+                angle(&mut line, shown[1]);
+                write!(line, ",{judge}").unwrap();
+            }
+        }
+        line
+    }
+}
+
+impl VirtualDevice for Simulator {
+    fn receive(&mut self, bytes: &[u8]) {
+        self.input.extend(bytes);
+        while let Some(i) = self.input.find(b"\r\n") {
+            let line = self.input.drain(..i + 2).take(i).collect::<Vec<_>>();
+            self.execute(&line);
+        }
+    }
+
+    fn transmit(&mut self, out: &mut Vec<u8>) {
+        let now = Instant::now();
+        while let Some((period, next)) = self.streaming {
+            if next > now {
+                break;
+            }
+            let line = self.sample(next);
+            self.output.extend(line.as_bytes());
+            self.output.extend(b"\r\n");
+            self.streaming = Some((period, next + period));
+        }
+        out.append(&mut self.output);
+    }
+}
+
+fn format_missing(unit: Unit) -> &'static str {
+    match unit {
+        Unit::DegMinSec => "-,-,-,-",
+        Unit::MinSec => "-,-,-",
+        _ => "-",
+    }
+}
+
+/// xorshift64*, which is plenty for simulated noise.
+pub(crate) struct Rng(u64);
+impl Rng {
+    pub(crate) fn new(seed: u64) -> Self {
+        const MIX: u64 = 0x9E37_79B9_7F4A_7C15;
+        // The state must not be zero, which it would be for a seed equal to the constant
+        match seed ^ MIX {
+            0 => Self(MIX),
+            state => Self(state),
+        }
+    }
+
+    fn next_u64(&mut self) -> u64 {
+        self.0 ^= self.0 >> 12;
+        self.0 ^= self.0 << 25;
+        self.0 ^= self.0 >> 27;
+        self.0.wrapping_mul(0x2545_F491_4F6C_DD1D)
+    }
+
+    /// Uniform in [0, 1)
+    fn uniform(&mut self) -> f64 {
+        (self.next_u64() >> 11) as f64 / (1u64 << 53) as f64
+    }
+
+    /// Standard normal (Box-Muller)
+    pub(crate) fn normal(&mut self) -> f64 {
+        let u = 1. - self.uniform();
+        (-2. * u.ln()).sqrt() * (2. * PI * self.uniform()).cos()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::thread;
+
+    use super::*;
+
+    fn simulator(settings: &str) -> Simulator {
+        Simulator::new(settings.parse().unwrap())
+    }
+
+    /// Lines sent after `command`, without the line endings.
+    fn reply(sim: &mut Simulator, command: &str) -> Vec<String> {
+        sim.receive(format!("{command}\r\n").as_bytes());
+        let mut out = vec![];
+        sim.transmit(&mut out);
+        (out.lines().map(|line| line.to_str().unwrap().to_owned())).collect()
+    }
+
+    fn judge(line: &str) -> &str {
+        line.rsplit(',').next().unwrap()
+    }
+
+    #[test]
+    fn rejects_invalid_settings() {
+        for settings in [
+            "dropout-ms=-1",
+            "dropout-ms=nan",
+            "noise",
+            "tone=1:2",
+            "foo=1",
+        ] {
+            assert!(settings.parse::<SimConfig>().is_err(), "{settings}");
+        }
+        let config: SimConfig = "dropout-ms=50, x=1.5".parse().unwrap();
+        assert_eq!(config.dropout_duration, Duration::from_millis(50));
+        assert_eq!(config.tilt, [1.5, 0.]);
+    }
+
+    #[test]
+    fn rng_state_is_never_zero() {
+        let mut rng = Rng::new(0x9E37_79B9_7F4A_7C15);
+        assert!((0..10).any(|_| rng.next_u64() != 0));
+    }
+
+    #[test]
+    fn answers_commands() {
+        let mut sim = simulator("x=10,y=-20,noise=0,drift=0");
+        let [line] = &reply(&mut sim, "G")[..] else {
+            panic!("Expected one line");
+        };
+        let data = crate::parse_sampling_data_1(line.as_bytes()).unwrap();
+        let urad = |a: Option<Angle>| (a.unwrap().value().val() * 1e6).round();
+        assert_eq!((urad(data.x), urad(data.y)), (10., -20.));
+        assert!(matches!(data.judge, crate::Judge::Ok));
+
+        assert!(reply(&mut sim, "L").is_empty());
+        assert!(reply(&mut sim, "S").is_empty());
+        assert_eq!(reply(&mut sim, "L,0"), ["ERR"]);
+        assert_eq!(reply(&mut sim, "X"), ["ERR"]);
+    }
+
+    #[test]
+    fn answers_configuration_commands() {
+        let mut sim = simulator("");
+        assert_eq!(reply(&mut sim, "A,10"), ["OK"]);
+        assert_eq!(reply(&mut sim, "A,0"), ["ERR"]);
+        assert_eq!(reply(&mut sim, "O,0.1,-0.2"), ["OK"]);
+        assert_eq!(reply(&mut sim, "F,3"), ["ERR"]);
+        assert_eq!(
+            reply(&mut sim, "Q"),
+            ["Q,mrad,10,0.100000,-0.200000,1.000000,1.000000,1"]
+        );
+    }
+
+    #[test]
+    fn streams_at_the_interval() {
+        let mut sim = simulator("");
+        let start = Instant::now();
+        assert!(reply(&mut sim, "L,3").is_empty());
+        thread::sleep(Duration::from_millis(500));
+        let mut out = vec![];
+        sim.transmit(&mut out);
+        let lines = out.lines().count() as f64;
+        out.clear();
+        let expected = start.elapsed().as_secs_f64() / (BASE_PERIOD * 3).as_secs_f64();
+        assert!(
+            (lines - expected).abs() <= 1.,
+            "{lines} lines, expected {expected}"
+        );
+
+        assert!(reply(&mut sim, "S").is_empty());
+        thread::sleep(BASE_PERIOD * 6);
+        sim.transmit(&mut out);
+        assert!(out.is_empty());
+    }
+
+    #[test]
+    fn drops_out_at_the_rate() {
+        // Dropouts of one sample each
+        let mut sim = simulator("dropout=0.1,dropout-ms=0");
+        let nd = (0..2000)
+            .filter(|_| judge(&reply(&mut sim, "G")[0]) == "ND")
+            .count();
+        assert!((150..250).contains(&nd), "{nd} dropouts");
+
+        let mut sim = simulator("dropout=1");
+        assert!((0..10).all(|_| judge(&reply(&mut sim, "G")[0]) == "ND"));
+        let mut sim = simulator("x=100,range=50");
+        assert_eq!(judge(&reply(&mut sim, "G")[0]), "ND");
+    }
+}
@@ -0,0 +1,512 @@
+//! Stability statistics of x and y.
+//!
+//! Angles are in rad and times in s. The Allan deviation and the power spectrum both treat
+//! the values as evenly spaced in time. Samples without x or y are skipped, which shifts the
+//! later values by a period each; this is a good approximation as long as such samples are rare.
+
+use std::{
+    collections::VecDeque,
+    f64::consts::PI,
+    time::{Duration, Instant},
+};
+
+use crate::Sample;
+
+/// Overlapping Allan deviation at averaging time `tau`.
+#[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct AllanPoint {
+    pub tau: f64,
+    pub deviation: [f64; 2],
+}
+
+/// Statistics of [x, y] over a window.
+#[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Summary {
+    /// Samples with x and y.
+    pub count: usize,
+    /// Samples without x or y.
+    pub missing: usize,
+    /// Time from the first to the last sample with x and y.
+    pub duration: f64,
+    pub mean: [f64; 2],
+    /// Sample standard deviation.
+    pub std: [f64; 2],
+    pub peak_to_peak: [f64; 2],
+    /// Slope of the least-squares line, in rad/s.
+    pub drift: [f64; 2],
+    /// At the decades of τ from the sampling period up to half the duration.
+    pub allan: Vec<AllanPoint>,
+}
+
+/// Keeps the samples received within a sliding window.
+pub struct Statistics {
+    window: Duration,
+    samples: VecDeque<(Instant, Option<[f64; 2]>)>,
+}
+
+impl Statistics {
+    pub fn new(window: Duration) -> Self {
+        Self {
+            window,
+            samples: VecDeque::new(),
+        }
+    }
+
+    pub fn push(&mut self, sample: &Sample) {
+        let xy = match (sample.data.x, sample.data.y) {
+            (Some(x), Some(y)) => Some([x.value().val(), y.value().val()]),
+            _ => None,
+        };
+        self.samples.push_back((sample.received, xy));
+        while (self.samples.front())
+            .is_some_and(|&(t, _)| sample.received.saturating_duration_since(t) > self.window)
+        {
+            self.samples.pop_front();
+        }
+    }
+
+    pub fn clear(&mut self) {
+        self.samples.clear();
+    }
+
+    /// `None` until there are at least two samples with x and y.
+    pub fn summary(&self) -> Option<Summary> {
+        let &(first, _) = self.samples.iter().find(|(_, xy)| xy.is_some())?;
+        let mut t = vec![];
+        let mut xy = [vec![], vec![]];
+        for &(at, value) in &self.samples {
+            if let Some(value) = value {
+                t.push(at.duration_since(first).as_secs_f64());
+                xy[0].push(value[0]);
+                xy[1].push(value[1]);
+            }
+        }
+        summarize(&t, [&xy[0], &xy[1]], self.samples.len() - t.len())
+    }
+}
+
+/// Statistics of samples with x and y at times `t` (s, ascending).
+/// `None` unless there are at least two samples spanning a nonzero time.
+pub fn summarize(t: &[f64], xy: [&[f64]; 2], missing: usize) -> Option<Summary> {
+    assert!(xy.iter().all(|values| values.len() == t.len()));
+    if t.len() < 2 {
+        return None;
+    }
+    let duration = t[t.len() - 1] - t[0];
+    let tau0 = duration / (t.len() - 1) as f64;
+    let per_axis = |f: &dyn Fn(&[f64]) -> f64| [f(xy[0]), f(xy[1])];
+    let allan = (standard_taus(tau0, duration / 2.).into_iter())
+        .filter_map(|tau| {
+            let m = (tau / tau0).round() as usize;
+            Some(AllanPoint {
+                tau: m as f64 * tau0,
+                deviation: [allan_deviation(xy[0], m)?, allan_deviation(xy[1], m)?],
+            })
+        })
+        .collect();
+    Some(Summary {
+        count: t.len(),
+        missing,
+        duration,
+        mean: per_axis(&mean),
+        std: per_axis(&std_dev),
+        peak_to_peak: per_axis(&peak_to_peak),
+        drift: [slope(t, xy[0])?, slope(t, xy[1])?],
+        allan,
+    })
+}
+
+pub fn mean(values: &[f64]) -> f64 {
+    values.iter().sum::<f64>() / values.len() as f64
+}
+
+/// Sample standard deviation, with `n - 1` in the denominator.
+pub fn std_dev(values: &[f64]) -> f64 {
+    let mean = mean(values);
+    let sum = values.iter().map(|v| (v - mean).powi(2)).sum::<f64>();
+    (sum / (values.len() as f64 - 1.)).sqrt()
+}
+
+pub fn peak_to_peak(values: &[f64]) -> f64 {
+    let max = values.iter().copied().fold(f64::NEG_INFINITY, f64::max);
+    let min = values.iter().copied().fold(f64::INFINITY, f64::min);
+    max - min
+}
+
+/// Slope of the least-squares line through `(t, y)`. `None` if all `t` are equal.
+pub fn slope(t: &[f64], y: &[f64]) -> Option<f64> {
+    assert_eq!(t.len(), y.len());
+    let (mean_t, mean_y) = (mean(t), mean(y));
+    let var_t = t.iter().map(|t| (t - mean_t).powi(2)).sum::<f64>();
+    let cov = (t.iter().zip(y))
+        .map(|(t, y)| (t - mean_t) * (y - mean_y))
+        .sum::<f64>();
+    (var_t > 0.).then(|| cov / var_t)
+}
+
+/// Overlapping Allan deviation of evenly spaced `values`, averaged over `m` samples.
+/// `None` unless there are at least `2m + 1` values.
+pub fn allan_deviation(values: &[f64], m: usize) -> Option<f64> {
+    let n = values.len();
+    if m == 0 || n < 2 * m + 1 {
+        return None;
+    }
+    // Cumulative sums of the deviations from the mean, to keep the differences accurate
+    let mean = mean(values);
+    let mut sums = Vec::with_capacity(n + 1);
+    sums.push(0.);
+    for v in values {
+        sums.push(sums[sums.len() - 1] + (v - mean));
+    }
+    let terms = n - 2 * m + 1;
+    let sum = (0..terms)
+        .map(|j| ((sums[j + 2 * m] - 2. * sums[j + m] + sums[j]) / m as f64).powi(2))
+        .sum::<f64>();
+    Some((sum / (2. * terms as f64)).sqrt())
+}
+
+/// Powers of ten from `min` (rounded up to the next one) up to `max`.
+pub fn standard_taus(min: f64, max: f64) -> Vec<f64> {
+    if min.is_nan() || min <= 0. {
+        return vec![];
+    }
+    let mut exponent = min.log10().ceil() as i32;
+    let mut taus = vec![];
+    while 10f64.powi(exponent) <= max {
+        taus.push(10f64.powi(exponent));
+        exponent += 1;
+    }
+    taus
+}
+
+/// Pearson correlation of `a[i]` with `b[i + lag]` over the pairs where both are present.
+/// `None` if fewer than three pairs remain or either side is constant.
+pub fn cross_correlation(a: &[Option<f64>], b: &[Option<f64>], lag: isize) -> Option<f64> {
+    let pairs = (0..a.len())
+        .filter_map(|i| {
+            let j = usize::try_from(i as isize + lag).ok()?;
+            Some((a[i]?, (*b.get(j)?)?))
+        })
+        .collect::<Vec<_>>();
+    if pairs.len() < 3 {
+        return None;
+    }
+    let n = pairs.len() as f64;
+    let mean_a = pairs.iter().map(|p| p.0).sum::<f64>() / n;
+    let mean_b = pairs.iter().map(|p| p.1).sum::<f64>() / n;
+    let (mut cov, mut var_a, mut var_b) = (0., 0., 0.);
+    for (a, b) in pairs {
+        cov += (a - mean_a) * (b - mean_b);
+        var_a += (a - mean_a).powi(2);
+        var_b += (b - mean_b).powi(2);
+    }
+    (var_a > 0. && var_b > 0.).then(|| cov / (var_a * var_b).sqrt())
+}
+
+/// Counts of values in equal bins.
+#[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Histogram {
+    /// Lower edge of the first bin.
+    pub start: f64,
+    pub width: f64,
+    pub counts: Vec<usize>,
+}
+
+/// Splits the range of `values` into `bins` bins. `None` if `values` is empty or `bins` is 0.
+/// Equal values all fall into one bin of width 1e-9 around them.
+pub fn histogram(values: &[f64], bins: usize) -> Option<Histogram> {
+    if values.is_empty() || bins == 0 {
+        return None;
+    }
+    let min = values.iter().copied().fold(f64::INFINITY, f64::min);
+    let max = values.iter().copied().fold(f64::NEG_INFINITY, f64::max);
+    let (start, width, bins) = if max > min {
+        (min, (max - min) / bins as f64, bins)
+    } else {
+        (min - 0.5e-9, 1e-9, 1)
+    };
+    let mut counts = vec![0; bins];
+    for v in values {
+        let bin = ((v - start) / width) as usize;
+        counts[bin.min(bins - 1)] += 1;
+    }
+    Some(Histogram {
+        start,
+        width,
+        counts,
+    })
+}
+
+/// One-sided power spectral density at `frequency` (Hz), in rad²/Hz.
+#[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct SpectrumPoint {
+    pub frequency: f64,
+    pub density: f64,
+}
+
+/// Power spectral density of evenly spaced `values` sampled at `sample_rate` (Hz), by Welch's method:
+/// the periodograms of Hann-windowed segments of `segment` values, overlapping by half, are averaged.
+/// `segment` is rounded up to a power of two; shorter data is zero-padded to it.
+/// The mean is removed first, so the density at 0 Hz is left out.
+/// Empty unless there are at least two values.
+pub fn power_spectrum(values: &[f64], sample_rate: f64, segment: usize) -> Vec<SpectrumPoint> {
+    if values.len() < 2 || sample_rate.is_nan() || sample_rate <= 0. {
+        return vec![];
+    }
+    let n = segment.max(2).next_power_of_two();
+    let used = n.min(values.len());
+    let window = (0..used)
+        .map(|i| (PI * i as f64 / used as f64).sin().powi(2))
+        .collect::<Vec<_>>();
+    let window_power = window.iter().map(|w| w * w).sum::<f64>();
+    let mean = mean(values);
+
+    let mut density = vec![0.; n / 2 + 1];
+    let mut segments = 0;
+    let mut start = 0;
+    while start + used <= values.len() {
+        let mut buffer = vec![[0.; 2]; n];
+        for (i, (v, w)) in values[start..start + used].iter().zip(&window).enumerate() {
+            buffer[i][0] = (v - mean) * w;
+        }
+        fft(&mut buffer);
+        for (d, [re, im]) in density.iter_mut().zip(&buffer) {
+            *d += re * re + im * im;
+        }
+        segments += 1;
+        start += (used / 2).max(1);
+    }
+    (density.into_iter().enumerate().skip(1))
+        .map(|(k, power)| {
+            // Both sides except at the Nyquist frequency
+            let sides = if k == n / 2 { 1. } else { 2. };
+            SpectrumPoint {
+                frequency: k as f64 * sample_rate / n as f64,
+                density: sides * power / (sample_rate * window_power * segments as f64),
+            }
+        })
+        .collect()
+}
+
+/// In-place radix-2 FFT of `[re, im]` values. The length must be a power of two.
+fn fft(values: &mut [[f64; 2]]) {
+    let n = values.len();
+    assert!(n.is_power_of_two());
+    // Bit-reversal permutation
+    let mut j = 0;
+    for i in 1..n {
+        let mut bit = n >> 1;
+        while j & bit != 0 {
+            j ^= bit;
+            bit >>= 1;
+        }
+        j |= bit;
+        if i < j {
+            values.swap(i, j);
+        }
+    }
+    let mut len = 2;
+    while len <= n {
+        let angle = -2. * PI / len as f64;
+        for chunk in values.chunks_exact_mut(len) {
+            let (lower, upper) = chunk.split_at_mut(len / 2);
+            for (k, (a, b)) in lower.iter_mut().zip(upper).enumerate() {
+                let (sin, cos) = (angle * k as f64).sin_cos();
+                let t = [b[0] * cos - b[1] * sin, b[0] * sin + b[1] * cos];
+                *b = [a[0] - t[0], a[1] - t[1]];
+                *a = [a[0] + t[0], a[1] + t[1]];
+            }
+        }
+        len *= 2;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sim::Rng;
+
+    fn assert_close(a: f64, b: f64, tolerance: f64) {
+        assert!((a - b).abs() <= tolerance, "{a} != {b}");
+    }
+
+    fn white_noise(n: usize, sigma: f64) -> Vec<f64> {
+        let mut rng = Rng::new(1);
+        (0..n).map(|_| sigma * rng.normal()).collect()
+    }
+
+    #[test]
+    fn moments_of_a_line() {
+        let t = [0., 1., 2., 3., 4.];
+        let y = [1., 3., 5., 7., 9.];
+        assert_eq!(mean(&y), 5.);
+        // Squared deviations 16 + 4 + 0 + 4 + 16 over n - 1
+        assert_close(std_dev(&y), 10f64.sqrt(), 1e-12);
+        assert_eq!(peak_to_peak(&y), 8.);
+        assert_close(slope(&t, &y).unwrap(), 2., 1e-12);
+        assert_eq!(slope(&[1., 1.], &[0., 1.]), None);
+    }
+
+    #[test]
+    fn allan_deviation_by_hand() {
+        let values = [1., 2., 4., 3., 5.];
+        // m = 1: differences 1, 2, -1, 2; (1 + 4 + 1 + 4) / (2 * 4)
+        assert_close(allan_deviation(&values, 1).unwrap(), 1.25f64.sqrt(), 1e-12);
+        // m = 2: means of pairs 1.5, 3, 3.5, 4; differences 3.5 - 1.5 and 4 - 3; (4 + 1) / (2 * 2)
+        assert_close(allan_deviation(&values, 2).unwrap(), 1.25f64.sqrt(), 1e-12);
+        assert_eq!(allan_deviation(&values, 3), None);
+        assert_eq!(allan_deviation(&values, 0), None);
+    }
+
+    #[test]
+    fn allan_deviation_of_white_noise_falls_as_the_root_of_tau() {
+        let sigma = 2e-6;
+        let values = white_noise(100_000, sigma);
+        for m in [1, 10, 100] {
+            let adev = allan_deviation(&values, m).unwrap();
+            assert_close(adev * (m as f64).sqrt() / sigma, 1., 0.1);
+        }
+    }
+
+    #[test]
+    fn allan_deviation_ignores_an_offset() {
+        let values = [1., 2., 4., 3., 5.];
+        let shifted = values.map(|v| v + 1e3);
+        assert_close(
+            allan_deviation(&shifted, 1).unwrap(),
+            allan_deviation(&values, 1).unwrap(),
+            1e-9,
+        );
+    }
+
+    #[test]
+    fn standard_taus_are_decades_in_range() {
+        assert_eq!(standard_taus(0.016667, 30.), [0.1, 1., 10.]);
+        assert_eq!(standard_taus(1., 1.), [1.]);
+        assert!(standard_taus(10., 5.).is_empty());
+        assert!(standard_taus(0., 5.).is_empty());
+        assert!(standard_taus(f64::NAN, 5.).is_empty());
+    }
+
+    #[test]
+    fn summary_of_evenly_spaced_samples() {
+        let n = 1001;
+        let t = (0..n).map(|i| i as f64 * 0.01).collect::<Vec<_>>();
+        // x drifts by 1 µrad/s; y is white noise around 5 µrad
+        let x = t.iter().map(|t| t * 1e-6).collect::<Vec<_>>();
+        let y = white_noise(n, 1e-7)
+            .iter()
+            .map(|v| v + 5e-6)
+            .collect::<Vec<_>>();
+        let summary = summarize(&t, [&x, &y], 3).unwrap();
+        assert_eq!(summary.count, n);
+        assert_eq!(summary.missing, 3);
+        assert_close(summary.duration, 10., 1e-9);
+        assert_close(summary.mean[0], 5e-6, 1e-15);
+        assert_close(summary.mean[1], 5e-6, 1e-8);
+        assert_close(summary.std[1], 1e-7, 1e-8);
+        assert_close(summary.peak_to_peak[0], 10e-6, 1e-15);
+        assert_close(summary.drift[0], 1e-6, 1e-15);
+        assert_close(summary.drift[1], 0., 1e-8);
+        // Decades of τ from the sampling period of 0.01 s up to half of the 10 s
+        let taus = summary.allan.iter().map(|p| p.tau).collect::<Vec<_>>();
+        assert_eq!(taus.len(), 3);
+        for (tau, expected) in taus.into_iter().zip([0.01, 0.1, 1.]) {
+            assert_close(tau, expected, 1e-9);
+        }
+        assert_close(summary.allan[0].deviation[1] / 1e-7, 1., 0.1);
+        assert_close(summary.allan[1].deviation[1] * 10f64.sqrt() / 1e-7, 1., 0.2);
+    }
+
+    #[test]
+    fn summary_needs_two_samples() {
+        assert_eq!(summarize(&[0.], [&[0.], &[0.]], 0), None);
+        assert_eq!(summarize(&[], [&[], &[]], 5), None);
+    }
+
+    #[test]
+    fn histogram_bins() {
+        let values = (0..10).map(f64::from).collect::<Vec<_>>();
+        let histogram = histogram(&values, 5).unwrap();
+        assert_eq!(histogram.start, 0.);
+        assert_close(histogram.width, 1.8, 1e-12);
+        // The maximum falls into the last bin
+        assert_eq!(histogram.counts, [2, 2, 2, 2, 2]);
+    }
+
+    #[test]
+    fn histogram_of_equal_values() {
+        let histogram = histogram(&[3., 3., 3.], 10).unwrap();
+        assert_eq!(histogram.counts, [3]);
+        assert!(histogram.start < 3. && 3. < histogram.start + histogram.width);
+        assert_eq!(super::histogram(&[], 10), None);
+        assert_eq!(super::histogram(&[1.], 0), None);
+    }
+
+    #[test]
+    fn fft_matches_the_dft() {
+        let mut rng = Rng::new(2);
+        let input = (0..16)
+            .map(|_| [rng.normal(), rng.normal()])
+            .collect::<Vec<_>>();
+        let mut output = input.clone();
+        fft(&mut output);
+        for (k, [re, im]) in output.into_iter().enumerate() {
+            let mut expected = [0.; 2];
+            for (i, [a, b]) in input.iter().enumerate() {
+                let (sin, cos) = (-2. * PI * (i * k) as f64 / 16.).sin_cos();
+                expected[0] += a * cos - b * sin;
+                expected[1] += a * sin + b * cos;
+            }
+            assert_close(re, expected[0], 1e-9);
+            assert_close(im, expected[1], 1e-9);
+        }
+    }
+
+    #[test]
+    fn spectrum_of_a_sinusoid() {
+        // 5 Hz at 100 Hz falls on bin 5 * 256 / 100 = 12.8; amplitude 3
+        let (rate, amplitude) = (100., 3.);
+        let values = (0..4096)
+            .map(|i| amplitude * (2. * PI * 5. * i as f64 / rate).sin() + 1.)
+            .collect::<Vec<_>>();
+        let spectrum = power_spectrum(&values, rate, 256);
+        assert_eq!(spectrum.len(), 128);
+        assert_close(spectrum[0].frequency, rate / 256., 1e-12);
+        assert_close(spectrum[127].frequency, rate / 2., 1e-12);
+        let peak = (spectrum.iter())
+            .max_by(|a, b| a.density.total_cmp(&b.density))
+            .unwrap();
+        assert_close(peak.frequency, 5., rate / 256.);
+        // Parseval: the density integrates to the mean square of the sinusoid
+        let df = rate / 256.;
+        let power = spectrum.iter().map(|p| p.density * df).sum::<f64>();
+        assert_close(power / (amplitude * amplitude / 2.), 1., 0.02);
+    }
+
+    #[test]
+    fn spectrum_of_white_noise_is_flat() {
+        // Variance σ² spread over 0 to rate / 2 on one side
+        let (rate, sigma) = (50., 2.);
+        let values = white_noise(1 << 16, sigma);
+        let spectrum = power_spectrum(&values, rate, 512);
+        let expected = sigma * sigma / (rate / 2.);
+        let mean = spectrum.iter().map(|p| p.density).sum::<f64>() / spectrum.len() as f64;
+        assert_close(mean / expected, 1., 0.05);
+        // Averaging over many segments keeps every bin close to the mean
+        assert!(spectrum
+            .iter()
+            .all(|p| (p.density / expected - 1.).abs() < 0.5));
+    }
+
+    #[test]
+    fn spectrum_needs_two_values_and_a_rate() {
+        assert!(power_spectrum(&[1.], 10., 16).is_empty());
+        assert!(power_spectrum(&[1., 2.], 0., 16).is_empty());
+        assert!(power_spectrum(&[1., 2.], f64::NAN, 16).is_empty());
+    }
+}